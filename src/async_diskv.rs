@@ -0,0 +1,97 @@
+use std::panic;
+use std::sync::Arc;
+
+use tokio::task;
+
+use crate::diskv::{Diskv, DiskvResult};
+
+//
+// AsyncDiskv
+// Wraps Diskv for use from async code. Every call runs on tokio's blocking thread pool, so file system work never
+// blocks the executor. Cache and its lock are those of the wrapped Diskv, they are only ever held inside the blocking
+// task, never across an await. Clones share the same store.
+//
+#[derive(Clone)]
+pub struct AsyncDiskv {
+    diskv: Arc<Diskv>,
+}
+
+impl AsyncDiskv {
+    pub fn new(diskv: Diskv) -> AsyncDiskv {
+        AsyncDiskv {
+            diskv: Arc::new(diskv),
+        }
+    }
+
+    pub async fn put(&self, key: &str, val: Vec<u8>) -> DiskvResult<()> {
+        let key = key.to_string();
+        self.spawn(move |diskv| diskv.put(&key, val)).await
+    }
+
+    pub async fn get(&self, key: &str) -> DiskvResult<Option<Vec<u8>>> {
+        let key = key.to_string();
+        self.spawn(move |diskv| diskv.get(&key)).await
+    }
+
+    pub async fn delete(&self, key: &str) -> DiskvResult<()> {
+        let key = key.to_string();
+        self.spawn(move |diskv| diskv.delete(&key)).await
+    }
+
+    // a panic in f is carried over to the caller, same as with Diskv
+    async fn spawn<T, F>(&self, f: F) -> DiskvResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Diskv) -> DiskvResult<T> + Send + 'static,
+    {
+        let diskv = Arc::clone(&self.diskv);
+        match task::spawn_blocking(move || f(&diskv)).await {
+            Ok(r) => r,
+            Err(e) => panic::resume_unwind(e.into_panic()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskv::Options;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn async_concurrent_get_put() -> DiskvResult<()> {
+        let base_path = PathBuf::from("test_data_async");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = AsyncDiskv::new(Diskv::new(Options {
+            base_path: base_path.clone(),
+            cache_size_max: 64,
+            ..Default::default()
+        })?);
+
+        let mut tasks = Vec::new();
+        for i in 0..8 {
+            let dkv = dkv.clone();
+            tasks.push(tokio::spawn(async move {
+                for j in 0..10 {
+                    let key = format!("k{}_{}", i, j);
+                    dkv.put(&key, key.clone().into_bytes()).await?;
+                    assert_eq!(Some(key.clone().into_bytes()), dkv.get(&key).await?);
+                }
+                dkv.delete(&format!("k{}_0", i)).await
+            }));
+        }
+        for t in tasks {
+            t.await.expect("task panicked")?;
+        }
+
+        for i in 0..8 {
+            assert_eq!(None, dkv.get(&format!("k{}_0", i)).await?);
+            let key = format!("k{}_9", i);
+            assert_eq!(Some(key.clone().into_bytes()), dkv.get(&key).await?);
+        }
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+}
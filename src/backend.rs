@@ -0,0 +1,600 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::diskv::{create_dir_all, sync_dir, MemoryFiles, Storage};
+
+//
+// Backend
+// Flat set of named files Diskv keeps values, expiry and metadata files in, see Options::backend. Names are those
+// Diskv gives them, plain ASCII without path separators, so a backend can store them as they are. Diskv serializes
+// writes of a key itself, but reads, writes and deletes of different names come from many threads at once, hence
+// Send + Sync. Writes must replace a file as a whole, a reader seeing either the old content or the new one.
+// FsBackend keeps files in a directory and MemBackend in a HashMap, as Diskv does without a backend.
+//
+pub trait Backend: Send + Sync {
+    // None if there is no such file
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>>;
+    fn write(&self, name: &str, buf: &[u8]) -> io::Result<()>;
+    // no-op if there is no such file
+    fn delete(&self, name: &str) -> io::Result<()>;
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    // length and modification time of a file, None if there is no such file. backends which don't keep modification
+    // times can leave this to read, which reports UNIX_EPOCH.
+    fn stat(&self, name: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        Ok(self.read(name)?.map(|buf| (buf.len() as u64, UNIX_EPOCH)))
+    }
+
+    // drops content overwritten or deleted since, for backends which keep it around, returning how many records of
+    // it were dropped. Diskv::compact calls it. backends which free space as they go have nothing to do.
+    fn compact(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+// files directly in dir, written atomically and synced like those of Diskv without a backend
+pub struct FsBackend {
+    storage: Storage,
+}
+
+impl FsBackend {
+    // dir gets created if need be
+    pub fn new<P: Into<PathBuf>>(dir: P) -> io::Result<FsBackend> {
+        let dir = dir.into();
+        create_dir_all(&dir, None)?;
+        Ok(FsBackend {
+            storage: Storage::dir(dir, 0, 64 * 1024, None),
+        })
+    }
+}
+
+impl Backend for FsBackend {
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        self.storage.read(name)
+    }
+
+    fn write(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+        self.storage.write(name, buf)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.storage.remove(name)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.storage.names()
+    }
+
+    fn stat(&self, name: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        self.storage.stat(name)
+    }
+}
+
+// files in a HashMap, gone once the backend is dropped
+pub struct MemBackend {
+    storage: Storage,
+}
+
+impl MemBackend {
+    pub fn new() -> MemBackend {
+        MemBackend {
+            storage: Storage::Memory(RwLock::new(MemoryFiles::new())),
+        }
+    }
+}
+
+impl Default for MemBackend {
+    fn default() -> MemBackend {
+        MemBackend::new()
+    }
+}
+
+impl Backend for MemBackend {
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        self.storage.read(name)
+    }
+
+    fn write(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+        self.storage.write(name, buf)
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        self.storage.remove(name)
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        self.storage.names()
+    }
+
+    fn stat(&self, name: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        self.storage.stat(name)
+    }
+}
+
+//
+// SegmentBackend
+// All files in a single append-only segment file, for stores of many small values, where a file per value wastes
+// inodes and syscalls. A write appends a record of PUT_RECORD, name length as u32 little endian, name, modification
+// time as little endian milliseconds since UNIX epoch, content length as u32 little endian and content. A delete
+// appends a tombstone, TOMBSTONE_RECORD followed by name length and name. Opening reads the segment through, building
+// an index of where the latest content of every name is, and truncates a record cut short by a crash. Overwritten
+// and deleted content stays in the segment until compact rewrites the live records into a fresh one. Appends are
+// synced, as files written by Diskv are. Reads seek in the same file, so they take the lock of the segment too.
+// Names are at most MAX_SEGMENT_NAME_LEN bytes, enough for every name Diskv produces, so that a corrupt name length
+// fails with InvalidData rather than a huge allocation.
+//
+const PUT_RECORD: u8 = 1;
+const TOMBSTONE_RECORD: u8 = 2;
+const MAX_SEGMENT_NAME_LEN: usize = 256;
+
+pub struct SegmentBackend {
+    path: PathBuf,
+    segment: Mutex<Segment>,
+}
+
+struct Segment {
+    file: fs::File,
+    len: u64,
+    index: HashMap<String, SegmentEntry>,
+    // records compact would drop: superseded puts and tombstones
+    dead: usize,
+}
+
+#[derive(Clone, Copy)]
+struct SegmentEntry {
+    offset: u64,
+    len: u32,
+    modified: SystemTime,
+}
+
+impl SegmentBackend {
+    // segment file at path gets created if need be, its directory must exist
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<SegmentBackend> {
+        SegmentBackend::open_with(path.into(), false)
+    }
+
+    // read_only neither creates the segment nor truncates it, and fails with NotFound if it's missing. otherwise a
+    // fresh segment left behind by a compact which never completed gets removed.
+    pub(crate) fn open_with(path: PathBuf, read_only: bool) -> io::Result<SegmentBackend> {
+        let file = if read_only {
+            fs::File::open(&path)?
+        } else {
+            match fs::remove_file(compact_path(&path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+            fs::OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)?
+        };
+        let segment = Segment::load(file, read_only)?;
+        Ok(SegmentBackend {
+            path,
+            segment: Mutex::new(segment),
+        })
+    }
+
+    // a panic can at worst leave an incomplete record at the end, which the next append or open truncates
+    fn segment(&self) -> MutexGuard<'_, Segment> {
+        self.segment.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // live records in a fresh segment next to the current one, along with its length and index
+    fn rewrite(
+        &self,
+        segment: &mut Segment,
+        tmp: &Path,
+    ) -> io::Result<(u64, HashMap<String, SegmentEntry>)> {
+        let mut live: Vec<(String, SegmentEntry)> = segment
+            .index
+            .iter()
+            .map(|(name, entry)| (name.clone(), *entry))
+            .collect();
+        live.sort_by_key(|(_, entry)| entry.offset);
+
+        let mut w = io::BufWriter::new(fs::File::create(tmp)?);
+        let (mut len, mut index) = (0, HashMap::with_capacity(live.len()));
+        for (name, entry) in live {
+            let content = segment.read_entry(&entry)?;
+            let buf = encode_record(&name, Some((&content, entry.modified)))?;
+            w.write_all(&buf)?;
+            let offset = len + (buf.len() - content.len()) as u64;
+            index.insert(name, SegmentEntry { offset, ..entry });
+            len += buf.len() as u64;
+        }
+        w.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        Ok((len, index))
+    }
+}
+
+impl Segment {
+    fn load(mut file: fs::File, read_only: bool) -> io::Result<Segment> {
+        let mut index = HashMap::new();
+        let mut dead = 0;
+        let mut len = 0;
+        {
+            let mut r = io::BufReader::new(&mut file);
+            while let Some((name, entry, next)) = read_record(&mut r, len)? {
+                let superseded = match entry {
+                    Some(entry) => index.insert(name, entry),
+                    None => {
+                        dead += 1;
+                        index.remove(&name)
+                    }
+                };
+                if superseded.is_some() {
+                    dead += 1;
+                }
+                len = next;
+            }
+        }
+        if !read_only && file.metadata()?.len() > len {
+            file.set_len(len)?;
+        }
+        Ok(Segment {
+            file,
+            len,
+            index,
+            dead,
+        })
+    }
+
+    fn read_entry(&mut self, entry: &SegmentEntry) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; entry.len as usize];
+        self.file.seek(io::SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+
+    // appends a put of content, or a tombstone for None
+    fn append(&mut self, name: &str, content: Option<&[u8]>) -> io::Result<()> {
+        // millisecond precision, as the modification time reads back from the segment
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let modified = UNIX_EPOCH + Duration::from_millis(millis);
+        let buf = encode_record(name, content.map(|content| (content, modified)))?;
+        let written = self
+            .file
+            .write_all(&buf)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = written {
+            // records appended later must not follow a partial one
+            let _ = self.file.set_len(self.len);
+            return Err(e);
+        }
+
+        let superseded = match content {
+            Some(content) => {
+                let entry = SegmentEntry {
+                    offset: self.len + (buf.len() - content.len()) as u64,
+                    len: content.len() as u32,
+                    modified,
+                };
+                self.index.insert(name.to_string(), entry)
+            }
+            None => {
+                self.dead += 1;
+                self.index.remove(name)
+            }
+        };
+        if superseded.is_some() {
+            self.dead += 1;
+        }
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn encode_record(name: &str, put: Option<(&[u8], SystemTime)>) -> io::Result<Vec<u8>> {
+    let content_len = put.map_or(0, |(content, _)| content.len());
+    if name.len() > MAX_SEGMENT_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "segment name is too long",
+        ));
+    }
+    if content_len > u32::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "segment records are limited to 4 GiB",
+        ));
+    }
+    let mut buf = Vec::with_capacity(17 + name.len() + content_len);
+    buf.push(if put.is_some() {
+        PUT_RECORD
+    } else {
+        TOMBSTONE_RECORD
+    });
+    buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+    buf.extend_from_slice(name.as_bytes());
+    if let Some((content, modified)) = put {
+        let millis = modified
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        buf.extend_from_slice(&millis.to_le_bytes());
+        buf.extend_from_slice(&(content.len() as u32).to_le_bytes());
+        buf.extend_from_slice(content);
+    }
+    Ok(buf)
+}
+
+// record of r at offset: its name, the entry of its content for a put, None for a tombstone, and the offset of the
+// next record. None at the end of the segment, and for a record cut short.
+fn read_record<R: Read>(
+    r: &mut R,
+    offset: u64,
+) -> io::Result<Option<(String, Option<SegmentEntry>, u64)>> {
+    let mut head = [0; 5];
+    if !read_full(r, &mut head)? {
+        return Ok(None);
+    }
+    let name_len = u32::from_le_bytes(head[1..].try_into().unwrap()) as usize;
+    if name_len > MAX_SEGMENT_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("segment name of {} bytes", name_len),
+        ));
+    }
+    let mut name = vec![0; name_len];
+    if !read_full(r, &mut name)? {
+        return Ok(None);
+    }
+    let name = String::from_utf8(name)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "segment name is not UTF-8"))?;
+    let offset = offset + (head.len() + name.len()) as u64;
+    match head[0] {
+        TOMBSTONE_RECORD => Ok(Some((name, None, offset))),
+        PUT_RECORD => {
+            let mut put = [0; 12];
+            if !read_full(r, &mut put)? {
+                return Ok(None);
+            }
+            let millis = u64::from_le_bytes(put[..8].try_into().unwrap());
+            let len = u32::from_le_bytes(put[8..].try_into().unwrap());
+            let skipped = io::copy(&mut (&mut *r).take(len as u64), &mut io::sink())?;
+            if skipped < len as u64 {
+                return Ok(None);
+            }
+            let entry = SegmentEntry {
+                offset: offset + put.len() as u64,
+                len,
+                modified: UNIX_EPOCH + Duration::from_millis(millis),
+            };
+            Ok(Some((name, Some(entry), entry.offset + len as u64)))
+        }
+        op => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown segment record {}", op),
+        )),
+    }
+}
+
+// fresh segment compact writes before renaming it over the segment at path
+fn compact_path(path: &Path) -> PathBuf {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".compact");
+    PathBuf::from(tmp)
+}
+
+// false if r ends before buf is filled
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl Backend for SegmentBackend {
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut segment = self.segment();
+        match segment.index.get(name).copied() {
+            Some(entry) => segment.read_entry(&entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn write(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+        self.segment().append(name, Some(buf))
+    }
+
+    fn delete(&self, name: &str) -> io::Result<()> {
+        let mut segment = self.segment();
+        if segment.index.contains_key(name) {
+            segment.append(name, None)?;
+        }
+        Ok(())
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.segment().index.keys().cloned().collect())
+    }
+
+    fn stat(&self, name: &str) -> io::Result<Option<(u64, SystemTime)>> {
+        Ok(self
+            .segment()
+            .index
+            .get(name)
+            .map(|entry| (entry.len as u64, entry.modified)))
+    }
+
+    // the fresh segment is written next to the current one and renamed over it, so a crash leaves one or the other.
+    // the lock is held throughout, holding up every read and write meanwhile.
+    fn compact(&self) -> io::Result<usize> {
+        let mut segment = self.segment();
+        if segment.dead == 0 {
+            return Ok(0);
+        }
+        let tmp = compact_path(&self.path);
+        let (len, index) = match self.rewrite(&mut segment, &tmp) {
+            Ok(rewritten) => rewritten,
+            Err(e) => {
+                let _ = fs::remove_file(&tmp);
+                return Err(e);
+            }
+        };
+        // opened before the rename, so that the current segment is kept if either fails
+        let file = fs::OpenOptions::new().read(true).append(true).open(&tmp)?;
+        fs::rename(&tmp, &self.path)?;
+        sync_dir(self.path.parent().unwrap_or_else(|| Path::new(".")))?;
+        let dropped = segment.dead;
+        *segment = Segment {
+            file,
+            len,
+            index,
+            dead: 0,
+        };
+        Ok(dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskv::{DiskvBuilder, DiskvResult, StorageFormat};
+    use std::fs;
+    use std::io::Read;
+    use std::time::Duration;
+
+    // runs the same operations against Diskv on top of backend
+    fn exercise_backend<B: Backend + 'static>(backend: B) -> DiskvResult<()> {
+        let dkv = DiskvBuilder::new().backend(backend).build()?;
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put("k2", b"v2".to_vec())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get_uncached("k2")?);
+
+        dkv.put_with_ttl("k3", b"v3".to_vec(), Duration::from_millis(20))?;
+        assert_eq!(Some(b"v3".to_vec()), dkv.get_uncached("k3")?);
+        std::thread::sleep(Duration::from_millis(40));
+        assert_eq!(None, dkv.get("k3")?);
+
+        dkv.put_reader("k4", &b"streamed"[..])?;
+        let mut buf = Vec::new();
+        dkv.get_reader("k4")?.unwrap().read_to_end(&mut buf)?;
+        assert_eq!(b"streamed".to_vec(), buf);
+        assert_eq!(8, dkv.metadata("k4")?.unwrap().size);
+
+        assert!(dkv.rename_key("k4", "k5")?);
+        assert!(!dkv.contains_key("k4")?);
+        assert_eq!(Some(b"streamed".to_vec()), dkv.get_uncached("k5")?);
+
+        let long_key = "l".repeat(250);
+        dkv.put(&long_key, b"hashed".to_vec())?;
+        assert_eq!(Some(b"hashed".to_vec()), dkv.get_uncached(&long_key)?);
+
+        dkv.delete("k1")?;
+        assert!(!dkv.contains_key("k1")?);
+        let mut keys = dkv.keys()?;
+        keys.sort();
+        assert_eq!(vec!["k2".to_string(), "k5".to_string(), long_key], keys);
+        Ok(())
+    }
+
+    #[test]
+    fn fs_backend() -> DiskvResult<()> {
+        let dir = PathBuf::from("test_data_fs_backend");
+        let _ = fs::remove_dir_all(&dir);
+        exercise_backend(FsBackend::new(&dir)?)?;
+        assert!(dir.join("k2").is_file());
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mem_backend() -> DiskvResult<()> {
+        exercise_backend(MemBackend::new())
+    }
+
+    #[test]
+    fn segment_backend() -> DiskvResult<()> {
+        let dir = PathBuf::from("test_data_segment_backend");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir)?;
+        exercise_backend(SegmentBackend::open(dir.join("segment"))?)?;
+        assert_eq!(vec![dir.join("segment")], list_dir(&dir));
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    fn list_dir(dir: &Path) -> Vec<PathBuf> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect()
+    }
+
+    #[test]
+    fn segment_compaction() -> DiskvResult<()> {
+        let dir = PathBuf::from("test_data_segment_compaction");
+        let _ = fs::remove_dir_all(&dir);
+        let open = || {
+            DiskvBuilder::new()
+                .base_path(&dir)
+                .storage_format(StorageFormat::Segment)
+                .build()
+        };
+        let segment = dir.join("segment.log");
+        let dkv = open()?;
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put("k2", b"v2".to_vec())?;
+        dkv.put("k2", b"v2 again".to_vec())?;
+        dkv.put("k3", b"v3".to_vec())?;
+        dkv.delete("k3")?;
+        let before = fs::metadata(&segment)?.len();
+
+        // first k2 put, k3 put and its tombstone
+        assert_eq!(3, dkv.compact()?);
+        assert!(fs::metadata(&segment)?.len() < before);
+        assert_eq!(0, dkv.compact()?);
+        assert_eq!(None, dkv.get_uncached("k3")?);
+        dkv.put("k4", b"v4".to_vec())?;
+        drop(dkv);
+
+        // what a crash in the middle of an append leaves behind
+        let mut f = fs::OpenOptions::new().append(true).open(&segment)?;
+        f.write_all(&[PUT_RECORD, 2, 0, 0, 0, b'k'])?;
+        drop(f);
+
+        let dkv = open()?;
+        let mut keys = dkv.keys()?;
+        keys.sort();
+        assert_eq!(vec!["k1", "k2", "k4"], keys);
+        assert_eq!(Some(b"v2 again".to_vec()), dkv.get("k2")?);
+        assert_eq!(None, dkv.get("k3")?);
+        dkv.put("k5", b"v5".to_vec())?;
+        drop(dkv);
+        assert_eq!(Some(b"v5".to_vec()), open()?.get("k5")?);
+        assert_eq!(vec![segment.clone()], list_dir(&dir));
+
+        // what a crash in the middle of compact leaves behind
+        fs::write(compact_path(&segment), b"partial")?;
+        assert_eq!(Some(b"v5".to_vec()), open()?.get("k5")?);
+        assert_eq!(vec![segment.clone()], list_dir(&dir));
+
+        // a corrupt name length
+        let mut f = fs::OpenOptions::new().append(true).open(&segment)?;
+        f.write_all(&[TOMBSTONE_RECORD, 0xff, 0xff, 0xff, 0x7f])?;
+        drop(f);
+        match SegmentBackend::open(&segment) {
+            Err(e) => assert_eq!(io::ErrorKind::InvalidData, e.kind()),
+            Ok(_) => panic!("corrupt segment opened"),
+        }
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use std::io;
+
+//
+// Codec
+// Compression applied to values before they are written to disk. Every encoded value starts with a one byte marker of
+// the codec it was written with, so values stay readable after Options::codec is changed, as long as the codec they
+// were written with is enabled. Gzip and Zstd come with cargo features of the same names.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    None,
+    #[cfg(feature = "gzip")]
+    Gzip,
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+pub(crate) const MARKER_NONE: u8 = 0;
+const MARKER_GZIP: u8 = 1;
+const MARKER_ZSTD: u8 = 2;
+
+// codec marker followed by the compressed value
+pub(crate) fn encode(codec: Codec, val: &[u8]) -> io::Result<Vec<u8>> {
+    match codec {
+        Codec::None => {
+            let mut buf = Vec::with_capacity(1 + val.len());
+            buf.push(MARKER_NONE);
+            buf.extend_from_slice(val);
+            Ok(buf)
+        }
+        #[cfg(feature = "gzip")]
+        Codec::Gzip => {
+            use std::io::Write;
+            let mut enc =
+                flate2::write::GzEncoder::new(vec![MARKER_GZIP], flate2::Compression::default());
+            enc.write_all(val)?;
+            enc.finish()
+        }
+        #[cfg(feature = "zstd")]
+        Codec::Zstd => {
+            let mut buf = vec![MARKER_ZSTD];
+            zstd::stream::copy_encode(val, &mut buf, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            Ok(buf)
+        }
+    }
+}
+
+// length of the value encoded in encoded_len bytes starting with marker, when that's known without decoding it
+pub(crate) fn plain_len(marker: u8, encoded_len: u64) -> Option<u64> {
+    match marker {
+        MARKER_NONE => Some(encoded_len.saturating_sub(1)),
+        _ => None,
+    }
+}
+
+pub(crate) fn decode(buf: &[u8]) -> io::Result<Vec<u8>> {
+    let (marker, payload) = match buf.split_first() {
+        Some((marker, payload)) => (*marker, payload),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "missing codec marker",
+            ))
+        }
+    };
+    match marker {
+        MARKER_NONE => Ok(payload.to_vec()),
+        #[cfg(feature = "gzip")]
+        MARKER_GZIP => {
+            use std::io::Read;
+            let mut val = Vec::new();
+            flate2::read::GzDecoder::new(payload).read_to_end(&mut val)?;
+            Ok(val)
+        }
+        #[cfg(feature = "zstd")]
+        MARKER_ZSTD => zstd::stream::decode_all(payload),
+        #[cfg(not(feature = "gzip"))]
+        MARKER_GZIP => Err(not_enabled("gzip")),
+        #[cfg(not(feature = "zstd"))]
+        MARKER_ZSTD => Err(not_enabled("zstd")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown codec marker {}", marker),
+        )),
+    }
+}
+
+// reader decoding what's read from r, a value written with the codec of marker, after the marker itself
+pub(crate) fn decoder(
+    marker: u8,
+    r: Box<dyn io::Read + Send>,
+) -> io::Result<Box<dyn io::Read + Send>> {
+    match marker {
+        MARKER_NONE => Ok(r),
+        #[cfg(feature = "gzip")]
+        MARKER_GZIP => Ok(Box::new(flate2::read::GzDecoder::new(r))),
+        #[cfg(feature = "zstd")]
+        MARKER_ZSTD => Ok(Box::new(zstd::stream::read::Decoder::new(r)?)),
+        #[cfg(not(feature = "gzip"))]
+        MARKER_GZIP => Err(not_enabled("gzip")),
+        #[cfg(not(feature = "zstd"))]
+        MARKER_ZSTD => Err(not_enabled("zstd")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown codec marker {}", marker),
+        )),
+    }
+}
+
+#[allow(dead_code)] // unused when all codecs are enabled
+fn not_enabled(codec: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("value compressed with {}, which is not enabled", codec),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compressible() -> Vec<u8> {
+        "diskv ".repeat(10_000).into_bytes()
+    }
+
+    fn incompressible() -> Vec<u8> {
+        let mut seed: u32 = 7;
+        (0..60_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn round_trip(codec: Codec) -> io::Result<()> {
+        for val in &[Vec::new(), compressible(), incompressible()] {
+            assert_eq!(*val, decode(&encode(codec, val)?)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn codec_none_round_trip() -> io::Result<()> {
+        round_trip(Codec::None)?;
+        assert_eq!(vec![MARKER_NONE, b'a'], encode(Codec::None, b"a")?);
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn codec_gzip_round_trip() -> io::Result<()> {
+        round_trip(Codec::Gzip)?;
+        assert!(encode(Codec::Gzip, &compressible())?.len() < compressible().len() / 5);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn codec_zstd_round_trip() -> io::Result<()> {
+        round_trip(Codec::Zstd)?;
+        assert!(encode(Codec::Zstd, &compressible())?.len() < compressible().len() / 5);
+        Ok(())
+    }
+
+    #[test]
+    fn codec_decoder() -> io::Result<()> {
+        use std::io::Read;
+        let codecs = [
+            Codec::None,
+            #[cfg(feature = "gzip")]
+            Codec::Gzip,
+            #[cfg(feature = "zstd")]
+            Codec::Zstd,
+        ];
+        for codec in codecs.iter().copied() {
+            let encoded = encode(codec, &compressible())?;
+            let r = Box::new(io::Cursor::new(encoded[1..].to_vec()));
+            let mut val = Vec::new();
+            decoder(encoded[0], r)?.read_to_end(&mut val)?;
+            assert_eq!(compressible(), val);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn codec_bad_marker() {
+        assert!(decode(&[]).is_err());
+        assert!(decode(&[42, b'a']).is_err());
+    }
+}
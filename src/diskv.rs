@@ -1,17 +1,73 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::error;
 use std::fmt;
 use std::fs;
 use std::io;
+use std::io::{Read, Write};
+use std::ops;
 use std::path;
 use std::sync;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+use log::{debug, trace, warn};
+
+use crate::backend::{Backend, SegmentBackend};
+use crate::codec::{self, Codec};
+use crate::eviction::{EvictionPolicy, LruPolicy};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+#[cfg(feature = "encryption")]
+use chacha20poly1305::ChaCha20Poly1305;
 
 // ref: https://doc.rust-lang.org/stable/rust-by-example/error/multiple_error_types/wrap_error.html
-type DiskvResult<T> = Result<T, DiskvError>;
+pub type DiskvResult<T> = Result<T, DiskvError>;
 
 #[derive(Debug)]
 pub enum DiskvError {
     IOError(io::Error),
+    InvalidKey(String),
+    ValueTooLarge {
+        size: u64,
+        limit: u64,
+    },
+    NotANumber(String),
+    CorruptArchive(String),
+    ReadOnly,
+    NotADirectory(path::PathBuf),
+    ChecksumMismatch {
+        key: String,
+    },
+    #[cfg(feature = "encryption")]
+    DecryptionFailed {
+        key: String,
+    },
+    KeyCollision {
+        key: String,
+    },
+    OutOfSpace(io::Error),
+    KeyIOError {
+        op: &'static str,
+        key: String,
+        path: path::PathBuf,
+        source: io::Error,
+    },
+    NotFound {
+        key: String,
+    },
+    Timeout,
+    #[cfg(feature = "serde")]
+    Serde(serde_json::Error),
+    InvalidUtf8 {
+        key: String,
+    },
+    ConcurrentModification {
+        key: String,
+    },
 }
 
 impl fmt::Display for DiskvError {
@@ -19,6 +75,54 @@ impl fmt::Display for DiskvError {
         match self {
             // this is wrapper so defer to underlying type's impl of fmt
             DiskvError::IOError(e) => e.fmt(f),
+            DiskvError::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+            DiskvError::ValueTooLarge { size, limit } => write!(
+                f,
+                "value of {} bytes exceeds limit of {} bytes",
+                size, limit
+            ),
+            DiskvError::NotANumber(key) => write!(f, "value of key {} is not a number", key),
+            DiskvError::CorruptArchive(reason) => write!(f, "corrupt archive: {}", reason),
+            DiskvError::ReadOnly => write!(f, "store is read-only"),
+            DiskvError::NotADirectory(path) => write!(f, "{} is not a directory", path.display()),
+            DiskvError::ChecksumMismatch { key } => {
+                write!(f, "checksum mismatch, value of key {} is corrupt", key)
+            }
+            #[cfg(feature = "encryption")]
+            DiskvError::DecryptionFailed { key } => write!(
+                f,
+                "value of key {} can't be decrypted, wrong key or corrupt",
+                key
+            ),
+            DiskvError::KeyCollision { key } => write!(
+                f,
+                "file name of key {} is taken by another key with the same hash",
+                key
+            ),
+            DiskvError::OutOfSpace(e) => write!(f, "out of disk space: {}", e),
+            DiskvError::KeyIOError {
+                op,
+                key,
+                path,
+                source,
+            } => write!(
+                f,
+                "failed to {} key '{}' at {}: {}",
+                op,
+                key,
+                path.display(),
+                source
+            ),
+            DiskvError::NotFound { key } => write!(f, "key {} not found", key),
+            DiskvError::Timeout => write!(f, "storage operation timed out"),
+            #[cfg(feature = "serde")]
+            DiskvError::Serde(e) => e.fmt(f),
+            DiskvError::InvalidUtf8 { key } => write!(f, "value of key {} is not valid UTF-8", key),
+            DiskvError::ConcurrentModification { key } => write!(
+                f,
+                "value of key {} was modified by another writer since it was cached",
+                key
+            ),
         }
     }
 }
@@ -27,6 +131,24 @@ impl error::Error for DiskvError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             DiskvError::IOError(e) => Some(e),
+            DiskvError::InvalidKey(_) => None,
+            DiskvError::ValueTooLarge { .. } => None,
+            DiskvError::NotANumber(_) => None,
+            DiskvError::CorruptArchive(_) => None,
+            DiskvError::ReadOnly => None,
+            DiskvError::NotADirectory(_) => None,
+            DiskvError::ChecksumMismatch { .. } => None,
+            #[cfg(feature = "encryption")]
+            DiskvError::DecryptionFailed { .. } => None,
+            DiskvError::KeyCollision { .. } => None,
+            DiskvError::OutOfSpace(e) => Some(e),
+            DiskvError::KeyIOError { source, .. } => Some(source),
+            DiskvError::NotFound { .. } => None,
+            DiskvError::Timeout => None,
+            #[cfg(feature = "serde")]
+            DiskvError::Serde(e) => Some(e),
+            DiskvError::InvalidUtf8 { .. } => None,
+            DiskvError::ConcurrentModification { .. } => None,
         }
     }
 }
@@ -37,329 +159,8503 @@ impl From<io::Error> for DiskvError {
     }
 }
 
-//
-// DiskvCache
-// This is HashMap backed in-memory cache used by Diskv. Its not exposed to client of Diskv.
-// cache_size_max controls amount of bytes to be cached. If any value is larger than cache_size_max, it is not cached.
-// keys are not considered as part of cache size.
-//
-#[derive(Debug)]
-pub struct DiskvCache {
-    cache: HashMap<String, Vec<u8>>,
-    cache_size: u32,
-    cache_size_max: u32,
-}
-
-impl DiskvCache {
-    fn new(cache_size_max: u32) -> DiskvCache {
-        DiskvCache {
-            cache: HashMap::new(),
-            cache_size: 0,
-            cache_size_max: cache_size_max,
+// errors of writes which ran out of disk space, as OutOfSpace so that callers can tell them from other IO errors
+fn out_of_space(e: DiskvError) -> DiskvError {
+    match e {
+        DiskvError::IOError(e)
+            if e.kind() == io::ErrorKind::StorageFull || e.kind() == io::ErrorKind::WriteZero =>
+        {
+            DiskvError::OutOfSpace(e)
         }
+        e => e,
     }
+}
 
-    fn make_space_for(&mut self, val_len: u32) {
-        let mut keys_to_delete: Vec<String> = Vec::new();
-        let mut key_sizes: u32 = 0;
-        for (k, v) in self.cache.iter() {
-            key_sizes += v.len() as u32;
-            keys_to_delete.push(k.to_string());
-            if self.cache_size - key_sizes >= val_len {
-                break;
-            }
-        }
-        for k in keys_to_delete.iter() {
-            self.delete(k);
-        }
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for DiskvError {
+    fn from(e: serde_json::Error) -> DiskvError {
+        DiskvError::Serde(e)
     }
+}
 
-    fn put(&mut self, key: &String, val: Vec<u8>) {
-        let val_len = val.len() as u32;
-        if val_len > self.cache_size_max {
-            eprintln!(
-                "==> cache max size: {}, val size: {}, ignored.",
-                self.cache_size_max, val_len
-            );
-            return;
-        }
+//
+// Expiry
+// Keys put with a TTL get a sidecar file, named file name of the key + META_SUFFIX, holding the expiry time as
+// little endian milliseconds since UNIX epoch. Keys without the sidecar never expire.
+//
+const META_SUFFIX: &str = ".meta";
 
-        self.delete(&key);
-        if self.cache_size + val_len > self.cache_size_max {
-            eprintln!("==> cache full, making space");
-            self.make_space_for(val_len);
-        }
+// values are first written to a temporary file named file name + TMP_INFIX + pid + "." + a count of temporary files
+// of the process, so that no two writers ever share one, which then gets renamed over the actual file. stray temporary
+// files, left behind by a crash, are never read as values.
+const TMP_INFIX: &str = ".tmp.";
 
-        if self.cache_size + val_len > self.cache_size_max {
-            panic!("couldn't make space for given key");
-        }
+static TMP_COUNT: AtomicU64 = AtomicU64::new(0);
 
-        self.cache.insert(key.clone(), val);
-        self.cache_size += val_len;
-        eprintln!("==> cached. cache_size: {}", self.cache_size);
+fn is_expired(expires_at: Option<time::SystemTime>) -> bool {
+    match expires_at {
+        Some(t) => time::SystemTime::now() >= t,
+        None => false,
     }
+}
 
-    fn get(&self, key: &String) -> Option<Vec<u8>> {
-        match self.cache.get(key) {
-            Some(v) => {
-                eprintln!("==> cache hit. key: {}", key);
-                Some(v.to_vec())
-            }
-            None => {
-                eprintln!("==> cache miss. key: {}", key);
-                None
-            }
-        }
-    }
+fn encode_expiry(expires_at: time::SystemTime) -> Vec<u8> {
+    let millis = expires_at
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64;
+    millis.to_le_bytes().to_vec()
+}
 
-    fn delete(&mut self, key: &String) {
-        match self.cache.remove_entry(key) {
-            Some(v) => {
-                eprintln!("==> cached. cache_size: {}", self.cache_size);
-                self.cache_size -= v.1.len() as u32
-            }
-            None => return,
+fn decode_expiry(buf: &[u8]) -> DiskvResult<time::SystemTime> {
+    match buf.try_into() {
+        Ok(millis) => {
+            Ok(time::UNIX_EPOCH + time::Duration::from_millis(u64::from_le_bytes(millis)))
         }
+        Err(_) => Err(DiskvError::IOError(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupt expiry file",
+        ))),
     }
 }
 
 //
-// Options
+// Key encoding
+// Keys are mapped to file names which are safe to create right under base_path: bytes other than ASCII letters,
+// digits, '-' and '_' are escaped as %XX. So a key can never name a nested path or point outside base_path, and a '.'
+// in a file name always belongs to META_SUFFIX or TMP_INFIX. Names longer than MAX_NAME_LEN are replaced with
+// HASHED_PREFIX followed by a hash of the key in hex, see KeyHasher, and because that can't be reversed, the key is
+// stored in a header in front of the value: key length as u32 little endian, followed by the key.
+// Keys whose hashes collide are chained: the first one put gets the hashed name, those after it the hashed name
+// followed by -1, -2 and so on. A key is found by reading key headers along its chain, up to the slot holding it, or
+// the first free one, which is where a new key goes, see locate_name. Deleting a key moves the last key of its chain
+// into its slot, so chains never have gaps. Locks are striped by file name, so all keys of a chain share a lock.
 //
-pub struct Options {
-    pub base_path: String,
-    pub cache_size_max: u32,
+const MAX_NAME_LEN: usize = 200;
+const MAX_KEY_LEN: usize = 255;
+const HASHED_PREFIX: &str = "+";
+
+enum FileName {
+    Escaped(Vec<u8>),
+    Hashed,
 }
 
 //
-// Diskv
-// This is disk backed, cache supported KV store.
-// RwLock is used to serialize write/delete operations where as read operations can run in parallel.
+// KeyHasher
+// Hash giving file names to keys too long to be escaped, see Key encoding. Fnv1a, the default, is fast and with 64
+// bits collisions are unlikely, but possible. Sha256, with the sha2 feature, and Blake3, with the blake3 feature, make
+// them practically impossible, at the cost of longer names and slower hashing. DefaultHasher is std's SipHash, whose
+// output may change between Rust releases, so only suits stores which don't outlive the binary. Custom takes any 64
+// bit hash. Changing the hasher of an existing store makes values of its hashed keys unreachable.
 //
-pub struct Diskv {
-    options: Options,
-    cache: sync::RwLock<DiskvCache>,
+#[derive(Debug, Clone, Copy, Default)]
+pub enum KeyHasher {
+    #[default]
+    Fnv1a,
+    #[cfg(feature = "sha2")]
+    Sha256,
+    #[cfg(feature = "blake3")]
+    Blake3,
+    DefaultHasher,
+    Custom(fn(&[u8]) -> u64),
 }
 
-impl fmt::Display for Diskv {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "base path: {}", self.options.base_path)?;
-        writeln!(f, "locked: {:?}", self.cache)
+impl KeyHasher {
+    fn hash_hex(&self, key: &[u8]) -> String {
+        match self {
+            KeyHasher::Fnv1a => format!("{:016x}", fnv1a(key)),
+            #[cfg(feature = "sha2")]
+            KeyHasher::Sha256 => {
+                use sha2::Digest;
+                sha2::Sha256::digest(key)
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect()
+            }
+            #[cfg(feature = "blake3")]
+            KeyHasher::Blake3 => blake3::hash(key).to_hex().to_string(),
+            KeyHasher::DefaultHasher => {
+                use std::hash::Hasher;
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                hasher.write(key);
+                format!("{:016x}", hasher.finish())
+            }
+            KeyHasher::Custom(hash) => format!("{:016x}", hash(key)),
+        }
     }
 }
 
-impl Diskv {
-    pub fn new(options: Options) -> DiskvResult<Diskv> {
-        fs::create_dir_all(&options.base_path)?;
-        let cache_size_max = options.cache_size_max;
-        Ok(Diskv {
-            options: options,
-            cache: sync::RwLock::new(DiskvCache::new(cache_size_max)),
-        })
+fn escape_name(key: &[u8]) -> String {
+    let mut name = String::with_capacity(key.len());
+    for b in key {
+        if b.is_ascii_alphanumeric() || *b == b'-' || *b == b'_' {
+            name.push(*b as char);
+        } else {
+            name.push_str(&format!("%{:02X}", b));
+        }
     }
+    name
+}
 
-    pub fn put(&self, key: &String, val: Vec<u8>) -> Result<(), DiskvError> {
-        let cache_val = val.clone();
-        let mut cache = self.cache.write().unwrap(); // write lock
-        match fs::write(path::Path::new(&self.options.base_path).join(key), val) {
-            Ok(_) => Ok(cache.put(key, cache_val)),
-            Err(e) => Err(DiskvError::IOError(e)),
-        }
+fn encode_name(key: &[u8], hasher: KeyHasher) -> String {
+    let name = escape_name(key);
+    if name.len() > MAX_NAME_LEN {
+        return format!("{}{}", HASHED_PREFIX, hasher.hash_hex(key));
     }
+    name
+}
 
-    fn try_get(&self, key: &String) -> Result<Option<Vec<u8>>, DiskvError> {
-        let cache = self.cache.read().unwrap(); // read lock
-        match cache.get(&key) {
-            Some(v) => Ok(Some(v)),
-            None => Ok(None),
+fn meta_name_of(name: &str) -> String {
+    format!("{}{}", name, META_SUFFIX)
+}
+
+// slot of the chain of hashed name base, see Key encoding
+fn slot_name(base: &str, slot: usize) -> String {
+    if slot == 0 {
+        return base.to_string();
+    }
+    format!("{}-{}", base, slot)
+}
+
+// name of the value file of key if it's stored, or else the name it gets when put: with a hashed name, the slot of
+// its chain holding key, or the first free one
+fn locate_name(storage: &Storage, key: &[u8], hasher: KeyHasher) -> io::Result<String> {
+    let base = encode_name(key, hasher);
+    if !base.starts_with(HASHED_PREFIX) {
+        return Ok(base);
+    }
+    let mut slot = 0;
+    loop {
+        let name = slot_name(&base, slot);
+        match storage.read_key_header(&name) {
+            Ok(stored) if stored == key => return Ok(name),
+            Ok(_) => slot += 1,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(name),
+            Err(e) => return Err(e),
         }
     }
+}
 
-    pub fn get(&self, key: &String) -> Result<Option<Vec<u8>>, DiskvError> {
-        match self.try_get(key) { // read lock released
-            Ok(v) => match v {
-                Some(v) => Ok(Some(v)),
-                None => match fs::read(path::Path::new(&self.options.base_path).join(&key)) {
-                    Ok(v) => {
-                        self.put(&key, v.clone())?; // write lock acquired
-                        Ok(Some(v))
-                    }
-                    Err(e) => {
-                        if e.kind() == io::ErrorKind::NotFound {
-                            Ok(None)
-                        } else {
-                            Err(DiskvError::IOError(e))
-                        }
-                    }
-                },
-            },
-            Err(e) => Err(e),
+// shard of the lock of key among n shards. keys of a chain have the same name to hash, see Key encoding.
+fn shard_of(key: &[u8], hasher: KeyHasher, n: usize) -> usize {
+    (fnv1a(encode_name(key, hasher).as_bytes()) % n as u64) as usize
+}
+
+// None for names which encode_name can't produce, those are not values
+fn parse_name(name: &str) -> Option<FileName> {
+    if let Some(hash) = name.strip_prefix(HASHED_PREFIX) {
+        // slot of a chain
+        let hash = match hash.split_once('-') {
+            Some((hash, slot))
+                if !slot.starts_with('0') && slot.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                hash
+            }
+            Some(_) => return None,
+            None => hash,
+        };
+        if (hash.len() == 16 || hash.len() == 64)
+            && hash
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+        {
+            return Some(FileName::Hashed);
         }
+        return None;
     }
 
-    pub fn delete(&self, key: &String) -> Result<(), DiskvError> {
-        let mut cache = self.cache.write().unwrap(); // write lock
-        match fs::remove_file(path::Path::new(&self.options.base_path).join(key)) {
-            Ok(_) => Ok(cache.delete(key)),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::NotFound {
-                    Ok(())
-                } else {
-                    Err(DiskvError::IOError(e))
-                }
+    let mut key = Vec::with_capacity(name.len());
+    let mut bytes = name.bytes();
+    while let Some(b) = bytes.next() {
+        if b.is_ascii_alphanumeric() || b == b'-' || b == b'_' {
+            key.push(b);
+        } else if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            if hex.bytes().any(|b| b.is_ascii_lowercase()) {
+                return None;
             }
+            key.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            return None;
         }
     }
+    if key.is_empty() {
+        return None;
+    }
+    Some(FileName::Escaped(key))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+// FNV-1a, it's simple and, unlike DefaultHasher, stable across releases
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
 
-    #[test]
-    fn cache_get_put_get_put_get_delete_get() {
-        let key = String::from("k1");
+fn is_hashed(key: &[u8]) -> bool {
+    escape_name(key).len() > MAX_NAME_LEN
+}
 
-        let mut c = DiskvCache::new(10);
-        assert_eq!(None, c.get(&key));
+// With a checksum, the encoded value in a file is preceded by CHECKSUM_MARKER, which is no codec's marker, and CRC-32
+// of the value as u32 little endian. Files without one, written with Options::verify_checksums off, start with the
+// codec marker right away.
+const CHECKSUM_MARKER: u8 = 0xff;
+const CHECKSUM_LEN: usize = 1 + 4;
 
-        c.put(&key, String::from("abcd").into_bytes());
-        assert_eq!(Some(String::from("abcd").into_bytes()), c.get(&key));
+// Encrypted files, see Options::encryption_key, have ENCRYPTED_MARKER right after the key header, followed by the
+// nonce and everything else, checksum included, sealed with ChaCha20-Poly1305. The key is authenticated along with
+// it, so a file copied over the file of another key doesn't decrypt. Nonces are random, from getrandom, so the
+// same value encrypts differently every time it's written.
+const ENCRYPTED_MARKER: u8 = 0xfe;
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 12;
 
-        c.put(&key, String::from("pqrs").into_bytes());
-        assert_eq!(Some(String::from("pqrs").into_bytes()), c.get(&key));
+// how a value is written to its file
+struct FileFormat<'a> {
+    codec: Codec,
+    checksum: bool,
+    encryption_key: Option<&'a [u8; 32]>,
+}
 
-        c.delete(&key);
-        assert_eq!(None, c.get(&key));
+// file content for key: key header when its name is hashed, then the checksum if asked for, followed by the value
+// encoded with codec. all but the key header get encrypted with an encryption key.
+fn encode_file(key: &[u8], val: &[u8], format: &FileFormat) -> io::Result<Vec<u8>> {
+    let encoded = codec::encode(format.codec, val)?;
+    let mut body = Vec::with_capacity(CHECKSUM_LEN + encoded.len());
+    if format.checksum {
+        body.push(CHECKSUM_MARKER);
+        body.extend_from_slice(&crc32fast::hash(val).to_le_bytes());
+    }
+    body.extend_from_slice(&encoded);
+    if let Some(secret) = format.encryption_key {
+        body = encrypt(secret, key, &body)?;
+    }
+    if !is_hashed(key) {
+        return Ok(body);
     }
+    let mut buf = Vec::with_capacity(4 + key.len() + body.len());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(&body);
+    Ok(buf)
+}
 
-    #[test]
-    fn cache_key_overwrite_size_check() {
-        let key1 = String::from("k1");
-        let mut c = DiskvCache::new(10);
-        assert_eq!(None, c.get(&key1));
+// value from file content of key, KeyCollision if the file belongs to another key with the same hash. checksum, if
+// there is one, is verified only when asked to. files which are not encrypted are read with an encryption key set as
+// well.
+fn decode_file(key: &[u8], buf: &[u8], format: &FileFormat) -> DiskvResult<Vec<u8>> {
+    let body = if is_hashed(key) {
+        let (stored_key, body) = split_key_header(buf)?;
+        if stored_key != key {
+            return Err(key_collision(key));
+        }
+        body
+    } else {
+        buf
+    };
+    let decrypted;
+    let body = match body.split_first() {
+        Some((&ENCRYPTED_MARKER, sealed)) => {
+            decrypted = decrypt(format.encryption_key, key, sealed)?;
+            &decrypted[..]
+        }
+        _ => body,
+    };
+    let verify = format.checksum;
+    let (checksum, encoded) = split_checksum(body)?;
+    let val = codec::decode(encoded)?;
+    match checksum {
+        Some(checksum) if verify && checksum != crc32fast::hash(&val) => {
+            Err(DiskvError::ChecksumMismatch {
+                key: key_string(key),
+            })
+        }
+        _ => Ok(val),
+    }
+}
+
+fn key_collision(key: &[u8]) -> DiskvError {
+    DiskvError::KeyCollision {
+        key: key_string(key),
+    }
+}
 
-        c.put(&key1, String::from("0123456789").into_bytes());
-        assert_eq!(Some(String::from("0123456789").into_bytes()), c.get(&key1));
+// key as errors and logs name it, keys which are not valid UTF-8, see Diskv::put_bytes, being converted lossily
+fn key_string(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
 
-        c.put(&key1, String::from("9876543210").into_bytes());
-        assert_eq!(Some(String::from("9876543210").into_bytes()), c.get(&key1));
+// ENCRYPTED_MARKER, nonce and body sealed along with key
+#[cfg(feature = "encryption")]
+fn encrypt(secret: &[u8; 32], key: &[u8], body: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+    let sealed = ChaCha20Poly1305::new(secret.into())
+        .encrypt(
+            &nonce.into(),
+            Payload {
+                msg: body,
+                aad: key,
+            },
+        )
+        .map_err(|_| io::Error::other("failed to encrypt value"))?;
+    let mut buf = Vec::with_capacity(1 + NONCE_LEN + sealed.len());
+    buf.push(ENCRYPTED_MARKER);
+    buf.extend_from_slice(&nonce);
+    buf.extend_from_slice(&sealed);
+    Ok(buf)
+}
 
-        c.put(&key1, String::from("123").into_bytes());
-        assert_eq!(Some(String::from("123").into_bytes()), c.get(&key1));
+#[cfg(feature = "encryption")]
+fn decrypt(secret: Option<&[u8; 32]>, key: &[u8], sealed: &[u8]) -> DiskvResult<Vec<u8>> {
+    let failed = || DiskvError::DecryptionFailed {
+        key: key_string(key),
+    };
+    let secret = secret.ok_or_else(failed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(failed());
     }
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    ChaCha20Poly1305::new(secret.into())
+        .decrypt(
+            nonce.into(),
+            Payload {
+                msg: sealed,
+                aad: key,
+            },
+        )
+        .map_err(|_| failed())
+}
 
-    #[test]
-    fn cache_make_space() {
-        let key1 = String::from("k1");
-        let key2 = String::from("k2");
-        let key3 = String::from("k3");
+// without the feature there is never an encryption key to encrypt with
+#[cfg(not(feature = "encryption"))]
+fn encrypt(_secret: &[u8; 32], _key: &[u8], body: &[u8]) -> io::Result<Vec<u8>> {
+    Ok(body.to_vec())
+}
 
-        let mut c = DiskvCache::new(10);
-        assert_eq!(None, c.get(&key1));
-        assert_eq!(None, c.get(&key2));
-        assert_eq!(None, c.get(&key3));
+#[cfg(not(feature = "encryption"))]
+fn decrypt(_secret: Option<&[u8; 32]>, _key: &[u8], _sealed: &[u8]) -> DiskvResult<Vec<u8>> {
+    Err(DiskvError::IOError(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "value is encrypted, and encryption is not enabled",
+    )))
+}
+
+// checksum, if buf starts with one, and the rest of buf
+fn split_checksum(buf: &[u8]) -> io::Result<(Option<u32>, &[u8])> {
+    if buf.first() != Some(&CHECKSUM_MARKER) {
+        return Ok((None, buf));
+    }
+    if buf.len() < CHECKSUM_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupt checksum",
+        ));
+    }
+    let (checksum, rest) = buf.split_at(CHECKSUM_LEN);
+    Ok((
+        Some(u32::from_le_bytes(checksum[1..].try_into().unwrap())),
+        rest,
+    ))
+}
+
+fn split_key_header(buf: &[u8]) -> io::Result<(&[u8], &[u8])> {
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt key header");
+    if buf.len() < 4 {
+        return Err(corrupt());
+    }
+    let (len, rest) = buf.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(corrupt());
+    }
+    Ok(rest.split_at(len))
+}
+
+// reads just the key header of a hashed file
+fn read_key_header(path: &path::Path) -> io::Result<Vec<u8>> {
+    let mut f = fs::File::open(path)?;
+    let mut len = [0u8; 4];
+    f.read_exact(&mut len)?;
+    let mut key = vec![0u8; u32::from_le_bytes(len) as usize];
+    f.read_exact(&mut key)?;
+    Ok(key)
+}
+
+//
+// DiskvCache
+// This is HashMap backed in-memory cache used by Diskv. Its not exposed to client of Diskv.
+// cache_size_max controls amount of bytes to be cached. If any value is larger than cache_size_max, it is not cached.
+// A cache_size_max of 0 disables the cache: put stores nothing, not even empty values, and nothing is counted ignored.
+// keys are not considered as part of cache size. cache_max_entries, if set, bounds the number of entries as well, so
+// that lots of tiny values can't pile up HashMap overhead. Both bounds are enforced by every put.
+// With adaptive set, cache_size_max moves between its floor and ceiling as the hit rate goes, see AdaptiveCache.
+// When space is needed, entries are evicted in the order the eviction policy picks, least recently used first by
+// default. The policy sits behind its own mutex so that get can tell it about accesses with a shared reference (under
+// read lock). Expired entries are treated as misses.
+//
+pub struct DiskvCache {
+    cache: HashMap<Vec<u8>, CacheEntry>,
+    cache_size: u64,
+    cache_size_max: u64,
+    cache_max_entries: Option<usize>,
+    adaptive: Option<AdaptiveCache>,
+    policy: sync::Mutex<Box<dyn EvictionPolicy>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    ignored: AtomicU64,
+    window_hits: AtomicU64,
+    window_lookups: AtomicU64,
+}
 
-        c.put(&key1, String::from("0123456").into_bytes());
-        assert_eq!(Some(String::from("0123456").into_bytes()), c.get(&key1));
+//
+// CacheStats
+// Snapshot of cache counters, as returned by Diskv::stats. ignored counts values not cached for being larger than
+// cache_size_max. cache_size_max is the limit in effect, which only differs from Options::cache_size_max with
+// Options::adaptive_cache.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub ignored: u64,
+    pub cache_size: u64,
+    pub entries: usize,
+    pub cache_size_max: u64,
+}
 
-        c.put(&key2, String::from("789").into_bytes());
-        assert_eq!(Some(String::from("789").into_bytes()), c.get(&key2));
-        assert_eq!(Some(String::from("0123456").into_bytes()), c.get(&key1));
+//
+// AdaptiveCache
+// Options::adaptive_cache lets cache_size_max follow the hit rate. After every window lookups of a shard, its limit
+// doubles, up to ceiling, if more than grow_above of them were hits, and halves, down to floor, if fewer than
+// shrink_below were, evicting values which no longer fit. Rates are fractions between 0 and 1. The initial limit is
+// cache_size_max, brought within floor and ceiling, which like cache_size_max are shared out evenly among shards.
+//
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveCache {
+    pub floor: u64,
+    pub ceiling: u64,
+    pub window: u64,
+    pub grow_above: f64,
+    pub shrink_below: f64,
+}
 
-        c.put(&key3, String::from("abcdabcd").into_bytes());
-        assert_eq!(Some(String::from("abcdabcd").into_bytes()), c.get(&key3));
-        assert_eq!(None, c.get(&key1));
-        assert_eq!(None, c.get(&key2));
+impl Default for AdaptiveCache {
+    fn default() -> AdaptiveCache {
+        AdaptiveCache {
+            floor: 256 * 1024,
+            ceiling: 16 * 1024 * 1024,
+            window: 1000,
+            grow_above: 0.9,
+            shrink_below: 0.5,
+        }
     }
+}
 
-    #[test]
-    fn cache_make_space_more() {
-        let key1 = String::from("k1");
-        let key2 = String::from("k2");
-        let key3 = String::from("k3");
+impl fmt::Debug for DiskvCache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DiskvCache")
+            .field("cache", &self.cache)
+            .field("cache_size", &self.cache_size)
+            .field("cache_size_max", &self.cache_size_max)
+            .field("cache_max_entries", &self.cache_max_entries)
+            .field("stats", &self.stats())
+            .finish_non_exhaustive()
+    }
+}
 
-        let mut c = DiskvCache::new(5);
-        assert_eq!(None, c.get(&key1));
-        assert_eq!(None, c.get(&key2));
-        assert_eq!(None, c.get(&key3));
+//
+// EntryMeta
+// What Diskv::metadata tells about a key: size of its value and the time it was last written.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub size: u64,
+    pub modified: time::SystemTime,
+}
 
-        c.put(&key1, String::from("aa").into_bytes());
-        assert_eq!(Some(String::from("aa").into_bytes()), c.get(&key1));
+#[derive(Debug)]
+struct CacheEntry {
+    val: Vec<u8>,
+    expires_at: Option<time::SystemTime>,
+    modified: Option<time::SystemTime>,
+}
+
+impl DiskvCache {
+    #[cfg(test)]
+    fn new(cache_size_max: u64) -> DiskvCache {
+        DiskvCache::with_policy(cache_size_max, None, None, Box::<LruPolicy>::default())
+    }
+
+    fn with_policy(
+        cache_size_max: u64,
+        cache_max_entries: Option<usize>,
+        adaptive: Option<AdaptiveCache>,
+        policy: Box<dyn EvictionPolicy>,
+    ) -> DiskvCache {
+        let cache_size_max = match &adaptive {
+            Some(a) => cache_size_max.min(a.ceiling).max(a.floor),
+            None => cache_size_max,
+        };
+        DiskvCache {
+            cache: HashMap::new(),
+            cache_size: 0,
+            cache_size_max,
+            cache_max_entries,
+            adaptive,
+            policy: sync::Mutex::new(policy),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            ignored: AtomicU64::new(0),
+            window_hits: AtomicU64::new(0),
+            window_lookups: AtomicU64::new(0),
+        }
+    }
 
-        c.put(&key2, String::from("bb").into_bytes());
-        assert_eq!(Some(String::from("bb").into_bytes()), c.get(&key2));
-        assert_eq!(Some(String::from("aa").into_bytes()), c.get(&key1));
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            cache_size: self.cache_size,
+            entries: self.cache.len(),
+            cache_size_max: self.cache_size_max,
+        }
+    }
 
-        c.put(&key3, String::from("cc").into_bytes());
-        assert_eq!(Some(String::from("cc").into_bytes()), c.get(&key3));
+    fn reset_stats(&self) {
+        self.hits.store(0, Ordering::Relaxed);
+        self.misses.store(0, Ordering::Relaxed);
+        self.evictions.store(0, Ordering::Relaxed);
+        self.ignored.store(0, Ordering::Relaxed);
+        self.window_hits.store(0, Ordering::Relaxed);
+        self.window_lookups.store(0, Ordering::Relaxed);
+    }
 
-        // only needed space is made - other keys stay intact
-        if c.get(&key1) == None {
-            assert_eq!(Some(String::from("bb").into_bytes()), c.get(&key2));
+    fn count_lookup(&self, hit: bool) {
+        if hit {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.window_hits.fetch_add(1, Ordering::Relaxed);
         } else {
-            assert_eq!(Some(String::from("aa").into_bytes()), c.get(&key1));
+            self.misses.fetch_add(1, Ordering::Relaxed);
         }
+        self.window_lookups.fetch_add(1, Ordering::Relaxed);
     }
 
-    #[test]
-    fn cache_ignore_large_vals() {
-        let key = String::from("k1");
-        let mut c = DiskvCache::new(10);
-        assert_eq!(None, c.get(&key));
+    // whether a window of lookups is over, so that adapt has something to do
+    fn adapt_due(&self) -> bool {
+        match &self.adaptive {
+            Some(a) => self.window_lookups.load(Ordering::Relaxed) >= a.window.max(1),
+            None => false,
+        }
+    }
+
+    // grows or shrinks cache_size_max by the hit rate of the window just over, see AdaptiveCache
+    fn adapt(&mut self) {
+        if !self.adapt_due() {
+            return;
+        }
+        let a = match self.adaptive {
+            Some(a) => a,
+            None => return,
+        };
+        let hits = self.window_hits.swap(0, Ordering::Relaxed);
+        let lookups = self.window_lookups.swap(0, Ordering::Relaxed);
+        let hit_rate = hits as f64 / lookups as f64;
+        if hit_rate > a.grow_above {
+            self.cache_size_max = self.cache_size_max.saturating_mul(2).min(a.ceiling);
+        } else if hit_rate < a.shrink_below {
+            self.cache_size_max = (self.cache_size_max / 2).max(a.floor);
+            self.make_space_for(0);
+        }
+        debug!(
+            "hit rate: {:.2}, cache max size: {}",
+            hit_rate, self.cache_size_max
+        );
+    }
 
-        c.put(&key, String::from("abcdpqrsxy").into_bytes()); // gets cached
-        assert_eq!(Some(String::from("abcdpqrsxy").into_bytes()), c.get(&key));
+    // policy only decides eviction order, so a poisoned lock is simply used as is
+    fn policy(&self) -> sync::MutexGuard<'_, Box<dyn EvictionPolicy>> {
+        self.policy.lock().unwrap_or_else(|e| e.into_inner())
+    }
 
-        c.put(&key, String::from("abcdpqrsxyz").into_bytes()); // won't get cached
-        assert_eq!(Some(String::from("abcdpqrsxy").into_bytes()), c.get(&key));
+    // evicts values until val_len more bytes fit, and with cache_max_entries one more entry, returning how many bytes
+    // of values were freed. evicting stops as soon as enough is freed, and also when the policy runs out of
+    // candidates.
+    fn make_space_for(&mut self, val_len: u64) -> u64 {
+        let needed = self
+            .cache_size
+            .saturating_add(val_len)
+            .saturating_sub(self.cache_size_max);
+        let mut freed = 0;
+        while freed < needed || self.entries_full() {
+            let k = match self.policy().evict_candidate() {
+                Some(k) => k,
+                None => break,
+            };
+            let evicted_len = match self.cache.get(&k) {
+                Some(e) => e.val.len() as u64,
+                None => continue, // policy lagging behind
+            };
+            debug!("evicting key: {}", String::from_utf8_lossy(&k));
+            self.delete(&k);
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+            freed += evicted_len;
+        }
+        freed
     }
 
-    #[test]
-    fn diskv_get_put_get() -> DiskvResult<()> {
-        let test_data_path = String::from("test_data");
-        let dkv = Diskv::new(Options {
-            base_path: test_data_path.clone(),
-            cache_size_max: 12,
-        })
-        .expect("failed to init diskv");
+    // keys put would evict to cache a value of val_len bytes of a new key, as make_space_for would pick them, without
+    // evicting them
+    fn simulate_eviction(&self, val_len: u64) -> Vec<Vec<u8>> {
+        if val_len > self.cache_size_max
+            || self.cache_max_entries == Some(0)
+            || (self.cache_size.saturating_add(val_len) <= self.cache_size_max
+                && !self.entries_full())
+        {
+            return Vec::new();
+        }
+        let needed = self
+            .cache_size
+            .saturating_add(val_len)
+            .saturating_sub(self.cache_size_max);
+        let mut freed = 0;
+        let mut entries = self.cache.len();
+        let mut evicted = Vec::new();
+        for k in self.policy().eviction_order() {
+            let entries_full = self.cache_max_entries.is_some_and(|max| entries >= max);
+            if freed >= needed && !entries_full {
+                break;
+            }
+            if let Some(e) = self.cache.get(&k) {
+                freed += e.val.len() as u64;
+                entries -= 1;
+                evicted.push(k);
+            }
+        }
+        evicted
+    }
 
-        let key1 = String::from("k1");
-        let key2 = String::from("k2");
+    // sets cache_size_max, evicting what no longer fits right away
+    fn resize(&mut self, cache_size_max: u64) {
+        self.cache_size_max = cache_size_max;
+        if self.cache_size > cache_size_max {
+            self.make_space_for(0);
+        }
+    }
 
-        dkv.put(&key2, String::from("aa").into_bytes())?;
-        assert_eq!(
-            String::from("aa").into_bytes(),
-            dkv.get(&key2).unwrap().unwrap()
-        );
+    fn disabled(&self) -> bool {
+        self.cache_size_max == 0
+    }
 
-        // get
-        assert!(dkv.get(&key1).unwrap().is_none());
+    fn entries_full(&self) -> bool {
+        match self.cache_max_entries {
+            Some(max) => self.cache.len() >= max,
+            None => false,
+        }
+    }
 
-        // put get
-        dkv.put(&key1, String::from("0123456789").into_bytes())?;
-        assert_eq!(
-            String::from("0123456789").into_bytes(),
-            dkv.get(&key1).unwrap().unwrap()
-        );
+    fn put(&mut self, key: &[u8], val: Vec<u8>) {
+        if self.disabled() {
+            return;
+        }
+        let val_len = val.len() as u64;
+        if val_len > self.cache_size_max {
+            warn!(
+                "cache max size: {}, val size: {}, ignored.",
+                self.cache_size_max, val_len
+            );
+            self.ignored.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
 
-        // put get
-        dkv.put(&key1, String::from("1111111111").into_bytes())?;
-        assert_eq!(
-            String::from("1111111111").into_bytes(),
-            dkv.get(&key1).unwrap().unwrap()
-        );
+        self.delete(key);
+        if self.cache_max_entries == Some(0) {
+            return;
+        }
+        if self.cache_size.saturating_add(val_len) > self.cache_size_max || self.entries_full() {
+            debug!("cache full, making space");
+            self.make_space_for(val_len);
+        }
 
-        // delete get
-        dkv.delete(&key1)?;
-        assert!(dkv.get(&key1).unwrap().is_none());
+        if self.cache_size.saturating_add(val_len) > self.cache_size_max || self.entries_full() {
+            panic!("couldn't make space for given key");
+        }
 
-        assert_eq!(
-            String::from("aa").into_bytes(),
-            dkv.get(&key2).unwrap().unwrap()
+        self.cache.insert(
+            key.to_vec(),
+            CacheEntry {
+                val,
+                expires_at: None,
+                modified: None,
+            },
         );
+        self.policy().on_insert(key);
+        self.cache_size += val_len;
+        trace!("cached. cache_size: {}", self.cache_size);
+    }
 
-        fs::remove_dir_all(&test_data_path)?;
+    // like get, without counting a lookup
+    fn peek(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.cache.get(key) {
+            Some(e) if !is_expired(e.expires_at) => {
+                self.policy().on_access(key);
+                Some(e.val.to_vec())
+            }
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.cache.get(key) {
+            Some(e) if is_expired(e.expires_at) => {
+                trace!("cache miss, expired. key: {}", String::from_utf8_lossy(key));
+                self.count_lookup(false);
+                None
+            }
+            Some(e) => {
+                trace!("cache hit. key: {}", String::from_utf8_lossy(key));
+                self.count_lookup(true);
+                self.policy().on_access(key);
+                Some(e.val.to_vec())
+            }
+            None => {
+                trace!("cache miss. key: {}", String::from_utf8_lossy(key));
+                self.count_lookup(false);
+                None
+            }
+        }
+    }
+
+    // no-op for keys which are not cached
+    // counts as use of key for the eviction policy, if it's cached
+    fn touch(&self, key: &[u8]) {
+        if self.contains(key) {
+            self.policy().on_access(key);
+        }
+    }
+
+    fn set_expiry(&mut self, key: &[u8], expires_at: Option<time::SystemTime>) {
+        if let Some(e) = self.cache.get_mut(key) {
+            e.expires_at = expires_at;
+        }
+    }
+
+    // modification time of the value file of key as it was when cached, see Options::detect_external_writes
+    fn set_modified(&mut self, key: &[u8], modified: Option<time::SystemTime>) {
+        if let Some(e) = self.cache.get_mut(key) {
+            e.modified = modified;
+        }
+    }
+
+    fn modified(&self, key: &[u8]) -> Option<time::SystemTime> {
+        self.cache.get(key)?.modified
+    }
+
+    fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.cache.keys().map(Vec::as_slice)
+    }
+
+    // like contains, expired entries included
+    fn holds(&self, key: &[u8]) -> bool {
+        self.cache.contains_key(key)
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        match self.cache.get(key) {
+            Some(e) => !is_expired(e.expires_at),
+            None => false,
+        }
+    }
+
+    fn clear(&mut self) {
+        let mut policy = self.policy();
+        for k in self.cache.keys() {
+            policy.on_remove(k);
+        }
+        drop(policy);
+        self.cache.clear();
+        self.cache_size = 0;
+        trace!("cache cleared");
+    }
+
+    fn delete(&mut self, key: &[u8]) {
+        self.take(key);
+    }
+
+    // like delete, returning the entry of key
+    fn take(&mut self, key: &[u8]) -> Option<CacheEntry> {
+        let (_, e) = self.cache.remove_entry(key)?;
+        self.policy().on_remove(key);
+        self.cache_size = self.cache_size.saturating_sub(e.val.len() as u64);
+        trace!(
+            "uncached. key: {}, cache_size: {}",
+            String::from_utf8_lossy(key),
+            self.cache_size
+        );
+        Some(e)
+    }
+}
+
+//
+// StorageFormat
+// How values are laid out under base_path. Files puts every value, and expiry file, in a file of its own. Segment
+// appends them all to a single segment file, SEGMENT_NAME, see SegmentBackend, which suits many small values better
+// but needs Diskv::compact now and then to reclaim the space of overwritten and deleted values. With Segment, wal and
+// dir_shard_depth are ignored, every write being a synced append already, and file_mode only applies to base_path.
+// Stores can't be switched from one to the other, values of the other format are not seen.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StorageFormat {
+    #[default]
+    Files,
+    Segment,
+}
+
+const SEGMENT_NAME: &str = "segment.log";
+
+//
+// WritePolicy
+// Whether writes populate the cache. WriteThrough caches every value written, WriteAround only writes it to disk,
+// uncaching the key, so that bulk loads of cold data don't evict the hot set. Either way get caches what it reads.
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WritePolicy {
+    #[default]
+    WriteThrough,
+    WriteAround,
+}
+
+//
+// Options
+// Fields not of interest can be left to their defaults with ..Default::default(), DiskvBuilder does that too.
+//
+pub struct Options {
+    pub base_path: path::PathBuf,
+    // 0 turns the cache off: every get reads from disk, without taking the write lock, and adaptive_cache is ignored
+    pub cache_size_max: u64,
+    pub codec: Codec,
+    // nothing is written to disk and base_path is ignored, values are gone once Diskv is dropped
+    pub in_memory: bool,
+    // constructs the policy of the cache, e.g. || Box::<FifoPolicy>::default()
+    pub eviction_policy: fn() -> Box<dyn EvictionPolicy>,
+    // write-ahead log, at the cost of writing every value twice. no effect in memory.
+    pub wal: bool,
+    // larger values fail with ValueTooLarge, None means no limit
+    pub max_value_size: Option<u64>,
+    // syncs base_path after every delete, else a deleted key can come back after a crash. values and expiry files are
+    // always synced as they are written, see Diskv::sync.
+    pub sync_on_write: bool,
+    // splits the cache and its lock into that many shards by hash of the key, cache_size_max being shared out evenly
+    pub cache_shards: usize,
+    // number of key locks, by hash of the key, see Diskv. writes of keys of different stripes run in parallel, so
+    // writers of many keys at once want it well above their thread count.
+    pub lock_stripes: usize,
+    // writes fail with ReadOnly and base_path isn't created. expired keys are reported absent but left on disk, the
+    // write-ahead log is neither replayed nor written.
+    pub read_only: bool,
+    // levels of sub-directories, at most 8, named after bytes of a hash of the key, e.g. base_path/3f/a0/key for 2.
+    // changing it for an existing store makes its values unreachable.
+    pub dir_shard_depth: usize,
+    // dropping Diskv syncs base_path and checkpoints the write-ahead log, errors only being logged. on by default,
+    // turning it off makes dropping fast for users who prefer speed.
+    pub sync_on_drop: bool,
+    // starts a thread deleting expired keys every interval. None leaves them to be deleted as they are read.
+    pub reaper_interval: Option<time::Duration>,
+    // stores a CRC-32 along with every value and checks it on read, failing with ChecksumMismatch. turned off, files
+    // written with one stay readable.
+    pub verify_checksums: bool,
+    // encrypts values written from then on with ChaCha20-Poly1305, values failing to decrypt fail with
+    // DecryptionFailed. keys are not encrypted, they are visible in file names.
+    #[cfg(feature = "encryption")]
+    pub encryption_key: Option<[u8; 32]>,
+    // size of the buffer, and of the chunks, value files are written with
+    pub write_buffer_size: usize,
+    // whether values written get cached, see WritePolicy
+    pub write_policy: WritePolicy,
+    // gives file names to keys too long to be escaped, see KeyHasher
+    pub key_hasher: KeyHasher,
+    // caps how many values get cached, whatever their size, shared out evenly among shards. None means no cap.
+    pub cache_max_entries: Option<usize>,
+    // lets cache_size_max grow and shrink with the hit rate of the cache, None keeps it fixed
+    pub adaptive_cache: Option<AdaptiveCache>,
+    // on Unix, permissions of files created under base_path, e.g. 0o600, further restricted by the umask. directories
+    // get search permission where read permission is. None leaves permissions to the umask.
+    pub file_mode: Option<u32>,
+    // reads and writes of files which take longer fail with Timeout, see Diskv::timed. None waits however long.
+    pub op_timeout: Option<time::Duration>,
+    // keeps files in a Backend rather than under base_path. in_memory, wal, dir_shard_depth, file_mode and
+    // sync_on_write are then ignored.
+    pub backend: Option<Box<dyn Backend>>,
+    // caps the total size of value files, writes deleting values of other keys to fit, see Diskv::make_disk_room.
+    // values larger than the cap fail with ValueTooLarge. None lets the store grow however large.
+    pub max_disk_bytes: Option<u64>,
+    // a file per value or a single segment file, see StorageFormat. in_memory and backend take precedence over it.
+    pub storage_format: StorageFormat,
+    // get stats the value file on every cache hit, so that a value deleted by another process isn't served from cache
+    pub verify_on_hit: bool,
+    // writes of a cached key fail with ConcurrentModification if another process modified its value file since, see
+    // Diskv::check_external_write. costs a stat per write.
+    pub detect_external_writes: bool,
+    // put, get and delete retry that many times on Interrupted or WouldBlock, io_retry_backoff apart and doubling with
+    // every retry. other errors are returned right away.
+    pub io_retries: u32,
+    pub io_retry_backoff: time::Duration,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            base_path: path::PathBuf::from("data"),
+            cache_size_max: 1024 * 1024,
+            codec: Codec::None,
+            in_memory: false,
+            eviction_policy: || Box::<LruPolicy>::default(),
+            wal: false,
+            max_value_size: None,
+            sync_on_write: false,
+            cache_shards: 1,
+            lock_stripes: 16,
+            read_only: false,
+            dir_shard_depth: 0,
+            sync_on_drop: true,
+            reaper_interval: None,
+            verify_checksums: true,
+            #[cfg(feature = "encryption")]
+            encryption_key: None,
+            write_buffer_size: 64 * 1024,
+            write_policy: WritePolicy::WriteThrough,
+            key_hasher: KeyHasher::Fnv1a,
+            cache_max_entries: None,
+            adaptive_cache: None,
+            file_mode: None,
+            op_timeout: None,
+            backend: None,
+            max_disk_bytes: None,
+            storage_format: StorageFormat::Files,
+            verify_on_hit: false,
+            detect_external_writes: false,
+            io_retries: 0,
+            io_retry_backoff: time::Duration::from_millis(10),
+        }
+    }
+}
+
+impl Options {
+    // options for an ephemeral store, handy in tests of code using Diskv
+    pub fn in_memory() -> Options {
+        Options {
+            in_memory: true,
+            ..Default::default()
+        }
+    }
+}
+
+//
+// DiskvBuilder
+// Recommended way of configuring Diskv: fields not set keep their defaults, so code using it keeps compiling as
+// Options grows. Setters are named after the Options fields, see there for what they do, those of optional fields
+// taking the value to set, e.g. max_value_size(1024) for Some(1024).
+//
+#[derive(Default)]
+pub struct DiskvBuilder {
+    options: Options,
+}
+
+impl DiskvBuilder {
+    pub fn new() -> DiskvBuilder {
+        DiskvBuilder::default()
+    }
+
+    pub fn base_path<P: Into<path::PathBuf>>(mut self, base_path: P) -> DiskvBuilder {
+        self.options.base_path = base_path.into();
+        self
+    }
+
+    pub fn cache_size_max(mut self, cache_size_max: u64) -> DiskvBuilder {
+        self.options.cache_size_max = cache_size_max;
+        self
+    }
+
+    pub fn codec(mut self, codec: Codec) -> DiskvBuilder {
+        self.options.codec = codec;
+        self
+    }
+
+    pub fn in_memory(mut self, in_memory: bool) -> DiskvBuilder {
+        self.options.in_memory = in_memory;
+        self
+    }
+
+    pub fn eviction_policy(mut self, policy: fn() -> Box<dyn EvictionPolicy>) -> DiskvBuilder {
+        self.options.eviction_policy = policy;
+        self
+    }
+
+    pub fn wal(mut self, wal: bool) -> DiskvBuilder {
+        self.options.wal = wal;
+        self
+    }
+
+    pub fn max_value_size(mut self, limit: u64) -> DiskvBuilder {
+        self.options.max_value_size = Some(limit);
+        self
+    }
+
+    pub fn sync_on_write(mut self, sync_on_write: bool) -> DiskvBuilder {
+        self.options.sync_on_write = sync_on_write;
+        self
+    }
+
+    pub fn cache_shards(mut self, cache_shards: usize) -> DiskvBuilder {
+        self.options.cache_shards = cache_shards;
+        self
+    }
+
+    pub fn lock_stripes(mut self, lock_stripes: usize) -> DiskvBuilder {
+        self.options.lock_stripes = lock_stripes;
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> DiskvBuilder {
+        self.options.read_only = read_only;
+        self
+    }
+
+    pub fn dir_shard_depth(mut self, dir_shard_depth: usize) -> DiskvBuilder {
+        self.options.dir_shard_depth = dir_shard_depth;
+        self
+    }
+
+    pub fn sync_on_drop(mut self, sync_on_drop: bool) -> DiskvBuilder {
+        self.options.sync_on_drop = sync_on_drop;
+        self
+    }
+
+    pub fn reaper_interval(mut self, interval: time::Duration) -> DiskvBuilder {
+        self.options.reaper_interval = Some(interval);
+        self
+    }
+
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> DiskvBuilder {
+        self.options.verify_checksums = verify_checksums;
+        self
+    }
+
+    #[cfg(feature = "encryption")]
+    pub fn encryption_key(mut self, key: [u8; 32]) -> DiskvBuilder {
+        self.options.encryption_key = Some(key);
+        self
+    }
+
+    pub fn write_buffer_size(mut self, write_buffer_size: usize) -> DiskvBuilder {
+        self.options.write_buffer_size = write_buffer_size;
+        self
+    }
+
+    pub fn write_policy(mut self, policy: WritePolicy) -> DiskvBuilder {
+        self.options.write_policy = policy;
+        self
+    }
+
+    pub fn key_hasher(mut self, hasher: KeyHasher) -> DiskvBuilder {
+        self.options.key_hasher = hasher;
+        self
+    }
+
+    pub fn cache_max_entries(mut self, max: usize) -> DiskvBuilder {
+        self.options.cache_max_entries = Some(max);
+        self
+    }
+
+    pub fn adaptive_cache(mut self, adaptive: AdaptiveCache) -> DiskvBuilder {
+        self.options.adaptive_cache = Some(adaptive);
+        self
+    }
+
+    pub fn file_mode(mut self, mode: u32) -> DiskvBuilder {
+        self.options.file_mode = Some(mode);
+        self
+    }
+
+    pub fn op_timeout(mut self, timeout: time::Duration) -> DiskvBuilder {
+        self.options.op_timeout = Some(timeout);
+        self
+    }
+
+    pub fn backend<B: Backend + 'static>(mut self, backend: B) -> DiskvBuilder {
+        self.options.backend = Some(Box::new(backend));
+        self
+    }
+
+    pub fn max_disk_bytes(mut self, limit: u64) -> DiskvBuilder {
+        self.options.max_disk_bytes = Some(limit);
+        self
+    }
+
+    pub fn storage_format(mut self, format: StorageFormat) -> DiskvBuilder {
+        self.options.storage_format = format;
+        self
+    }
+
+    pub fn verify_on_hit(mut self, verify_on_hit: bool) -> DiskvBuilder {
+        self.options.verify_on_hit = verify_on_hit;
+        self
+    }
+
+    pub fn detect_external_writes(mut self, detect_external_writes: bool) -> DiskvBuilder {
+        self.options.detect_external_writes = detect_external_writes;
+        self
+    }
+
+    pub fn io_retries(mut self, io_retries: u32) -> DiskvBuilder {
+        self.options.io_retries = io_retries;
+        self
+    }
+
+    pub fn io_retry_backoff(mut self, backoff: time::Duration) -> DiskvBuilder {
+        self.options.io_retry_backoff = backoff;
+        self
+    }
+
+    pub fn build(self) -> DiskvResult<Diskv> {
+        Diskv::new(self.options)
+    }
+}
+
+//
+// Diskv
+// This is disk backed, cache supported KV store. It can also be kept entirely in memory, see Options::in_memory.
+// Key locks, striped over Options::lock_stripes mutexes, serialize write/delete operations on a key. put and delete
+// hold the key lock while writing to disk, and the RwLock of the cache shard of the key only to update the cache, so
+// writes of keys of different stripes run in parallel. Other writes, and reads which miss the cache, hold the key
+// lock and the write lock of the shard throughout, where as reads hitting the cache only take its read lock. Key locks
+// are always taken before shard locks. Operations on the whole store, like clear, take all key locks and then the
+// locks of all shards, always in order. Keys sharing a hashed file name hash the same, so they always share a stripe
+// and a shard too.
+//
+pub struct Diskv {
+    options: Options,
+    storage: Arc<Storage>,
+    wal: Option<Arc<Wal>>,
+    shards: Arc<Vec<sync::RwLock<DiskvCache>>>,
+    key_locks: Arc<Vec<sync::Mutex<()>>>,
+    reaper: Option<Reaper>,
+}
+
+// key lock along with the write lock of the shard of the key, see Diskv::write_cache
+struct KeyWriteGuard<'a> {
+    cache: sync::RwLockWriteGuard<'a, DiskvCache>,
+    _key: sync::MutexGuard<'a, ()>,
+}
+
+impl ops::Deref for KeyWriteGuard<'_> {
+    type Target = DiskvCache;
+
+    fn deref(&self) -> &DiskvCache {
+        &self.cache
+    }
+}
+
+impl ops::DerefMut for KeyWriteGuard<'_> {
+    fn deref_mut(&mut self) -> &mut DiskvCache {
+        &mut self.cache
+    }
+}
+
+// all key locks along with the write locks of all shards, see Diskv::write_all
+struct WriteAllGuard<'a> {
+    shards: Vec<sync::RwLockWriteGuard<'a, DiskvCache>>,
+    _keys: Vec<sync::MutexGuard<'a, ()>>,
+}
+
+impl<'a> ops::Deref for WriteAllGuard<'a> {
+    type Target = [sync::RwLockWriteGuard<'a, DiskvCache>];
+
+    fn deref(&self) -> &Self::Target {
+        &self.shards
+    }
+}
+
+impl ops::DerefMut for WriteAllGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.shards
+    }
+}
+
+impl fmt::Display for Diskv {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &*self.storage {
+            Storage::Dir { dir, .. } => writeln!(f, "base path: {}", dir.display())?,
+            Storage::Memory(_) => writeln!(f, "in memory")?,
+            Storage::Backend(_) => writeln!(f, "custom backend")?,
+        }
+        writeln!(f, "locked: {:?}", self.shards)
+    }
+}
+
+impl Diskv {
+    pub fn new(mut options: Options) -> DiskvResult<Diskv> {
+        let storage = if let Some(backend) = options.backend.take() {
+            Storage::Backend(backend)
+        } else if options.in_memory {
+            Storage::Memory(sync::RwLock::new(MemoryFiles::new()))
+        } else {
+            if !options.read_only {
+                create_dir_all(&options.base_path, options.file_mode)?;
+                #[cfg(unix)]
+                if let Some(mode) = options.file_mode {
+                    use std::os::unix::fs::PermissionsExt;
+                    let permissions = fs::Permissions::from_mode(dir_mode(mode));
+                    fs::set_permissions(&options.base_path, permissions)?;
+                }
+            }
+            match options.storage_format {
+                StorageFormat::Files => Storage::dir(
+                    options.base_path.clone(),
+                    options.dir_shard_depth,
+                    options.write_buffer_size,
+                    options.file_mode,
+                ),
+                StorageFormat::Segment => Storage::Backend(Box::new(SegmentBackend::open_with(
+                    options.base_path.join(SEGMENT_NAME),
+                    options.read_only,
+                )?)),
+            }
+        };
+        let shard_count = options.cache_shards.max(1);
+        let stripe_count = options.lock_stripes.max(1);
+        let cache_size_max = options.cache_size_max / shard_count as u64;
+        let cache_max_entries = options.cache_max_entries.map(|n| n / shard_count);
+        let adaptive = options.adaptive_cache.filter(|_| cache_size_max > 0);
+        let adaptive = adaptive.map(|a| AdaptiveCache {
+            floor: a.floor / shard_count as u64,
+            ceiling: a.ceiling / shard_count as u64,
+            ..a
+        });
+        let shards = (0..shard_count)
+            .map(|_| {
+                let policy = (options.eviction_policy)();
+                sync::RwLock::new(DiskvCache::with_policy(
+                    cache_size_max,
+                    cache_max_entries,
+                    adaptive,
+                    policy,
+                ))
+            })
+            .collect();
+        let mut dkv = Diskv {
+            options,
+            storage: Arc::new(storage),
+            wal: None,
+            shards: Arc::new(shards),
+            key_locks: Arc::new((0..stripe_count).map(|_| sync::Mutex::new(())).collect()),
+            reaper: None,
+        };
+        let on_disk = matches!(*dkv.storage, Storage::Dir { .. });
+        if dkv.options.wal && on_disk && !dkv.options.read_only {
+            let (wal, records) =
+                Wal::open(&dkv.options.base_path.join(WAL_NAME), dkv.options.file_mode)?;
+            dkv.replay(records)?;
+            wal.truncate()?;
+            dkv.wal = Some(Arc::new(wal));
+        }
+        if let Some(interval) = dkv.options.reaper_interval {
+            if !dkv.options.read_only {
+                dkv.reaper = Some(Reaper::spawn(&dkv, interval)?);
+            }
+        }
+        Ok(dkv)
+    }
+
+    // like new, but base_path must already exist, and be a directory, so that a mistyped path fails rather than
+    // creating a new, empty store. missing base_path is reported as an IOError of kind NotFound.
+    pub fn open(options: Options) -> DiskvResult<Diskv> {
+        if !options.in_memory && options.backend.is_none() {
+            let meta = match fs::metadata(&options.base_path) {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(DiskvError::IOError(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("base path {} does not exist", options.base_path.display()),
+                    )))
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if !meta.is_dir() {
+                return Err(DiskvError::NotADirectory(options.base_path));
+            }
+        }
+        Diskv::new(options)
+    }
+
+    // applies logged writes again. they are idempotent, so it does not matter which of them made it to disk before
+    fn replay(&self, records: Vec<WalRecord>) -> DiskvResult<()> {
+        if !records.is_empty() {
+            debug!("replaying {} write-ahead log records", records.len());
+        }
+        for record in records {
+            match record {
+                WalRecord::Put {
+                    key,
+                    val,
+                    expires_at,
+                } => self.store_value(&key, &val, expires_at)?,
+                WalRecord::Delete { key } => self.remove_value(&key)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn log(&self, record: WalRecord) -> DiskvResult<()> {
+        if let Some(wal) = &self.wal {
+            wal.append(&record)?;
+        }
+        Ok(())
+    }
+
+    // truncates the write-ahead log. writes are applied before they return, so everything logged so far is already
+    // in value files, this only keeps the log from growing. no-op without a log.
+    pub fn checkpoint(&self) -> DiskvResult<()> {
+        self.check_writable()?;
+        let _shards = self.write_all()?; // write locks, keep writes out while truncating
+        if let Some(wal) = &self.wal {
+            wal.truncate()?;
+        }
+        Ok(())
+    }
+
+    fn shard_index(&self, key: &[u8]) -> usize {
+        shard_of(key, self.options.key_hasher, self.shards.len())
+    }
+
+    fn shard(&self, key: &[u8]) -> &sync::RwLock<DiskvCache> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    fn stripe_index(&self, key: &[u8]) -> usize {
+        shard_of(key, self.options.key_hasher, self.key_locks.len())
+    }
+
+    fn lock_key(&self, key: &[u8]) -> sync::MutexGuard<'_, ()> {
+        lock_stripe(&self.key_locks[self.stripe_index(key)])
+    }
+
+    // key lock of key, then the write lock of its shard
+    fn write_cache(&self, key: &[u8]) -> DiskvResult<KeyWriteGuard<'_>> {
+        let key_lock = self.lock_key(key);
+        Ok(KeyWriteGuard {
+            cache: Diskv::write_shard(self.shard(key)),
+            _key: key_lock,
+        })
+    }
+
+    // read lock of the shard of key
+    fn read_cache(&self, key: &[u8]) -> DiskvResult<sync::RwLockReadGuard<'_, DiskvCache>> {
+        Ok(Diskv::read_shard(self.shard(key)))
+    }
+
+    // all key locks, then write locks of all shards, both in order
+    fn write_all(&self) -> DiskvResult<WriteAllGuard<'_>> {
+        let keys = self.key_locks.iter().map(lock_stripe).collect();
+        let shards = self.shards.iter().map(Diskv::write_shard).collect();
+        Ok(WriteAllGuard {
+            shards,
+            _keys: keys,
+        })
+    }
+
+    // cache lock gets poisoned when a thread panics while holding it, possibly leaving the cache inconsistent.
+    // values are all in storage anyway, so the cache is emptied and the lock is un-poisoned, the only loss being
+    // cached entries. poison is always recovered from this way, so taking the lock never fails.
+    fn write_shard(shard: &sync::RwLock<DiskvCache>) -> sync::RwLockWriteGuard<'_, DiskvCache> {
+        match shard.write() {
+            Ok(cache) => cache,
+            Err(e) => {
+                warn!("cache lock poisoned, clearing the cache");
+                let mut cache = e.into_inner();
+                cache.clear();
+                shard.clear_poison();
+                cache
+            }
+        }
+    }
+
+    // recovers poisoned lock through write_shard, again should it get poisoned meanwhile
+    fn read_shard(shard: &sync::RwLock<DiskvCache>) -> sync::RwLockReadGuard<'_, DiskvCache> {
+        loop {
+            if let Ok(cache) = shard.read() {
+                return cache;
+            }
+            drop(Diskv::write_shard(shard));
+        }
+    }
+
+    // name of the value file of key, see locate_name
+    fn key_name(&self, key: &[u8]) -> io::Result<String> {
+        locate_name(&self.storage, key, self.options.key_hasher)
+    }
+
+    fn meta_name(&self, key: &[u8]) -> io::Result<String> {
+        Ok(meta_name_of(&self.key_name(key)?))
+    }
+
+    // empty key would name base_path itself. keys longer than MAX_KEY_LEN get hashed file names and would work, but
+    // they are rejected so that keys stay within the file name limit of common file systems.
+    fn check_key(key: &[u8]) -> DiskvResult<()> {
+        if key.is_empty() {
+            return Err(DiskvError::InvalidKey(String::from("key is empty")));
+        }
+        if key.len() > MAX_KEY_LEN {
+            return Err(DiskvError::InvalidKey(format!(
+                "key is {} bytes long, at most {} are allowed",
+                key.len(),
+                MAX_KEY_LEN
+            )));
+        }
+        Ok(())
+    }
+
+    // first thing every operation which writes does
+    fn check_writable(&self) -> DiskvResult<()> {
+        if self.options.read_only {
+            return Err(DiskvError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    // values are checked along with keys, before anything is written
+    fn check_value(&self, val: &[u8]) -> DiskvResult<()> {
+        let limits = [self.options.max_value_size, self.options.max_disk_bytes];
+        match limits.iter().flatten().min() {
+            Some(&limit) if val.len() as u64 > limit => Err(DiskvError::ValueTooLarge {
+                size: val.len() as u64,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn read_expiry(&self, key: &[u8]) -> DiskvResult<Option<time::SystemTime>> {
+        let name = self.meta_name(key)?;
+        match self.timed(move |storage| Ok(storage.read(&name)?))? {
+            Some(buf) => Ok(Some(decode_expiry(&buf)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put(&self, key: &str, val: Vec<u8>) -> Result<(), DiskvError> {
+        self.put_bytes(key.as_bytes(), val)
+    }
+
+    // key expires once ttl has passed, after which get returns None for it and removes it from disk.
+    pub fn put_with_ttl(&self, key: &str, val: Vec<u8>, ttl: time::Duration) -> DiskvResult<()> {
+        self.check_writable()?;
+        self.check_value(&val)?;
+        self.put_expiring(key.as_bytes(), val, Some(time::SystemTime::now() + ttl))
+    }
+
+    // like put, leaving val out of the cache whatever the write policy, as with WriteAround. a cached value of key is
+    // dropped, so it can't be returned in place of val. for bulk writes of values which won't be read soon.
+    pub fn put_no_cache(&self, key: &str, val: Vec<u8>) -> DiskvResult<()> {
+        self.check_writable()?;
+        self.check_value(&val)?;
+        Diskv::check_key(key.as_bytes())?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        self.write_value_as(
+            &mut cache,
+            key.as_bytes(),
+            val,
+            None,
+            WritePolicy::WriteAround,
+        )
+    }
+
+    fn put_expiring(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+    ) -> DiskvResult<()> {
+        Diskv::check_key(key)?;
+        let _key_lock = self.lock_key(key); // key lock
+        let res = self.write_value_unlocked(key, val, expires_at);
+        self.with_context(res, "write", key)
+    }
+
+    // writes value like write_value, taking the write lock of the shard of key only to check and update the cache, so
+    // that other keys of the shard can be written while the value is stored. caller must be holding the key lock.
+    fn write_value_unlocked(
+        &self,
+        key: &[u8],
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+    ) -> DiskvResult<()> {
+        {
+            let mut cache = Diskv::write_shard(self.shard(key)); // write lock
+            self.check_external_write(&mut cache, key)?;
+            self.make_disk_room(&mut cache, key, val.len() as u64)?;
+        }
+        // write lock released
+        self.retrying(|| self.store_logged(key, &val, expires_at))?;
+        let mut cache = Diskv::write_shard(self.shard(key)); // write lock
+        self.cache_stored(&mut cache, key, val, expires_at, self.options.write_policy)
+    }
+
+    // runs f until it succeeds or fails for good, see Options::io_retries
+    fn retrying<T>(&self, mut f: impl FnMut() -> DiskvResult<T>) -> DiskvResult<T> {
+        let mut backoff = self.options.io_retry_backoff;
+        for _ in 0..self.options.io_retries {
+            match f() {
+                Err(DiskvError::IOError(e)) if is_transient(&e) => {
+                    debug!("retrying in {:?} after transient error: {}", backoff, e);
+                    thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                res => return res,
+            }
+        }
+        f()
+    }
+
+    // IO errors of put, get and delete, as KeyIOError naming key and the path of its value file
+    fn with_context<T>(&self, res: DiskvResult<T>, op: &'static str, key: &[u8]) -> DiskvResult<T> {
+        res.map_err(|e| match e {
+            DiskvError::IOError(source) => DiskvError::KeyIOError {
+                op,
+                key: key_string(key),
+                path: self
+                    .storage
+                    .location(&encode_name(key, self.options.key_hasher)),
+                source,
+            },
+            e => e,
+        })
+    }
+
+    // writes value along with its expiry and caches it. caller must be holding the write lock.
+    fn write_value(
+        &self,
+        cache: &mut DiskvCache,
+        key: &[u8],
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+    ) -> DiskvResult<()> {
+        let policy = self.options.write_policy;
+        self.write_value_as(cache, key, val, expires_at, policy)
+    }
+
+    // like write_value, with policy in place of Options::write_policy
+    fn write_value_as(
+        &self,
+        cache: &mut DiskvCache,
+        key: &[u8],
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+        policy: WritePolicy,
+    ) -> DiskvResult<()> {
+        self.check_external_write(cache, key)?;
+        self.make_disk_room(cache, key, val.len() as u64)?;
+        self.store_cached(cache, key, val, expires_at, policy)
+    }
+
+    // logs, stores and caches value, as write_value_as does once there is room for it. caller must be holding the
+    // write lock.
+    fn store_cached(
+        &self,
+        cache: &mut DiskvCache,
+        key: &[u8],
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+        policy: WritePolicy,
+    ) -> DiskvResult<()> {
+        // the cache is left alone if the value can't be written
+        self.store_logged(key, &val, expires_at)?;
+        self.cache_stored(cache, key, val, expires_at, policy)
+    }
+
+    // logs and stores value, as store_cached does before caching it
+    fn store_logged(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        expires_at: Option<time::SystemTime>,
+    ) -> DiskvResult<()> {
+        self.log(WalRecord::Put {
+            key: key.to_vec(),
+            val: val.to_vec(),
+            expires_at,
+        })
+        .map_err(out_of_space)?;
+        self.store_value(key, val, expires_at)
+            .map_err(out_of_space)?;
+        Ok(())
+    }
+
+    // caches value once it is stored, as policy says. caller must be holding the write lock.
+    fn cache_stored(
+        &self,
+        cache: &mut DiskvCache,
+        key: &[u8],
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+        policy: WritePolicy,
+    ) -> DiskvResult<()> {
+        match policy {
+            WritePolicy::WriteThrough => {
+                cache.put(key, val);
+                cache.set_expiry(key, expires_at);
+                if self.options.detect_external_writes {
+                    let modified = self.storage.stat(&self.key_name(key)?)?.map(|(_, m)| m);
+                    cache.set_modified(key, modified);
+                }
+            }
+            // previous value must not stay cached
+            WritePolicy::WriteAround => cache.delete(key),
+        }
+        Ok(())
+    }
+
+    // with Options::detect_external_writes, fails if the value file of key was modified since key got cached, e.g. by
+    // a put of another Diskv on the same base_path. key is then uncached, so that get returns the value of the other
+    // writer and a write retried after it goes through. it's optimistic: a write of the other writer in between the
+    // check and the write isn't caught, and only cached keys are checked. caller must be holding the write lock.
+    fn check_external_write(&self, cache: &mut DiskvCache, key: &[u8]) -> DiskvResult<()> {
+        if !self.options.detect_external_writes {
+            return Ok(());
+        }
+        let cached = match cache.modified(key) {
+            Some(cached) => cached,
+            None => return Ok(()),
+        };
+        // a file deleted meanwhile counts as modified too
+        let current = self.storage.stat(&self.key_name(key)?)?.map(|(_, m)| m);
+        if current != Some(cached) {
+            debug!(
+                "value file of key {} was modified externally, uncaching it",
+                key_string(key)
+            );
+            cache.delete(key);
+            return Err(DiskvError::ConcurrentModification {
+                key: key_string(key),
+            });
+        }
+        Ok(())
+    }
+
+    // with Options::max_disk_bytes, deletes values of other keys, least recently modified first, until a value of size
+    // bytes fits in place of the current value of key. there is no index of sizes, so every value file gets stat'ed,
+    // and the size of the new value is taken before encoding. caller must be holding the key lock and the write lock
+    // of key. keys of other stripes and shards are only deleted if their locks can be taken right away, so that writers
+    // can't deadlock: when not enough of them can be, the value is written over the limit.
+    fn make_disk_room(&self, cache: &mut DiskvCache, key: &[u8], size: u64) -> DiskvResult<()> {
+        let (own_stripe, own_shard) = (self.stripe_index(key), self.shard_index(key));
+        self.free_disk(key, size, |victim| {
+            let stripe = self.stripe_index(victim);
+            let _victim_lock = if stripe == own_stripe {
+                None
+            } else {
+                match self.key_locks[stripe].try_lock() {
+                    Ok(victim_lock) => Some(victim_lock),
+                    Err(sync::TryLockError::WouldBlock) => return Ok(false),
+                    Err(sync::TryLockError::Poisoned(e)) => Some(e.into_inner()),
+                }
+            };
+            let i = self.shard_index(victim);
+            if i == own_shard {
+                self.delete_locked(cache, victim)?;
+                return Ok(true);
+            }
+            let shard = &self.shards[i];
+            let mut other = match shard.try_write() {
+                Ok(other) => other,
+                Err(sync::TryLockError::WouldBlock) => return Ok(false),
+                Err(sync::TryLockError::Poisoned(e)) => {
+                    warn!("cache lock poisoned, clearing the cache");
+                    let mut other = e.into_inner();
+                    other.clear();
+                    shard.clear_poison();
+                    other
+                }
+            };
+            self.delete_locked(&mut other, victim)?;
+            Ok(true)
+        })
+    }
+
+    // make_disk_room, for callers holding all locks, see write_all
+    fn make_disk_room_all(
+        &self,
+        shards: &mut [sync::RwLockWriteGuard<'_, DiskvCache>],
+        key: &[u8],
+        size: u64,
+    ) -> DiskvResult<()> {
+        self.free_disk(key, size, |victim| {
+            let i = self.shard_index(victim);
+            self.delete_locked(&mut shards[i], victim)?;
+            Ok(true)
+        })
+    }
+
+    // deletes values of other keys for make_disk_room, delete returning false for a key it can't delete right away
+    fn free_disk(
+        &self,
+        key: &[u8],
+        size: u64,
+        mut delete: impl FnMut(&[u8]) -> DiskvResult<bool>,
+    ) -> DiskvResult<()> {
+        let limit = match self.options.max_disk_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let own_name = self.key_name(key)?;
+        let mut used = size;
+        let mut files = Vec::new();
+        for name in self.storage.name_iter()? {
+            let name = name?;
+            if name == own_name || parse_name(&name).is_none() {
+                continue;
+            }
+            // deleted since it was listed
+            if let Some((len, modified)) = self.storage.stat(&name)? {
+                used += len;
+                files.push((modified, name, len));
+            }
+        }
+        files.sort();
+
+        for (_, name, len) in files {
+            if used <= limit {
+                break;
+            }
+            let victim = match parse_name(&name) {
+                Some(FileName::Escaped(victim)) => victim,
+                Some(FileName::Hashed) => match self.storage.read_key_header(&name) {
+                    Ok(victim) => victim,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                },
+                None => continue,
+            };
+            if !delete(&victim)? {
+                continue;
+            }
+            debug!(
+                "evicted {} from disk to stay within max_disk_bytes",
+                key_string(&victim)
+            );
+            used -= len;
+        }
+        Ok(())
+    }
+
+    fn store_value(
+        &self,
+        key: &[u8],
+        val: &[u8],
+        expires_at: Option<time::SystemTime>,
+    ) -> DiskvResult<()> {
+        let buf = encode_file(key, val, &self.file_format())?;
+        let name = self.key_name(key)?;
+        let meta_name = meta_name_of(&name);
+        self.timed(move |storage| {
+            // expiry is written before the value so a crash in between can't leave a value which never expires
+            match expires_at {
+                Some(t) => storage.write(&meta_name, &encode_expiry(t))?,
+                None => storage.remove(&meta_name)?,
+            }
+            storage.write(&name, &buf)?;
+            Ok(())
+        })
+    }
+
+    // runs f, on a helper thread with Options::op_timeout, failing with Timeout if f takes longer. the thread is left
+    // to finish f, so an operation which timed out may still complete in the background. spawning a thread for every
+    // operation has a cost of its own, so this is only meant for storage which can hang, e.g. network file systems.
+    fn timed<T, F>(&self, f: F) -> DiskvResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Storage) -> DiskvResult<T> + Send + 'static,
+    {
+        let timeout = match self.options.op_timeout {
+            Some(timeout) => timeout,
+            None => return f(&self.storage),
+        };
+        let storage = Arc::clone(&self.storage);
+        let (done, result) = mpsc::channel();
+        thread::Builder::new()
+            .name(String::from("diskv-op"))
+            .spawn(move || {
+                let _ = done.send(f(&storage));
+            })?;
+        match result.recv_timeout(timeout) {
+            Ok(res) => res,
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(DiskvError::Timeout),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(DiskvError::IOError(
+                io::Error::other("storage operation panicked"),
+            )),
+        }
+    }
+
+    // like put, returning the value key held before, which is read under the same write lock
+    pub fn put_returning(&self, key: &str, val: Vec<u8>) -> DiskvResult<Option<Vec<u8>>> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        self.check_value(&val)?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        let prev = self.read_locked(&mut cache, key.as_bytes())?;
+        self.write_value(&mut cache, key.as_bytes(), val, None)?;
+        Ok(prev)
+    }
+
+    // atomic exchange: put_returning, by the name it goes by for e.g. a single-slot mailbox between threads. of
+    // concurrent swaps of key, each gets the value put by the one before it, so none is lost.
+    pub fn swap(&self, key: &str, val: Vec<u8>) -> DiskvResult<Option<Vec<u8>>> {
+        self.put_returning(key, val)
+    }
+
+    // puts all items while holding write locks of all shards. all keys and values are validated before anything is
+    // written, but it is not atomic otherwise: if a write fails, items written before it are left in place and the
+    // rest are not written.
+    pub fn put_many(&self, items: Vec<(String, Vec<u8>)>) -> DiskvResult<()> {
+        self.check_writable()?;
+        for (key, val) in &items {
+            Diskv::check_key(key.as_bytes())?;
+            self.check_value(val)?;
+        }
+        let mut shards = self.write_all()?; // write locks
+        for (key, val) in items {
+            let (key, i) = (key.as_bytes(), self.shard_index(key.as_bytes()));
+            self.check_external_write(&mut shards[i], key)?;
+            self.make_disk_room_all(&mut shards, key, val.len() as u64)?;
+            let policy = self.options.write_policy;
+            self.store_cached(&mut shards[i], key, val, None, policy)?;
+        }
+        Ok(())
+    }
+
+    // shared handle of the store, see DiskvHandle
+    pub fn handle(self) -> DiskvHandle {
+        DiskvHandle {
+            diskv: Arc::new(self),
+        }
+    }
+
+    // puts all items, for imports where syncing every file as it's written would dominate. files are written without
+    // being synced, then synced in one pass once all are written, along with their directories, so a crash in the
+    // middle can leave any of them empty or cut short. items are validated one at a time, so if one fails, items
+    // before it are left written, unsynced. write locks of all shards are held throughout, and the write-ahead log,
+    // which the items don't go through, is checkpointed first. values get cached as Options::write_policy says,
+    // WriteAround skipping the cache.
+    pub fn bulk_load<I: Iterator<Item = (String, Vec<u8>)>>(&self, items: I) -> DiskvResult<()> {
+        self.check_writable()?;
+        let mut shards = self.write_all()?; // write locks
+        if let Some(wal) = &self.wal {
+            wal.truncate()?;
+        }
+        let mut written = Vec::new();
+        for (key, val) in items {
+            Diskv::check_key(key.as_bytes())?;
+            self.check_value(&val)?;
+            self.make_disk_room_all(&mut shards, key.as_bytes(), val.len() as u64)?;
+            let cache = &mut shards[self.shard_index(key.as_bytes())];
+            let buf = encode_file(key.as_bytes(), &val, &self.file_format())?;
+            let name = self.key_name(key.as_bytes())?;
+            self.storage.remove(&meta_name_of(&name))?;
+            self.storage
+                .write_unsynced(&name, &buf)
+                .map_err(|e| out_of_space(e.into()))?;
+            written.push(name);
+            match self.options.write_policy {
+                WritePolicy::WriteThrough => {
+                    cache.put(key.as_bytes(), val);
+                    cache.set_expiry(key.as_bytes(), None);
+                }
+                WritePolicy::WriteAround => cache.delete(key.as_bytes()),
+            }
+        }
+        for name in &written {
+            self.storage.sync_file(name)?;
+        }
+        Ok(self.storage.sync()?)
+    }
+
+    // keyspace of its own within the store, see Namespace
+    pub fn namespace(&self, name: &str) -> Namespace<'_> {
+        Namespace {
+            dkv: self,
+            prefix: format!("{}:{}:", name.len(), name),
+        }
+    }
+
+    // empty transaction, see Transaction
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            dkv: self,
+            ops: Vec::new(),
+        }
+    }
+
+    fn try_get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, DiskvError> {
+        let cache = self.read_cache(key)?; // read lock
+        match cache.get(key) {
+            Some(v) => Ok(Some(v)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, DiskvError> {
+        self.get_bytes(key.as_bytes())
+    }
+
+    // value of key read from disk into the cache, for a miss. reading and caching under the same write lock means
+    // the value cached is the one on disk, without having to check whether keys of the shard were written while the
+    // file was read, at the cost of holding up other operations on the shard meanwhile. a miss so takes the read lock
+    // and then the write lock once, and never writes. a value cached by another thread since the miss is returned as
+    // it is.
+    fn populate_locked(&self, cache: &mut DiskvCache, key: &[u8]) -> DiskvResult<Option<Vec<u8>>> {
+        if let Some(v) = cache.peek(key) {
+            return Ok(Some(v));
+        }
+        let expires_at = self.read_expiry(key)?;
+        if is_expired(expires_at) {
+            if !self.options.read_only {
+                self.delete_locked(cache, key)?;
+            }
+            return Ok(None);
+        }
+        let found = if self.options.detect_external_writes {
+            self.read_file_modified(key)?.map(|(v, m)| (v, Some(m)))
+        } else {
+            self.read_file(key)?.map(|v| (v, None))
+        };
+        match found {
+            Some((v, modified)) => {
+                cache.put(key, v.clone());
+                cache.set_expiry(key, expires_at);
+                cache.set_modified(key, modified);
+                Ok(Some(v))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // reads keys into the cache ahead of their first get, e.g. hot keys on startup. absent keys are skipped, and values
+    // which don't fit are left out as by get. write locks of all shards are taken once, for the whole batch.
+    pub fn warm<K: AsRef<str>>(&self, keys: &[K]) -> DiskvResult<()> {
+        for key in keys {
+            Diskv::check_key(key.as_ref().as_bytes())?;
+        }
+        let mut shards = self.write_all()?; // write locks
+        for key in keys {
+            let key = key.as_ref();
+            let i = self.shard_index(key.as_bytes());
+            self.populate_locked(&mut shards[i], key.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    // like get, for callers which treat an absent key as an error: NotFound rather than None
+    pub fn get_required(&self, key: &str) -> DiskvResult<Vec<u8>> {
+        self.get(key)?.ok_or_else(|| DiskvError::NotFound {
+            key: key.to_string(),
+        })
+    }
+
+    // values are returned in the same order as keys, None for the absent ones
+    pub fn get_many<K: AsRef<str>>(&self, keys: &[K]) -> DiskvResult<Vec<Option<Vec<u8>>>> {
+        keys.iter().map(|key| self.get(key.as_ref())).collect()
+    }
+
+    // same as get, except that a value read from disk is not put in the cache. useful for scans which shouldn't
+    // evict the working set.
+    pub fn get_uncached(&self, key: &str) -> DiskvResult<Option<Vec<u8>>> {
+        self.get_uncached_bytes(key.as_bytes())
+    }
+
+    // like get_uncached, for keys of arbitrary bytes, see put_bytes
+    fn get_uncached_bytes(&self, key: &[u8]) -> DiskvResult<Option<Vec<u8>>> {
+        Diskv::check_key(key)?;
+        if let Some(v) = self.try_get(key)? {
+            return Ok(Some(v));
+        }
+        Ok(self.read_from_disk(key)?.map(|(v, _)| v))
+    }
+
+    // value of key along with its metadata, as metadata would report it. on a miss both come from a single read of
+    // the file, the modification time being that of the file read, and like get_uncached the value is not cached. a
+    // cached value is returned as it is, its file getting stat'ed for the modification time, as the cache doesn't
+    // keep it.
+    pub fn get_with_metadata(&self, key: &str) -> DiskvResult<Option<(Vec<u8>, EntryMeta)>> {
+        Diskv::check_key(key.as_bytes())?;
+        if let Some(v) = self.try_get(key.as_bytes())? {
+            if let Some((_, modified)) = self.storage.stat(&self.key_name(key.as_bytes())?)? {
+                let size = v.len() as u64;
+                return Ok(Some((v, EntryMeta { size, modified })));
+            }
+        }
+        if is_expired(self.read_expiry(key.as_bytes())?) {
+            if !self.options.read_only {
+                self.delete(key)?;
+            }
+            return Ok(None);
+        }
+        Ok(self
+            .read_file_modified(key.as_bytes())?
+            .map(|(v, modified)| {
+                let size = v.len() as u64;
+                (v, EntryMeta { size, modified })
+            }))
+    }
+
+    // reads value and its expiry from disk. expired key is deleted, unless read-only, and reported as absent.
+    fn read_from_disk(
+        &self,
+        key: &[u8],
+    ) -> DiskvResult<Option<(Vec<u8>, Option<time::SystemTime>)>> {
+        let expires_at = self.read_expiry(key)?;
+        if is_expired(expires_at) {
+            if !self.options.read_only {
+                self.delete_bytes(key)?;
+            }
+            return Ok(None);
+        }
+        Ok(self.read_file(key)?.map(|v| (v, expires_at)))
+    }
+
+    // current value of key, for operations which read and then write under the same write lock
+    fn read_locked(&self, cache: &mut DiskvCache, key: &[u8]) -> DiskvResult<Option<Vec<u8>>> {
+        if let Some(v) = cache.get(key) {
+            return Ok(Some(v));
+        }
+        if is_expired(self.read_expiry(key)?) {
+            self.delete_locked(cache, key)?;
+            return Ok(None);
+        }
+        self.read_file(key)
+    }
+
+    fn file_format(&self) -> FileFormat<'_> {
+        FileFormat {
+            codec: self.options.codec,
+            checksum: self.options.verify_checksums,
+            #[cfg(feature = "encryption")]
+            encryption_key: self.options.encryption_key.as_ref(),
+            #[cfg(not(feature = "encryption"))]
+            encryption_key: None,
+        }
+    }
+
+    // like read_file, along with the modification time of the file as read
+    fn read_file_modified(&self, key: &[u8]) -> DiskvResult<Option<(Vec<u8>, time::SystemTime)>> {
+        let name = self.key_name(key)?;
+        match self.timed(move |storage| Ok(storage.read_with_modified(&name)?))? {
+            Some((buf, modified)) => {
+                let v = decode_file(key, &buf, &self.file_format())?;
+                Ok(Some((v, modified)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn read_file(&self, key: &[u8]) -> DiskvResult<Option<Vec<u8>>> {
+        let name = self.key_name(key)?;
+        match self.timed(move |storage| Ok(storage.read(&name)?))? {
+            Some(buf) => decode_file(key, &buf, &self.file_format()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // like get, for values which are text. values which are not valid UTF-8 fail with InvalidUtf8 rather than being
+    // converted lossily.
+    pub fn get_string(&self, key: &str) -> DiskvResult<Option<String>> {
+        match self.get(key)? {
+            Some(v) => String::from_utf8(v)
+                .map(Some)
+                .map_err(|_| DiskvError::InvalidUtf8 {
+                    key: key.to_string(),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put_string(&self, key: &str, val: &str) -> DiskvResult<()> {
+        self.put(key, val.as_bytes().to_vec())
+    }
+
+    // like put, for keys of arbitrary bytes, e.g. hashes. file names escape every byte as they do for string keys, and
+    // keys too long for a name are kept in the key header, so keys read back are the bytes put. keys which are valid
+    // UTF-8 are the same keys as their string. listings of string keys leave out those which are not, e.g. keys, see
+    // keys_bytes.
+    pub fn put_bytes(&self, key: &[u8], val: Vec<u8>) -> DiskvResult<()> {
+        self.check_writable()?;
+        self.check_value(&val)?;
+        self.put_expiring(key, val, None)
+    }
+
+    // like get, for keys of arbitrary bytes, see put_bytes
+    pub fn get_bytes(&self, key: &[u8]) -> DiskvResult<Option<Vec<u8>>> {
+        Diskv::check_key(key)?;
+        let (hit, adapt, disabled) = {
+            let cache = self.read_cache(key)?; // read lock
+            if cache.disabled() {
+                (None, false, true)
+            } else {
+                (cache.get(key), cache.adapt_due(), false)
+            }
+        };
+        // read lock released
+        if disabled {
+            let res = self.retrying(|| self.read_from_disk(key).map(|found| found.map(|(v, _)| v)));
+            return self.with_context(res, "read", key);
+        }
+        let stale = hit.is_some()
+            && self.options.verify_on_hit
+            && !self.storage.exists(&self.key_name(key)?)?;
+        if let Some(v) = hit.filter(|_| !stale) {
+            if adapt {
+                self.write_cache(key)?.adapt(); // write lock
+            }
+            return Ok(Some(v));
+        }
+
+        let mut cache = self.write_cache(key)?; // write lock
+        if adapt {
+            cache.adapt();
+        }
+        // checked again under the write lock, the key may have been put meanwhile
+        if stale && !self.storage.exists(&self.key_name(key)?)? {
+            debug!(
+                "value file of cached key {} is gone, uncaching it",
+                key_string(key)
+            );
+            cache.delete(key);
+            return Ok(None);
+        }
+        let res = self.retrying(|| self.populate_locked(&mut cache, key));
+        self.with_context(res, "read", key)
+    }
+
+    // like delete, for keys of arbitrary bytes, see put_bytes
+    pub fn delete_bytes(&self, key: &[u8]) -> DiskvResult<()> {
+        self.check_writable()?;
+        Diskv::check_key(key)?;
+        let _key_lock = self.lock_key(key); // key lock
+        let res = self.retrying(|| self.delete_unlocked(key));
+        self.with_context(res, "delete", key)
+    }
+
+    // deletes key like delete_locked, taking the write lock of its shard only to uncache it. caller must be holding the
+    // key lock.
+    fn delete_unlocked(&self, key: &[u8]) -> DiskvResult<()> {
+        let hasher = self.options.key_hasher;
+        let name = remove_logged(&self.storage, self.wal.as_deref(), key, hasher)?;
+        Diskv::write_shard(self.shard(key)).delete(key); // write lock
+        if self.options.sync_on_write {
+            self.storage.sync_removal(&name)?;
+        }
+        Ok(())
+    }
+
+    // value of key, or if it's absent, the value computed by f, which gets put like put does. f runs under the write
+    // lock, so for a key racing threads find absent, only one of them runs f and the others get its value.
+    pub fn get_or_insert_with<F: FnOnce() -> Vec<u8>>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> DiskvResult<Vec<u8>> {
+        Diskv::check_key(key.as_bytes())?;
+        if let Some(v) = self.try_get(key.as_bytes())? {
+            return Ok(v);
+        }
+        // read lock released
+        self.check_writable()?;
+
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        if let Some(v) = self.read_locked(&mut cache, key.as_bytes())? {
+            return Ok(v);
+        }
+        let val = f();
+        self.check_value(&val)?;
+        self.write_value(&mut cache, key.as_bytes(), val.clone(), None)?;
+        Ok(val)
+    }
+
+    // reader streaming the value of key, None if it's absent, for values too large to be held in memory. the cache is
+    // neither used nor populated. values are decompressed as they are read, and their checksum is verified once they
+    // are read in full, the last read failing with InvalidData if it doesn't match. encrypted values can only be
+    // authenticated as a whole, so they are read into memory first. the value is read as the caller reads the reader,
+    // so Options::io_retries doesn't apply, and Options::op_timeout only to reading the expiry of key.
+    pub fn get_reader(&self, key: &str) -> DiskvResult<Option<impl Read + Send>> {
+        Diskv::check_key(key.as_bytes())?;
+        if is_expired(self.read_expiry(key.as_bytes())?) {
+            return Ok(None);
+        }
+        let mut r = match self.storage.open(&self.key_name(key.as_bytes())?)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        if is_hashed(key.as_bytes()) {
+            let mut len = [0u8; 4];
+            r.read_exact(&mut len)?;
+            if u32::from_le_bytes(len) as usize != key.len() {
+                return Err(key_collision(key.as_bytes()));
+            }
+            let mut stored_key = vec![0u8; key.len()];
+            r.read_exact(&mut stored_key)?;
+            if stored_key != key.as_bytes() {
+                return Err(key_collision(key.as_bytes()));
+            }
+        }
+
+        let mut marker = [0u8; 1];
+        r.read_exact(&mut marker)?;
+        if marker[0] == ENCRYPTED_MARKER {
+            return Ok(self.read_file(key.as_bytes())?.map(|v| ValueReader {
+                inner: Box::new(io::Cursor::new(v)),
+                key: key.to_string(),
+                checksum: None,
+                crc: crc32fast::Hasher::new(),
+            }));
+        }
+        let mut checksum = None;
+        if marker[0] == CHECKSUM_MARKER {
+            let mut buf = [0u8; 4];
+            r.read_exact(&mut buf)?;
+            if self.options.verify_checksums {
+                checksum = Some(u32::from_le_bytes(buf));
+            }
+            r.read_exact(&mut marker)?;
+        }
+        Ok(Some(ValueReader {
+            inner: codec::decoder(marker[0], r)?,
+            key: key.to_string(),
+            checksum,
+            crc: crc32fast::Hasher::new(),
+        }))
+    }
+
+    // puts the value read from r, streaming it to disk rather than holding it in memory. the cache isn't populated,
+    // and the key gets uncached. the value is written without compression and, unlike put, without going through the
+    // write-ahead log, which so gets checkpointed first, holding write locks of all shards while the value is written.
+    // encrypted values are sealed as a whole, so with Options::encryption_key set the value is read into memory and
+    // written as put does. exceeding max_value_size fails with ValueTooLarge once that many bytes are read, leaving
+    // any previous value in place, though without its expiry, which is removed before the value is written. the value
+    // counts towards max_disk_bytes, but no room is made for it, its size not being known up front. r can't be read
+    // again, nor handed to another thread, so neither Options::io_retries nor Options::op_timeout apply.
+    pub fn put_reader<R: Read>(&self, key: &str, mut r: R) -> DiskvResult<()> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        if self.file_format().encryption_key.is_some() {
+            let mut val = Vec::new();
+            r.read_to_end(&mut val)?;
+            self.check_value(&val)?;
+            let mut cache = self.write_cache(key.as_bytes())?; // write lock
+            self.make_disk_room(&mut cache, key.as_bytes(), val.len() as u64)?;
+            self.log(WalRecord::Put {
+                key: key.as_bytes().to_vec(),
+                val: val.clone(),
+                expires_at: None,
+            })
+            .map_err(out_of_space)?;
+            self.store_value(key.as_bytes(), &val, None)
+                .map_err(out_of_space)?;
+            cache.delete(key.as_bytes());
+            return Ok(());
+        }
+
+        let mut write = |cache: &mut DiskvCache| -> DiskvResult<()> {
+            // expiry goes first, as with store_value, a crash in between must not leave it on the new value
+            self.storage.remove(&self.meta_name(key.as_bytes())?)?;
+            self.storage
+                .write_with(&self.key_name(key.as_bytes())?, |w| {
+                    self.stream_value(key, &mut r, w)
+                })
+                .map_err(out_of_space)?;
+            cache.delete(key.as_bytes());
+            Ok(())
+        };
+        // logged writes of key must not be replayed over the value
+        match &self.wal {
+            Some(wal) => {
+                let mut shards = self.write_all()?; // write locks
+                wal.truncate()?;
+                write(&mut shards[self.shard_index(key.as_bytes())])
+            }
+            None => write(&mut *self.write_cache(key.as_bytes())?), // write lock
+        }
+    }
+
+    // writes file content of key, as encode_file would with Codec::None, with the value read from r
+    fn stream_value(&self, key: &str, r: &mut dyn Read, w: &mut dyn WriteSeek) -> DiskvResult<()> {
+        if is_hashed(key.as_bytes()) {
+            w.write_all(&(key.len() as u32).to_le_bytes())?;
+            w.write_all(key.as_bytes())?;
+        }
+        // checksum is filled in once the whole value is read
+        let checksum_at = if self.options.verify_checksums {
+            let at = w.stream_position()?;
+            w.write_all(&[CHECKSUM_MARKER, 0, 0, 0, 0])?;
+            Some(at)
+        } else {
+            None
+        };
+        w.write_all(&[codec::MARKER_NONE])?;
+
+        let mut buf = vec![0u8; self.options.write_buffer_size.clamp(1, 1024 * 1024)];
+        let (mut len, mut crc) = (0u64, crc32fast::Hasher::new());
+        loop {
+            let n = match r.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            len += n as u64;
+            if let Some(limit) = self.options.max_value_size {
+                if len > limit {
+                    return Err(DiskvError::ValueTooLarge { size: len, limit });
+                }
+            }
+            crc.update(&buf[..n]);
+            w.write_all(&buf[..n])?;
+        }
+        if let Some(at) = checksum_at {
+            w.seek(io::SeekFrom::Start(at + 1))?;
+            w.write_all(&crc.finalize().to_le_bytes())?;
+            w.seek(io::SeekFrom::End(0))?;
+        }
+        Ok(())
+    }
+
+    // writes new only if key currently holds expected, None meaning that key must be absent. returns whether it was
+    // written. new is written like put does, without an expiry.
+    pub fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<Vec<u8>>,
+        new: Vec<u8>,
+    ) -> DiskvResult<bool> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        self.check_value(&new)?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        if self.read_locked(&mut cache, key.as_bytes())? != expected {
+            return Ok(false);
+        }
+        self.write_value(&mut cache, key.as_bytes(), new, None)?;
+        Ok(true)
+    }
+
+    // writes val only if key is absent, returning whether it was written. the check and the write happen under the
+    // same write lock, so of threads racing to put the same absent key exactly one succeeds. an expired key counts as
+    // absent.
+    pub fn put_if_absent(&self, key: &str, val: Vec<u8>) -> DiskvResult<bool> {
+        self.compare_and_swap(key, None, val)
+    }
+
+    // adds delta to the counter held by key, a little endian i64, and returns the new total. an absent key counts as
+    // 0, values which are not 8 bytes long are NotANumber. total wraps around on overflow, like atomic integers do.
+    // counter is written like put does, without an expiry.
+    pub fn increment(&self, key: &str, delta: i64) -> DiskvResult<i64> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        let current = match self.read_locked(&mut cache, key.as_bytes())? {
+            Some(v) => match v[..].try_into() {
+                Ok(buf) => i64::from_le_bytes(buf),
+                Err(_) => return Err(DiskvError::NotANumber(key.to_string())),
+            },
+            None => 0,
+        };
+        let total = current.wrapping_add(delta);
+        self.write_value(
+            &mut cache,
+            key.as_bytes(),
+            total.to_le_bytes().to_vec(),
+            None,
+        )?;
+        Ok(total)
+    }
+
+    // replaces the value of key with f(current value, operand) and returns it, None standing for an absent key. f
+    // runs under the write lock, so concurrent merges of key are applied one after another and none gets lost. merged
+    // value is written like put does, without an expiry.
+    pub fn merge<F: FnOnce(Option<Vec<u8>>, Vec<u8>) -> Vec<u8>>(
+        &self,
+        key: &str,
+        operand: Vec<u8>,
+        f: F,
+    ) -> DiskvResult<Vec<u8>> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        let current = self.read_locked(&mut cache, key.as_bytes())?;
+        let merged = f(current, operand);
+        self.check_value(&merged)?;
+        self.write_value(&mut cache, key.as_bytes(), merged.clone(), None)?;
+        Ok(merged)
+    }
+
+    // replaces the value of key with f(current value), None standing for an absent key both ways: f returning None
+    // deletes key. like merge, f runs under the write lock, so nothing gets written to key between reading and
+    // writing it back. the new value is written like put does, without an expiry.
+    pub fn update<F: FnOnce(Option<Vec<u8>>) -> Option<Vec<u8>>>(
+        &self,
+        key: &str,
+        f: F,
+    ) -> DiskvResult<()> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        let current = self.read_locked(&mut cache, key.as_bytes())?;
+        let existed = current.is_some();
+        match f(current) {
+            Some(val) => {
+                self.check_value(&val)?;
+                self.write_value(&mut cache, key.as_bytes(), val, None)
+            }
+            None if existed => self.delete_locked(&mut cache, key.as_bytes()),
+            None => Ok(()),
+        }
+    }
+
+    // marks key as just used for the eviction policy, without reading or writing its value, and with new_ttl, makes
+    // it expire new_ttl from now. false if key is absent. an uncached key stays uncached. like every write, the new
+    // expiry goes through the write-ahead log, as a put of the value, so with wal on the value gets read for that.
+    pub fn touch(&self, key: &str, new_ttl: Option<time::Duration>) -> DiskvResult<bool> {
+        Diskv::check_key(key.as_bytes())?;
+        if new_ttl.is_some() {
+            self.check_writable()?;
+        }
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        let present = cache.contains(key.as_bytes())
+            || (self.storage.exists(&self.key_name(key.as_bytes())?)?
+                && !is_expired(self.read_expiry(key.as_bytes())?));
+        if !present {
+            return Ok(false);
+        }
+        cache.touch(key.as_bytes());
+        if let Some(ttl) = new_ttl {
+            let expires_at = time::SystemTime::now() + ttl;
+            if self.wal.is_some() {
+                if let Some(val) = self.read_file(key.as_bytes())? {
+                    self.log(WalRecord::Put {
+                        key: key.as_bytes().to_vec(),
+                        val,
+                        expires_at: Some(expires_at),
+                    })?;
+                }
+            }
+            let name = self.meta_name(key.as_bytes())?;
+            self.timed(move |storage| Ok(storage.write(&name, &encode_expiry(expires_at))?))?;
+            cache.set_expiry(key.as_bytes(), Some(expires_at));
+        }
+        Ok(true)
+    }
+
+    // checks presence of key without reading its value. cache is consulted first, on a miss the file is only
+    // stat'ed, so the cache is left as it is.
+    pub fn contains_key(&self, key: &str) -> DiskvResult<bool> {
+        Diskv::check_key(key.as_bytes())?;
+        let cache = self.read_cache(key.as_bytes())?; // read lock
+        if cache.contains(key.as_bytes()) {
+            return Ok(true);
+        }
+        if !self.storage.exists(&self.key_name(key.as_bytes())?)? {
+            return Ok(false);
+        }
+        Ok(!is_expired(self.read_expiry(key.as_bytes())?))
+    }
+
+    // summed up over all shards
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for shard in self.shards.iter() {
+            let cache = Diskv::read_shard(shard);
+            let s = cache.stats();
+            stats.hits += s.hits;
+            stats.misses += s.misses;
+            stats.evictions += s.evictions;
+            stats.ignored += s.ignored;
+            stats.cache_size = stats.cache_size.saturating_add(s.cache_size);
+            stats.entries += s.entries;
+            stats.cache_size_max = stats.cache_size_max.saturating_add(s.cache_size_max);
+        }
+        stats
+    }
+
+    // keys a put of a value of incoming_val_len bytes, under a key not cached yet, would evict from the cache, in the
+    // order they would be evicted, for trying out eviction policies. nothing is evicted, and the policy is left as it
+    // is. with several shards, a value only goes to the shard of its key, so this lists what each shard would evict
+    // if it got the value, shard after shard. policies which don't implement EvictionPolicy::eviction_order report
+    // nothing. keys which are not valid UTF-8, see put_bytes, are converted lossily.
+    pub fn simulate_eviction(&self, incoming_val_len: u64) -> Vec<String> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let cache = Diskv::read_shard(shard);
+            let evicted = cache.simulate_eviction(incoming_val_len);
+            keys.extend(evicted.iter().map(|key| key_string(key)));
+        }
+        keys
+    }
+
+    // changes the cache size limit of a running store, shared out among shards as Options::cache_size_max is. values
+    // which no longer fit get evicted before this returns, a larger limit just leaves room for more. with
+    // adaptive_cache, the limit keeps adapting from there, within floor and ceiling.
+    pub fn set_cache_size_max(&self, new_max: u64) -> DiskvResult<()> {
+        let shard_max = new_max / self.shards.len() as u64;
+        for shard in self.shards.iter() {
+            Diskv::write_shard(shard).resize(shard_max); // write lock
+        }
+        Ok(())
+    }
+
+    // stats in the Prometheus text exposition format, e.g. for a /metrics endpoint. counters only ever grow, unless
+    // reset_stats is called, which Prometheus takes for a restart.
+    pub fn metrics_text(&self) -> String {
+        let stats = self.stats();
+        let metrics = [
+            (
+                "diskv_cache_hits_total",
+                "counter",
+                "Lookups answered by the cache.",
+                stats.hits,
+            ),
+            (
+                "diskv_cache_misses_total",
+                "counter",
+                "Lookups which missed the cache.",
+                stats.misses,
+            ),
+            (
+                "diskv_cache_evictions_total",
+                "counter",
+                "Values evicted from the cache.",
+                stats.evictions,
+            ),
+            (
+                "diskv_cache_ignored_total",
+                "counter",
+                "Values not cached for being larger than the cache.",
+                stats.ignored,
+            ),
+            (
+                "diskv_cache_bytes",
+                "gauge",
+                "Bytes of values cached.",
+                stats.cache_size,
+            ),
+            (
+                "diskv_cache_entries",
+                "gauge",
+                "Values cached.",
+                stats.entries as u64,
+            ),
+            (
+                "diskv_cache_max_bytes",
+                "gauge",
+                "Cache size limit in bytes.",
+                stats.cache_size_max,
+            ),
+        ];
+        let mut text = String::new();
+        for (name, kind, help, value) in metrics.iter() {
+            text.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n{} {}\n",
+                name, help, name, kind, name, value
+            ));
+        }
+        text
+    }
+
+    // counters are zeroed, cache_size and entries reflect the cache as it is
+    pub fn reset_stats(&self) {
+        for shard in self.shards.iter() {
+            let cache = Diskv::read_shard(shard);
+            cache.reset_stats()
+        }
+    }
+
+    // keys held by the cache, sorted, for debugging and tests. expired entries are listed until they get evicted or
+    // deleted. the cache is looked at shard by shard, so this isn't a snapshot when other threads are writing. keys
+    // which are not valid UTF-8, see put_bytes, are converted lossily.
+    pub fn cached_keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        for shard in self.shards.iter() {
+            let cache = Diskv::read_shard(shard);
+            keys.extend(cache.keys().map(key_string));
+        }
+        keys.sort();
+        keys
+    }
+
+    // whether the cache holds key, an expired entry included. unlike get, it's not counted as a hit or miss, nor does
+    // it count as use for the eviction policy.
+    pub fn is_cached(&self, key: &str) -> bool {
+        match self.read_cache(key.as_bytes()) {
+            Ok(cache) => cache.holds(key.as_bytes()),
+            Err(_) => false,
+        }
+    }
+
+    // cached value of key, None on a miss, which unlike get is never read from disk, e.g. for a fast path falling back
+    // to something else. only takes the read lock, and counts as use for the eviction policy, but not as a lookup in
+    // stats. expired values, and a poisoned lock, are misses.
+    pub fn peek(&self, key: &str) -> Option<Vec<u8>> {
+        self.read_cache(key.as_bytes()).ok()?.peek(key.as_bytes())
+    }
+
+    // size and modification time of the value of key, None if it's absent. value is not read, except for compressed
+    // values, as their size is only known once decompressed.
+    pub fn metadata(&self, key: &str) -> DiskvResult<Option<EntryMeta>> {
+        Diskv::check_key(key.as_bytes())?;
+        let name = self.key_name(key.as_bytes())?;
+        let (len, modified) = match self.storage.stat(&name)? {
+            Some(stat) => stat,
+            None => return Ok(None),
+        };
+        if is_expired(self.read_expiry(key.as_bytes())?) {
+            return Ok(None);
+        }
+
+        // key header, if any, checksum, if any, and codec marker
+        let mut header_len = if is_hashed(key.as_bytes()) {
+            4 + key.len()
+        } else {
+            0
+        };
+        let prefix = self
+            .storage
+            .read_prefix(&name, header_len + CHECKSUM_LEN + 1)?
+            .unwrap_or_default();
+        if prefix.get(header_len) == Some(&CHECKSUM_MARKER) {
+            header_len += CHECKSUM_LEN;
+        }
+        if prefix.len() <= header_len {
+            // removed or rewritten since stat
+            return Ok(self.read_file(key.as_bytes())?.map(|v| EntryMeta {
+                size: v.len() as u64,
+                modified,
+            }));
+        }
+        if is_hashed(key.as_bytes()) && split_key_header(&prefix)?.0 != key.as_bytes() {
+            return Err(key_collision(key.as_bytes()));
+        }
+        let size = match codec::plain_len(prefix[header_len], len - header_len as u64) {
+            Some(size) => size,
+            None => match self.read_file(key.as_bytes())? {
+                Some(v) => v.len() as u64,
+                None => return Ok(None),
+            },
+        };
+        Ok(Some(EntryMeta { size, modified }))
+    }
+
+    // latest modification time of any value or expiry file, None for an empty store, e.g. to tell whether anything
+    // changed since. files are only stat'ed, never read. deletes leave no trace, so they don't show.
+    pub fn newest_modified(&self) -> DiskvResult<Option<time::SystemTime>> {
+        let mut newest = None;
+        for name in self.storage.name_iter()? {
+            let name = name?;
+            if !is_store_name(&name) || name.contains(TMP_INFIX) {
+                continue;
+            }
+            // deleted since it was listed
+            if let Some((_, modified)) = self.storage.stat(&name)? {
+                newest = newest.max(Some(modified));
+            }
+        }
+        Ok(newest)
+    }
+
+    // all keys along with the modification time of their value file, oldest first, or newest first with newest_first.
+    // keys modified at the same time are sorted by key either way. values are never read, except for the key header of
+    // hashed keys, and like len, expired keys are listed until they get removed. stored keys which are not valid UTF-8
+    // are left out, as by keys.
+    pub fn entries_by_mtime(
+        &self,
+        newest_first: bool,
+    ) -> DiskvResult<Vec<(String, time::SystemTime)>> {
+        let mut entries = Vec::new();
+        for name in self.storage.name_iter()? {
+            let name = name?;
+            let key = match parse_name(&name) {
+                Some(FileName::Escaped(key)) => key,
+                Some(FileName::Hashed) => match self.storage.read_key_header(&name) {
+                    Ok(key) => key,
+                    // deleted since it was listed
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                },
+                None => continue,
+            };
+            let key = match String::from_utf8(key) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            // deleted since it was listed
+            if let Some((_, modified)) = self.storage.stat(&name)? {
+                entries.push((key, modified));
+            }
+        }
+        entries.sort_by(|(k1, m1), (k2, m2)| {
+            let by_mtime = if newest_first { m2.cmp(m1) } else { m1.cmp(m2) };
+            by_mtime.then_with(|| k1.cmp(k2))
+        });
+        Ok(entries)
+    }
+
+    // number of keys stored, counted by walking base_path. sub-directories and expiry files are not values, so are
+    // skipped. expired keys are counted until they get removed.
+    pub fn len(&self) -> DiskvResult<usize> {
+        let names = self.storage.names()?;
+        Ok(names.iter().filter(|name| is_value_name(name)).count())
+    }
+
+    pub fn is_empty(&self) -> DiskvResult<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // number of keys starting with each of prefixes, with keys listed once for all of them. a key counts for every
+    // prefix it starts with. escaped keys are counted from their file names alone, but a hashed key, see KeyHasher,
+    // costs opening its value file to read the key header, or with a backend reading the whole file, so stores of many
+    // long keys pay about a read per key. like len, expired keys are counted until they get removed.
+    pub fn count_by_prefix<P: AsRef<str>>(
+        &self,
+        prefixes: &[P],
+    ) -> DiskvResult<HashMap<String, usize>> {
+        let mut counts: HashMap<String, usize> = prefixes
+            .iter()
+            .map(|p| (p.as_ref().to_string(), 0))
+            .collect();
+        for name in self.storage.name_iter()? {
+            let name = name?;
+            let key = match parse_name(&name) {
+                Some(FileName::Escaped(key)) => key,
+                Some(FileName::Hashed) => match self.storage.read_key_header(&name) {
+                    Ok(key) => key,
+                    // deleted since it was listed
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                },
+                None => continue,
+            };
+            for (prefix, count) in counts.iter_mut() {
+                if key.starts_with(prefix.as_bytes()) {
+                    *count += 1;
+                }
+            }
+        }
+        Ok(counts)
+    }
+
+    // all keys stored under base_path. stored keys which are not valid UTF-8, see put_bytes, are left out rather than
+    // converted lossily, keys_bytes lists them.
+    pub fn keys(&self) -> DiskvResult<Vec<String>> {
+        Ok(self
+            .keys_bytes()?
+            .into_iter()
+            .filter_map(|key| String::from_utf8(key).ok())
+            .collect())
+    }
+
+    // like keys, as bytes, so that keys which are not valid UTF-8, see put_bytes, are listed too
+    pub fn keys_bytes(&self) -> DiskvResult<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for name in self.storage.names()? {
+            match parse_name(&name) {
+                Some(FileName::Escaped(key)) => keys.push(key),
+                Some(FileName::Hashed) => match self.storage.read_key_header(&name) {
+                    Ok(key) => keys.push(key),
+                    // deleted since it was listed
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e.into()),
+                },
+                None => continue,
+            }
+        }
+        Ok(keys)
+    }
+
+    // values of all keys, in no particular order. cached values are used, but values read from disk are not cached.
+    // everything is loaded into memory at once, so for large stores iter, which reads values one at a time, is the
+    // better fit. keys deleted or expired since they were listed are skipped.
+    pub fn values(&self) -> DiskvResult<Vec<Vec<u8>>> {
+        let mut values = Vec::new();
+        for key in self.keys()? {
+            if let Some(v) = self.get_uncached(&key)? {
+                values.push(v);
+            }
+        }
+        Ok(values)
+    }
+
+    // iterator over all key/value pairs, read one at a time as it's advanced, in no particular order. like
+    // scan_prefix, values read from disk are not cached. keys put or deleted meanwhile may or may not be seen.
+    pub fn iter(&self) -> DiskvResult<DiskvIter<'_>> {
+        Ok(DiskvIter {
+            dkv: self,
+            names: self.storage.name_iter()?,
+        })
+    }
+
+    // key/value pairs for all keys starting with prefix, sorted by key. cached values are used, but values read from
+    // disk are not cached. as there is no index, every key in the store is looked at, so it's O(total keys).
+    pub fn scan_prefix(&self, prefix: &str) -> DiskvResult<Vec<(String, Vec<u8>)>> {
+        let mut keys: Vec<String> = self
+            .keys()?
+            .into_iter()
+            .filter(|key| key.starts_with(prefix))
+            .collect();
+        keys.sort();
+
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in keys {
+            // key may have been deleted or expired since it was listed
+            if let Some(v) = self.get_uncached(&key)? {
+                entries.push((key, v));
+            }
+        }
+        Ok(entries)
+    }
+
+    // up to limit key/value pairs of keys from start, included, to end, excluded, sorted by key, e.g. for
+    // pagination, continuing from the last key returned plus a 0 byte. like scan_prefix, values read from disk are
+    // not cached. files are stored in no particular order, so all keys are listed and sorted in memory first.
+    pub fn get_range(
+        &self,
+        start: &str,
+        end: &str,
+        limit: usize,
+    ) -> DiskvResult<Vec<(String, Vec<u8>)>> {
+        let mut keys: Vec<String> = self
+            .keys()?
+            .into_iter()
+            .filter(|key| start <= key.as_str() && key.as_str() < end)
+            .collect();
+        keys.sort();
+
+        let mut entries = Vec::with_capacity(keys.len().min(limit));
+        for key in keys {
+            if entries.len() == limit {
+                break;
+            }
+            // key may have been deleted or expired since it was listed
+            if let Some(v) = self.get_uncached(&key)? {
+                entries.push((key, v));
+            }
+        }
+        Ok(entries)
+    }
+
+    // like scan_prefix for several prefixes at once, with keys listed once rather than once per prefix. every prefix
+    // gets an entry, which holds the keys whose longest matching prefix it is, sorted by key. keys matching no prefix
+    // are left out.
+    pub fn scan_prefixes<P: AsRef<str>>(&self, prefixes: &[P]) -> DiskvResult<PrefixBuckets> {
+        let mut buckets: PrefixBuckets = prefixes
+            .iter()
+            .map(|p| (p.as_ref().to_string(), Vec::new()))
+            .collect();
+        let mut keys = self.keys()?;
+        keys.sort();
+        for key in keys {
+            let prefix = match prefixes
+                .iter()
+                .map(AsRef::as_ref)
+                .filter(|p| key.starts_with(p))
+                .max_by_key(|p| p.len())
+            {
+                Some(prefix) => prefix,
+                None => continue,
+            };
+            // key may have been deleted or expired since it was listed
+            if let Some(v) = self.get_uncached(&key)? {
+                if let Some(bucket) = buckets.get_mut(prefix) {
+                    bucket.push((key, v));
+                }
+            }
+        }
+        Ok(buckets)
+    }
+
+    // writes all key/value pairs to w, sorted by key, in the format described with ARCHIVE_MAGIC. expiry is not
+    // exported, expired keys are left out though. like scan_prefix, values read from disk are not cached. keys which
+    // are not valid UTF-8, see put_bytes, are exported too.
+    pub fn export<W: Write>(&self, mut w: W) -> DiskvResult<()> {
+        let mut keys = self.keys_bytes()?;
+        keys.sort();
+
+        w.write_all(ARCHIVE_MAGIC)?;
+        w.write_all(&ARCHIVE_VERSION.to_le_bytes())?;
+        for key in keys {
+            // key may have been deleted or expired since it was listed
+            if let Some(v) = self.get_uncached_bytes(&key)? {
+                w.write_all(&(key.len() as u32).to_le_bytes())?;
+                w.write_all(&key)?;
+                w.write_all(&(v.len() as u64).to_le_bytes())?;
+                w.write_all(&v)?;
+            }
+        }
+        w.write_all(&0u32.to_le_bytes())?;
+        w.flush()?;
+        Ok(())
+    }
+
+    // puts every key/value pair of an archive written by export. existing keys are overwritten, others are left
+    // alone. pairs are put as they are read, so those before a corrupt part of the archive are kept.
+    pub fn import<R: Read>(&self, mut r: R) -> DiskvResult<()> {
+        self.check_writable()?;
+        let header = read_archive_bytes(&mut r, ARCHIVE_MAGIC.len() as u64 + 4)?;
+        let (magic, version) = header.split_at(ARCHIVE_MAGIC.len());
+        if magic != ARCHIVE_MAGIC {
+            return Err(DiskvError::CorruptArchive(String::from("not an archive")));
+        }
+        let version = u32::from_le_bytes(version.try_into().unwrap());
+        if version != ARCHIVE_VERSION {
+            return Err(DiskvError::CorruptArchive(format!(
+                "unknown version {}",
+                version
+            )));
+        }
+
+        loop {
+            let len = read_archive_bytes(&mut r, 4)?;
+            let len = u32::from_le_bytes(len[..].try_into().unwrap());
+            if len == 0 {
+                return Ok(());
+            }
+            let key = read_archive_bytes(&mut r, len as u64)?;
+            let len = read_archive_bytes(&mut r, 8)?;
+            let len = u64::from_le_bytes(len[..].try_into().unwrap());
+            let val = read_archive_bytes(&mut r, len)?;
+            self.put_bytes(&key, val)?;
+        }
+    }
+
+    // removes all values, along with their expiry and temporary files, and empties the cache. base_path is kept, so
+    // the store can be used right after.
+    pub fn clear(&self) -> DiskvResult<()> {
+        self.check_writable()?;
+        let mut shards = self.write_all()?; // write locks
+        self.clear_locked(&mut shards)
+    }
+
+    // clear, for callers already holding write locks of all shards
+    fn clear_locked(&self, shards: &mut [sync::RwLockWriteGuard<DiskvCache>]) -> DiskvResult<()> {
+        // log goes first, replaying it must not bring back values removed below
+        if let Some(wal) = &self.wal {
+            wal.truncate()?;
+        }
+        for name in self.storage.names()? {
+            if is_store_name(&name) {
+                self.storage.remove(&name)?;
+            }
+        }
+        if self.options.sync_on_write {
+            self.storage.sync()?;
+        }
+        for cache in shards.iter_mut() {
+            cache.clear();
+        }
+        Ok(())
+    }
+
+    // every key with its value, read into memory, after which everything is removed as by clear, e.g. to keep the
+    // content of a short-lived store once done with it. expired keys are left out. should a value fail to be read,
+    // nothing is removed. taking self, the store can't be used afterwards, base_path is left behind, empty of values.
+    // keys which are not valid UTF-8, see put_bytes, can't be returned, so they fail with InvalidKey, nothing being
+    // removed either. write locks of all shards are held from the first read to the last removal, so nothing written
+    // meanwhile is removed without being returned.
+    pub fn drain(self) -> DiskvResult<HashMap<String, Vec<u8>>> {
+        self.check_writable()?;
+        let mut values = HashMap::new();
+        let mut shards = self.write_all()?; // write locks
+        for key in self.keys_bytes()? {
+            let i = self.shard_index(&key);
+            let v = match self.read_locked(&mut shards[i], &key)? {
+                Some(v) => v,
+                None => continue,
+            };
+            let key = String::from_utf8(key).map_err(|e| {
+                DiskvError::InvalidKey(format!(
+                    "{} is not valid UTF-8, so it can't be drained",
+                    key_string(e.as_bytes())
+                ))
+            })?;
+            values.insert(key, v);
+        }
+        self.clear_locked(&mut shards)?;
+        Ok(values)
+    }
+
+    // removes files left behind by crashes: temporary files of writes which never completed, and expiry files of
+    // values which are gone, returning how many were removed. write locks of all shards are held throughout, so no
+    // write is in flight and every temporary file is a leftover, whether or not the file it was meant to replace is
+    // still there. values, and files not created by Diskv, are left alone. with a backend keeping overwritten and
+    // deleted content around, e.g. StorageFormat::Segment, that is dropped too, and counted as records dropped.
+    pub fn compact(&self) -> DiskvResult<usize> {
+        self.check_writable()?;
+        let _shards = self.write_all()?; // write locks
+        let names: HashSet<String> = self.storage.names()?.into_iter().collect();
+        let mut removed = 0;
+        for name in &names {
+            if !is_store_name(name) || is_value_name(name) {
+                continue;
+            }
+            let orphaned = match name.strip_suffix(META_SUFFIX) {
+                Some(value_name) if !name.contains(TMP_INFIX) => !names.contains(value_name),
+                _ => true, // temporary file
+            };
+            if orphaned {
+                debug!("compact removing {}", name);
+                self.storage.remove(name)?;
+                removed += 1;
+            }
+        }
+        if removed > 0 && self.options.sync_on_write {
+            self.storage.sync()?;
+        }
+        if let Storage::Backend(backend) = &*self.storage {
+            removed += backend.compact()?;
+        }
+        Ok(removed)
+    }
+
+    // keys whose value file is corrupt: failing its checksum or not decoding at all. every value is read from disk,
+    // cached or not, and checked whether or not Options::verify_checksums is set; values written without checksum
+    // are only checked for decoding. values which fail to decrypt are reported too, verification going on past them,
+    // though that is as likely to be the wrong encryption key as corruption. keys which are not valid UTF-8, see
+    // put_bytes, are verified too, and reported converted lossily.
+    pub fn verify(&self) -> DiskvResult<Vec<String>> {
+        Ok(self
+            .corrupt_keys()?
+            .iter()
+            .map(|key| key_string(key))
+            .collect())
+    }
+
+    fn corrupt_keys(&self) -> DiskvResult<Vec<Vec<u8>>> {
+        let mut corrupt = Vec::new();
+        for key in self.keys_bytes()? {
+            let name = self.key_name(&key)?;
+            let buf = match self.timed(move |storage| Ok(storage.read(&name)?))? {
+                Some(buf) => buf,
+                None => continue, // deleted since it was listed
+            };
+            let format = FileFormat {
+                checksum: true,
+                ..self.file_format()
+            };
+            match decode_file(&key, &buf, &format) {
+                Ok(_) => {}
+                Err(DiskvError::ChecksumMismatch { .. }) => corrupt.push(key),
+                #[cfg(feature = "encryption")]
+                Err(DiskvError::DecryptionFailed { .. }) => corrupt.push(key),
+                Err(DiskvError::IOError(e)) if e.kind() == io::ErrorKind::InvalidData => {
+                    corrupt.push(key)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(corrupt)
+    }
+
+    // moves the value files of keys verify reports into quarantine_dir, created if need be, and deletes the keys,
+    // returning them. files keep their names, replacing quarantined files of the same name. files are renamed, so
+    // quarantine_dir has to be on the file system of base_path; files in memory or of a backend get copied instead.
+    // write locks of all shards are held throughout, so keys can't be rewritten in between verifying and quarantining
+    // them. keys are returned as verify reports them.
+    pub fn repair(&self, quarantine_dir: &path::Path) -> DiskvResult<Vec<String>> {
+        self.check_writable()?;
+        let mut shards = self.write_all()?; // write locks
+        let corrupt = self.corrupt_keys()?;
+        if corrupt.is_empty() {
+            return Ok(Vec::new());
+        }
+        create_dir_all(quarantine_dir, self.options.file_mode)?;
+        for key in &corrupt {
+            let name = self.key_name(key)?;
+            match &*self.storage {
+                Storage::Dir { .. } => {
+                    match fs::rename(self.storage.location(&name), quarantine_dir.join(&name)) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                _ => {
+                    if let Some(buf) = self.storage.read(&name)? {
+                        fs::write(quarantine_dir.join(&name), buf)?;
+                    }
+                }
+            }
+            warn!("quarantined corrupt value of key {}", key_string(key));
+            // a chain closes up over the slot moved out, see remove_stored
+            let i = self.shard_index(key);
+            self.delete_locked(&mut shards[i], key)?;
+        }
+        sync_dir(quarantine_dir)?;
+        self.storage.sync()?;
+        Ok(corrupt.iter().map(|key| key_string(key)).collect())
+    }
+
+    // empties the cache, e.g. to release memory after a bulk load, leaving every value on disk. unlike clear, nothing
+    // is deleted: values get read, and cached again, as they are asked for.
+    pub fn flush_cache(&self) {
+        for shard in self.shards.iter() {
+            Diskv::write_shard(shard).clear(); // write lock
+        }
+    }
+
+    // like flush_cache, for just key. puts write through, so its value is already on disk, and stays there.
+    pub fn evict(&self, key: &str) {
+        if let Ok(mut cache) = self.write_cache(key.as_bytes()) {
+            cache.delete(key.as_bytes());
+        }
+    }
+
+    pub fn delete(&self, key: &str) -> Result<(), DiskvError> {
+        self.delete_bytes(key.as_bytes())
+    }
+
+    // like delete, returning the removed value, None if key was absent
+    pub fn take(&self, key: &str) -> DiskvResult<Option<Vec<u8>>> {
+        self.check_writable()?;
+        Diskv::check_key(key.as_bytes())?;
+        let mut cache = self.write_cache(key.as_bytes())?; // write lock
+        let prev = self.read_locked(&mut cache, key.as_bytes())?;
+        if prev.is_some() {
+            self.delete_locked(&mut cache, key.as_bytes())?;
+        }
+        Ok(prev)
+    }
+
+    // gives the value of from, along with its expiry and cache entry, to key to, returning false if from is absent. a
+    // value to holds is replaced, as a put would. with WritePolicy::WriteAround, to is left uncached instead. files are
+    // renamed, except for hashed and encrypted keys, which are named in their files, so these values get rewritten
+    // under the new key. with the write-ahead log, the value is read and logged under to, so that replaying writes
+    // logged before can't undo the rename.
+    pub fn rename_key(&self, from: &str, to: &str) -> DiskvResult<bool> {
+        self.check_writable()?;
+        Diskv::check_key(from.as_bytes())?;
+        Diskv::check_key(to.as_bytes())?;
+        if from == to {
+            return self.contains_key(from);
+        }
+        // key locks, in stripe order
+        let mut stripes = vec![
+            self.stripe_index(from.as_bytes()),
+            self.stripe_index(to.as_bytes()),
+        ];
+        stripes.sort_unstable();
+        stripes.dedup();
+        let _key_locks: Vec<_> = stripes
+            .iter()
+            .map(|&stripe| lock_stripe(&self.key_locks[stripe]))
+            .collect();
+        // write locks, in shard order
+        let (i, j) = (
+            self.shard_index(from.as_bytes()),
+            self.shard_index(to.as_bytes()),
+        );
+        let mut shards = vec![Diskv::write_shard(&self.shards[i.min(j)])];
+        if i != j {
+            shards.push(Diskv::write_shard(&self.shards[i.max(j)]));
+        }
+        let last = shards.len() - 1;
+        let (fi, ti) = (if i < j { 0 } else { last }, if j < i { 0 } else { last });
+
+        let expires_at = self.read_expiry(from.as_bytes())?;
+        if is_expired(expires_at) {
+            self.delete_locked(&mut shards[fi], from.as_bytes())?;
+            return Ok(false);
+        }
+        let from_name = self.key_name(from.as_bytes())?;
+        let in_place = match self.storage.read_prefix(&from_name, 1)? {
+            Some(prefix) => {
+                !is_hashed(from.as_bytes())
+                    && !is_hashed(to.as_bytes())
+                    && prefix.first() != Some(&ENCRYPTED_MARKER)
+            }
+            None => return Ok(false),
+        };
+        let val = if !in_place || self.wal.is_some() {
+            match self.read_file(from.as_bytes())? {
+                Some(val) => Some(val),
+                None => return Ok(false),
+            }
+        } else {
+            None
+        };
+        if let (Some(_), Some(val)) = (&self.wal, &val) {
+            self.log(WalRecord::Put {
+                key: to.as_bytes().to_vec(),
+                val: val.clone(),
+                expires_at,
+            })?;
+            self.log(WalRecord::Delete {
+                key: from.as_bytes().to_vec(),
+            })?;
+        }
+
+        match val {
+            Some(val) if !in_place => {
+                self.store_value(to.as_bytes(), &val, expires_at)?;
+                remove_stored(&self.storage, from.as_bytes(), self.options.key_hasher)?;
+            }
+            _ => {
+                // expiry first, as with store_value
+                match expires_at {
+                    Some(_) => self.storage.rename(
+                        &self.meta_name(from.as_bytes())?,
+                        &self.meta_name(to.as_bytes())?,
+                    )?,
+                    None => self.storage.remove(&self.meta_name(to.as_bytes())?)?,
+                }
+                self.storage
+                    .rename(&from_name, &self.key_name(to.as_bytes())?)?;
+            }
+        }
+        if self.options.sync_on_write {
+            self.storage.sync_removal(&from_name)?;
+        }
+
+        let entry = shards[fi].take(from.as_bytes());
+        shards[ti].delete(to.as_bytes());
+        if let (Some(e), WritePolicy::WriteThrough) = (entry, self.options.write_policy) {
+            shards[ti].put(to.as_bytes(), e.val);
+            shards[ti].set_expiry(to.as_bytes(), e.expires_at);
+        }
+        Ok(true)
+    }
+
+    // deletes every key starting with prefix, returning how many were deleted. all shards are locked for the whole
+    // scan, as with clear. keys whose file is gone by the time they are reached count as already deleted.
+    pub fn delete_prefix(&self, prefix: &str) -> DiskvResult<usize> {
+        self.check_writable()?;
+        let mut shards = self.write_all()?; // write locks
+        let mut deleted = 0;
+        for key in self.keys()? {
+            if !key.starts_with(prefix) || !self.storage.exists(&self.key_name(key.as_bytes())?)? {
+                continue;
+            }
+            let cache = &mut shards[self.shard_index(key.as_bytes())];
+            self.delete_locked(cache, key.as_bytes())?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    // deletes every key for which f returns false, returning how many were deleted. like delete_prefix, all shards are
+    // locked for the whole scan. every value gets read to be passed to f, expired keys are deleted without it.
+    pub fn retain<F: FnMut(&str, &[u8]) -> bool>(&self, mut f: F) -> DiskvResult<usize> {
+        self.check_writable()?;
+        let mut shards = self.write_all()?; // write locks
+        let mut deleted = 0;
+        for key in self.keys()? {
+            let cache = &mut shards[self.shard_index(key.as_bytes())];
+            let val = match self.read_locked(cache, key.as_bytes())? {
+                Some(val) => val,
+                None => continue,
+            };
+            if !f(&key, &val) {
+                self.delete_locked(cache, key.as_bytes())?;
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    // caller must be holding the write lock
+    fn delete_locked(&self, cache: &mut DiskvCache, key: &[u8]) -> DiskvResult<()> {
+        delete_stored(
+            &self.storage,
+            self.wal.as_deref(),
+            cache,
+            key,
+            self.options.key_hasher,
+            self.options.sync_on_write,
+        )
+    }
+
+    // creates all shard directories of Options::dir_shard_depth up front, so that first writes into them don't pay
+    // for creating them. that is 256 directories per level, 65536 for a depth of 2 and 16 million for 3, so beyond 2
+    // it's rarely worth it. idempotent, and a no-op without dir_shard_depth or for stores not under base_path.
+    pub fn prepare_shards(&self) -> DiskvResult<()> {
+        self.check_writable()?;
+        Ok(self.storage.create_shard_dirs()?)
+    }
+
+    // syncs base_path and its shard directories, so that everything done so far, deletes included, survives a crash.
+    // no-op in memory.
+    pub fn sync(&self) -> DiskvResult<()> {
+        Ok(self.storage.sync()?)
+    }
+
+    fn remove_value(&self, key: &[u8]) -> DiskvResult<()> {
+        remove_stored(&self.storage, key, self.options.key_hasher)?;
+        Ok(())
+    }
+}
+
+// returned by Diskv::get_reader, checks the checksum, if there is one, as the end of the value is reached
+struct ValueReader {
+    inner: Box<dyn Read + Send>,
+    key: String,
+    checksum: Option<u32>,
+    crc: crc32fast::Hasher,
+}
+
+impl Read for ValueReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.update(&buf[..n]);
+        match self.checksum {
+            Some(checksum)
+                if n == 0 && !buf.is_empty() && checksum != self.crc.clone().finalize() =>
+            {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    DiskvError::ChecksumMismatch {
+                        key: self.key.clone(),
+                    }
+                    .to_string(),
+                ))
+            }
+            _ => Ok(n),
+        }
+    }
+}
+
+//
+// DiskvIter
+// Returned by Diskv::iter. Errors are yielded as they occur, stored keys which are not valid UTF-8 as InvalidKey the
+// same as with keys, and iterating can go on past them.
+//
+pub struct DiskvIter<'a> {
+    dkv: &'a Diskv,
+    names: Names,
+}
+
+impl Iterator for DiskvIter<'_> {
+    type Item = DiskvResult<(String, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<DiskvResult<(String, Vec<u8>)>> {
+        loop {
+            let name = match self.names.next()? {
+                Ok(name) => name,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let key = match parse_name(&name) {
+                Some(FileName::Escaped(key)) => key,
+                Some(FileName::Hashed) => match self.dkv.storage.read_key_header(&name) {
+                    Ok(key) => key,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue, // deleted meanwhile
+                    Err(e) => return Some(Err(e.into())),
+                },
+                None => continue,
+            };
+            // left out, as by keys
+            let key = match String::from_utf8(key) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            match self.dkv.get_uncached(&key) {
+                Ok(Some(val)) => return Some(Ok((key, val))),
+                Ok(None) => continue, // deleted or expired meanwhile
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+//
+// DiskvHandle
+// Diskv shared between threads, without wrapping it in an Arc by hand: clones are cheap and all refer to the same
+// store, which is dropped, and so synced as Options::sync_on_drop says, along with the last of them. The API of Diskv,
+// get, put and delete included, is available through Deref.
+//
+#[derive(Clone)]
+pub struct DiskvHandle {
+    diskv: Arc<Diskv>,
+}
+
+impl std::ops::Deref for DiskvHandle {
+    type Target = Diskv;
+
+    fn deref(&self) -> &Diskv {
+        &self.diskv
+    }
+}
+
+//
+// Transaction
+// Puts and deletes buffered by Transaction::put/delete, applied in order by commit while holding write locks of all
+// shards, so other threads see either none or all of them. Keys and values are validated before anything is applied.
+// Should applying an operation fail, those applied before it are undone, restoring values and expiry as they were
+// before commit, and the error is returned. Dropping a transaction without commit discards its operations.
+// With Options::max_disk_bytes, a batch which doesn't fit fails with OutOfSpace before anything is applied, values of
+// other keys are never deleted to make room for it.
+// A crash in the middle of commit can leave some of the operations applied, the write-ahead log doesn't know about
+// transactions.
+//
+pub struct Transaction<'a> {
+    dkv: &'a Diskv,
+    ops: Vec<TransactionOp>,
+}
+
+enum TransactionOp {
+    Put { key: String, val: Vec<u8> },
+    Delete { key: String },
+}
+
+impl TransactionOp {
+    fn key(&self) -> &str {
+        match self {
+            TransactionOp::Put { key, .. } => key,
+            TransactionOp::Delete { key } => key,
+        }
+    }
+}
+
+impl Transaction<'_> {
+    pub fn put(&mut self, key: &str, val: Vec<u8>) {
+        self.ops.push(TransactionOp::Put {
+            key: key.to_string(),
+            val,
+        });
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.ops.push(TransactionOp::Delete {
+            key: key.to_string(),
+        });
+    }
+
+    pub fn commit(self) -> DiskvResult<()> {
+        let dkv = self.dkv;
+        dkv.check_writable()?;
+        for op in &self.ops {
+            Diskv::check_key(op.key().as_bytes())?;
+            if let TransactionOp::Put { val, .. } = op {
+                dkv.check_value(val)?;
+            }
+        }
+        let mut shards = dkv.write_all()?; // write locks
+        Transaction::check_room(dkv, &self.ops)?;
+
+        let mut undo = Vec::with_capacity(self.ops.len());
+        for op in self.ops {
+            let key = op.key().to_string();
+            let prior = match Transaction::read_prior(dkv, &key) {
+                Ok(prior) => prior,
+                Err(e) => {
+                    Transaction::rollback(dkv, &mut shards, undo);
+                    return Err(e);
+                }
+            };
+            let cache = &mut shards[dkv.shard_index(key.as_bytes())];
+            let res = match op {
+                TransactionOp::Put { val, .. } => dkv
+                    .check_external_write(cache, key.as_bytes())
+                    .and_then(|_| {
+                        let policy = dkv.options.write_policy;
+                        dkv.store_cached(cache, key.as_bytes(), val, None, policy)
+                    }),
+                TransactionOp::Delete { .. } => dkv.delete_locked(cache, key.as_bytes()),
+            };
+            // failed operation is undone too, it may have been applied in part
+            undo.push((key, prior));
+            if let Err(e) = res {
+                Transaction::rollback(dkv, &mut shards, undo);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    // with Options::max_disk_bytes, fails with OutOfSpace unless the store is within the limit once ops are applied,
+    // or at least no further beyond it than before. this is checked once for the whole batch, before anything is
+    // applied, as making room would delete keys rollback doesn't know about. like make_disk_room, every value file
+    // gets stat'ed and sizes of new values are taken before encoding. caller must be holding all write locks.
+    fn check_room(dkv: &Diskv, ops: &[TransactionOp]) -> DiskvResult<()> {
+        let limit = match dkv.options.max_disk_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut used = 0;
+        for name in dkv.storage.name_iter()? {
+            let name = name?;
+            if parse_name(&name).is_none() {
+                continue;
+            }
+            if let Some((len, _)) = dkv.storage.stat(&name)? {
+                used += len;
+            }
+        }
+        // size of every key touched once the batch is applied, the last operation on a key winning
+        let mut sizes = HashMap::new();
+        for op in ops {
+            let size = match op {
+                TransactionOp::Put { val, .. } => val.len() as u64,
+                TransactionOp::Delete { .. } => 0,
+            };
+            sizes.insert(op.key(), size);
+        }
+        let mut needed = used;
+        for (key, size) in sizes {
+            let current = match dkv.storage.stat(&dkv.key_name(key.as_bytes())?)? {
+                Some((len, _)) => len,
+                None => 0,
+            };
+            needed = needed.saturating_sub(current) + size;
+        }
+        if needed > limit.max(used) {
+            return Err(DiskvError::OutOfSpace(io::Error::new(
+                io::ErrorKind::StorageFull,
+                format!(
+                    "transaction needs {} bytes, max_disk_bytes is {}",
+                    needed, limit
+                ),
+            )));
+        }
+        Ok(())
+    }
+
+    // value and expiry of key as stored, None if it's absent
+    fn read_prior(dkv: &Diskv, key: &str) -> DiskvResult<Prior> {
+        match dkv.read_file(key.as_bytes())? {
+            Some(val) => Ok(Some((val, dkv.read_expiry(key.as_bytes())?))),
+            None => Ok(None),
+        }
+    }
+
+    // restores keys to their prior state, latest operation first. errors are logged and the rest still restored, the
+    // error to report being the one which made commit fail.
+    fn rollback(
+        dkv: &Diskv,
+        shards: &mut [sync::RwLockWriteGuard<'_, DiskvCache>],
+        undo: Vec<(String, Prior)>,
+    ) {
+        for (key, prior) in undo.into_iter().rev() {
+            let cache = &mut shards[dkv.shard_index(key.as_bytes())];
+            let res = match prior {
+                Some((val, expires_at)) => {
+                    let policy = dkv.options.write_policy;
+                    dkv.store_cached(cache, key.as_bytes(), val, expires_at, policy)
+                }
+                None => dkv.delete_locked(cache, key.as_bytes()),
+            };
+            if let Err(e) = res {
+                warn!("failed to roll back key {}: {}", key, e);
+            }
+        }
+    }
+}
+
+type Prior = Option<(Vec<u8>, Option<time::SystemTime>)>;
+
+//
+// Namespace
+// Keyspace within a Diskv, as returned by Diskv::namespace, so that e.g. "users" and "sessions" can use the same keys
+// without colliding. Keys are stored prefixed with the length of the name and the name itself, "5:users:" + key,
+// which no key of another namespace starts with. Files, cache and locks are those of the Diskv, its limits apply to
+// prefixed keys, so keys of a namespace can be that much shorter. Keys put through Diskv itself which happen to start
+// with the prefix are seen by the namespace too.
+//
+pub struct Namespace<'a> {
+    dkv: &'a Diskv,
+    prefix: String,
+}
+
+impl Namespace<'_> {
+    pub fn get(&self, key: &str) -> DiskvResult<Option<Vec<u8>>> {
+        self.dkv.get(&self.key(key))
+    }
+
+    pub fn put(&self, key: &str, val: Vec<u8>) -> DiskvResult<()> {
+        self.dkv.put(&self.key(key), val)
+    }
+
+    pub fn put_with_ttl(&self, key: &str, val: Vec<u8>, ttl: time::Duration) -> DiskvResult<()> {
+        self.dkv.put_with_ttl(&self.key(key), val, ttl)
+    }
+
+    pub fn delete(&self, key: &str) -> DiskvResult<()> {
+        self.dkv.delete(&self.key(key))
+    }
+
+    pub fn contains_key(&self, key: &str) -> DiskvResult<bool> {
+        self.dkv.contains_key(&self.key(key))
+    }
+
+    // keys of the namespace, without the prefix, in no particular order
+    pub fn keys(&self) -> DiskvResult<Vec<String>> {
+        Ok(self
+            .dkv
+            .keys()?
+            .into_iter()
+            .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_string))
+            .collect())
+    }
+
+    // removes all keys of the namespace, leaving those of others alone. write locks of all shards are held
+    // throughout, as by Diskv::clear, but should a delete fail, keys deleted before it stay deleted.
+    pub fn clear(&self) -> DiskvResult<()> {
+        let dkv = self.dkv;
+        dkv.check_writable()?;
+        let mut shards = dkv.write_all()?; // write locks
+        for key in dkv.keys()? {
+            if key.starts_with(&self.prefix) {
+                let i = dkv.shard_index(key.as_bytes());
+                dkv.delete_locked(&mut shards[i], key.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+}
+
+// key/value pairs by prefix, as returned by Diskv::scan_prefixes
+type PrefixBuckets = HashMap<String, Vec<(String, Vec<u8>)>>;
+
+// delete_locked, for the reaper which has no Diskv. logs the delete, removes the files of key and uncaches it.
+fn delete_stored(
+    storage: &Storage,
+    wal: Option<&Wal>,
+    cache: &mut DiskvCache,
+    key: &[u8],
+    hasher: KeyHasher,
+    sync_on_write: bool,
+) -> DiskvResult<()> {
+    let name = remove_logged(storage, wal, key, hasher)?;
+    cache.delete(key);
+    if sync_on_write {
+        storage.sync_removal(&name)?;
+    }
+    Ok(())
+}
+
+// logs the delete of key and removes its files, see remove_stored
+fn remove_logged(
+    storage: &Storage,
+    wal: Option<&Wal>,
+    key: &[u8],
+    hasher: KeyHasher,
+) -> DiskvResult<String> {
+    if let Some(wal) = wal {
+        wal.append(&WalRecord::Delete { key: key.to_vec() })?;
+    }
+    remove_stored(storage, key, hasher)
+}
+
+// key locks guard no data, so a poisoned one is taken over as it is
+fn lock_stripe(stripe: &sync::Mutex<()>) -> sync::MutexGuard<'_, ()> {
+    stripe.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// errors which may well not happen again when retried, see Options::io_retries
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+// removes the files of key, returning the name of its value file. in a chain, the last key of the chain takes the
+// slot of key, its value replacing that of key in a single rename, see Key encoding.
+fn remove_stored(storage: &Storage, key: &[u8], hasher: KeyHasher) -> DiskvResult<String> {
+    let name = locate_name(storage, key, hasher)?;
+    storage.remove(&meta_name_of(&name))?;
+    let base = encode_name(key, hasher);
+    // a slot already emptied, e.g. by Diskv::repair, still gets the last key of the chain
+    if !base.starts_with(HASHED_PREFIX) {
+        storage.remove(&name)?;
+        return Ok(name);
+    }
+    let slot = match name.strip_prefix(&base).and_then(|s| s.strip_prefix('-')) {
+        Some(slot) => slot
+            .parse()
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?,
+        None => 0,
+    };
+    let mut last = slot;
+    while storage.exists(&slot_name(&base, last + 1))? {
+        last += 1;
+    }
+    if last == slot {
+        storage.remove(&name)?;
+        return Ok(name);
+    }
+    let last_name = slot_name(&base, last);
+    storage.rename(&last_name, &name)?;
+    if storage.exists(&meta_name_of(&last_name))? {
+        storage.rename(&meta_name_of(&last_name), &meta_name_of(&name))?;
+    }
+    Ok(name)
+}
+
+// best effort, drop has no way to report errors
+impl Drop for Diskv {
+    fn drop(&mut self) {
+        if let Some(reaper) = self.reaper.take() {
+            reaper.stop();
+        }
+        if !self.options.sync_on_drop || self.options.read_only {
+            return;
+        }
+        if let Err(e) = self.sync() {
+            warn!("failed to sync on drop: {}", e);
+        }
+        if let Err(e) = self.checkpoint() {
+            warn!("failed to checkpoint on drop: {}", e);
+        }
+    }
+}
+
+//
+// Reaper
+// Background thread spawned by Diskv::new when Options::reaper_interval is set. Every interval it looks at all
+// expiry files and deletes the keys which have expired, files and cache entries, like a get of them would. It shares
+// storage, log, key locks and cache shards with Diskv, taking the key lock and the write lock of the shard of a key
+// for deleting it, and is stopped and joined when Diskv is dropped. Errors are logged and the next round tries again.
+//
+struct Reaper {
+    shutdown: Arc<(sync::Mutex<bool>, sync::Condvar)>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Reaper {
+    fn spawn(dkv: &Diskv, interval: time::Duration) -> DiskvResult<Reaper> {
+        let shutdown = Arc::new((sync::Mutex::new(false), sync::Condvar::new()));
+        let storage = Arc::clone(&dkv.storage);
+        let wal = dkv.wal.clone();
+        let shards = Arc::clone(&dkv.shards);
+        let key_locks = Arc::clone(&dkv.key_locks);
+        let hasher = dkv.options.key_hasher;
+        let sync_on_write = dkv.options.sync_on_write;
+        let thread_shutdown = Arc::clone(&shutdown);
+        let thread = thread::Builder::new()
+            .name(String::from("diskv-reaper"))
+            .spawn(move || {
+                let (stopped, wakeup) = &*thread_shutdown;
+                let mut stopped = stopped.lock().unwrap_or_else(|e| e.into_inner());
+                loop {
+                    stopped = wakeup
+                        .wait_timeout(stopped, interval)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0;
+                    if *stopped {
+                        return;
+                    }
+                    match reap(
+                        &storage,
+                        wal.as_deref(),
+                        &shards,
+                        &key_locks,
+                        hasher,
+                        sync_on_write,
+                    ) {
+                        Ok(0) => {}
+                        Ok(n) => debug!("reaped {} expired keys", n),
+                        Err(e) => warn!("failed to reap expired keys: {}", e),
+                    }
+                }
+            })?;
+        Ok(Reaper { shutdown, thread })
+    }
+
+    fn stop(self) {
+        let (stopped, wakeup) = &*self.shutdown;
+        *stopped.lock().unwrap_or_else(|e| e.into_inner()) = true;
+        wakeup.notify_one();
+        if self.thread.join().is_err() {
+            warn!("reaper thread panicked");
+        }
+    }
+}
+
+// deletes every expired key, returning how many were deleted
+fn reap(
+    storage: &Storage,
+    wal: Option<&Wal>,
+    shards: &[sync::RwLock<DiskvCache>],
+    key_locks: &[sync::Mutex<()>],
+    hasher: KeyHasher,
+    sync_on_write: bool,
+) -> DiskvResult<usize> {
+    let mut reaped = 0;
+    for name in storage.names()? {
+        let value_name = match name.strip_suffix(META_SUFFIX) {
+            Some(value_name) => value_name,
+            None => continue,
+        };
+        let key = match parse_name(value_name) {
+            Some(FileName::Escaped(key)) => key,
+            Some(FileName::Hashed) => match storage.read_key_header(value_name) {
+                Ok(key) => key,
+                // expiry file without value, e.g. left by a crash, or deleted meanwhile
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            },
+            None => continue,
+        };
+
+        // key lock, then the write lock of its shard, in the order Diskv takes them
+        let _key_lock = lock_stripe(&key_locks[shard_of(&key, hasher, key_locks.len())]);
+        let mut cache = Diskv::write_shard(&shards[shard_of(&key, hasher, shards.len())]);
+
+        // key may have been put again, or moved within its chain, since it was listed
+        let name = meta_name_of(&locate_name(storage, &key, hasher)?);
+        let expires_at = match storage.read(&name)? {
+            Some(buf) => decode_expiry(&buf)?,
+            None => continue,
+        };
+        if is_expired(Some(expires_at)) {
+            delete_stored(storage, wal, &mut cache, &key, hasher, sync_on_write)?;
+            reaped += 1;
+        }
+    }
+    Ok(reaped)
+}
+
+// expiry and temporary files, as well as files not created by Diskv, don't have names produced by encode_name
+fn is_value_name(name: &str) -> bool {
+    parse_name(name).is_some()
+}
+
+// value, expiry or temporary file, i.e. name produced by encode_name, optionally followed by META_SUFFIX and then
+// optionally by TMP_INFIX + pid + "." + count, see tmp_path. files of versions naming them TMP_INFIX + pid count too.
+fn is_store_name(mut name: &str) -> bool {
+    if let Some(i) = name.find(TMP_INFIX) {
+        let suffix = &name[i + TMP_INFIX.len()..];
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        let valid = match suffix.split_once('.') {
+            Some((pid, count)) => is_number(pid) && is_number(count),
+            None => is_number(suffix),
+        };
+        if !valid {
+            return false;
+        }
+        name = &name[..i];
+    }
+    parse_name(name.strip_suffix(META_SUFFIX).unwrap_or(name)).is_some()
+}
+
+//
+// Storage
+// Flat set of named files Diskv keeps values and expiry files in: files under base_path, or a HashMap when the
+// store is in memory. Names and content are the same either way, so everything layered on top (key encoding, expiry,
+// codecs) works unchanged. The map has its own lock as files are read without holding the cache lock.
+// With a depth, files are spread over that many levels of sub-directories, see shard_dir. Files are written through
+// a buffer of write_buffer_size bytes. With Options::backend, files are kept by the backend, which FsBackend and
+// MemBackend implement on top of Dir and Memory.
+//
+pub(crate) enum Storage {
+    Dir {
+        dir: path::PathBuf,
+        depth: usize,
+        write_buffer_size: usize,
+        file_mode: Option<u32>,
+    },
+    Memory(sync::RwLock<MemoryFiles>),
+    Backend(Box<dyn Backend>),
+}
+
+pub(crate) type MemoryFiles = HashMap<String, MemoryFile>;
+
+pub(crate) struct MemoryFile {
+    buf: Vec<u8>,
+    modified: time::SystemTime,
+}
+
+// a level for every byte of a u64 hash
+const MAX_DIR_SHARD_DEPTH: usize = 8;
+
+// directory of file name: depth levels, each named after a byte of the hash of the value name the file belongs to,
+// as two hex digits. value, expiry and temporary files of a key so all end up in the same directory.
+fn shard_dir(dir: &path::Path, depth: usize, name: &str) -> path::PathBuf {
+    let value_name = name.split('.').next().unwrap_or(name);
+    let hash = fnv1a(value_name.as_bytes());
+    let mut path = dir.to_path_buf();
+    for i in 0..depth {
+        path.push(format!("{:02x}", (hash >> (8 * i)) as u8));
+    }
+    path
+}
+
+fn is_shard_dir_name(name: &str) -> bool {
+    name.len() == 2
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+// file names of storage, as returned by Storage::name_iter. with Dir, files found depth levels of shard directories
+// below dir are read a directory entry at a time, keeping a handle of each level open. names in memory are collected
+// up front.
+enum Names {
+    Dir {
+        depth: usize,
+        levels: Vec<fs::ReadDir>,
+    },
+    Memory(std::vec::IntoIter<String>),
+}
+
+impl Iterator for Names {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let (depth, levels) = match self {
+            Names::Dir { depth, levels } => (*depth, levels),
+            Names::Memory(names) => return names.next().map(Ok),
+        };
+        loop {
+            let entry = match levels.last_mut()?.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    levels.pop();
+                    continue;
+                }
+            };
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(e) => return Some(Err(e)),
+            };
+            let below = depth + 1 - levels.len(); // levels of shard directories below this entry
+            if below == 0 && file_type.is_file() {
+                return Some(Ok(name));
+            }
+            if below > 0 && file_type.is_dir() && is_shard_dir_name(&name) {
+                match fs::read_dir(entry.path()) {
+                    Ok(level) => levels.push(level),
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+    }
+}
+
+// depth levels of shard directories below dir, synced like those created by writes
+fn create_shard_levels(dir: &path::Path, depth: usize, file_mode: Option<u32>) -> io::Result<()> {
+    if depth == 0 {
+        return Ok(());
+    }
+    for b in 0..=u8::MAX {
+        let shard = dir.join(format!("{:02x}", b));
+        if !shard.is_dir() {
+            create_dir_all(&shard, file_mode)?;
+        }
+        create_shard_levels(&shard, depth - 1, file_mode)?;
+    }
+    sync_dir(dir)
+}
+
+// all shard directories below dir, deepest first
+fn collect_shard_dirs(
+    dir: &path::Path,
+    depth: usize,
+    dirs: &mut Vec<path::PathBuf>,
+) -> io::Result<()> {
+    if depth == 0 {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let is_shard = entry.file_type()?.is_dir()
+            && entry.file_name().to_str().is_some_and(is_shard_dir_name);
+        if is_shard {
+            collect_shard_dirs(&entry.path(), depth - 1, dirs)?;
+            dirs.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+impl Storage {
+    pub(crate) fn dir(
+        dir: path::PathBuf,
+        depth: usize,
+        write_buffer_size: usize,
+        file_mode: Option<u32>,
+    ) -> Storage {
+        Storage::Dir {
+            dir,
+            depth: depth.min(MAX_DIR_SHARD_DEPTH),
+            write_buffer_size: write_buffer_size.max(1),
+            file_mode,
+        }
+    }
+
+    // map operations can't leave it half updated, so a poisoned lock is simply used as is
+    fn memory_read(map: &sync::RwLock<MemoryFiles>) -> sync::RwLockReadGuard<'_, MemoryFiles> {
+        map.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn memory_write(map: &sync::RwLock<MemoryFiles>) -> sync::RwLockWriteGuard<'_, MemoryFiles> {
+        map.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    // where a file is, for error messages: its path, or just its name if it's not under a directory
+    fn location(&self, name: &str) -> path::PathBuf {
+        match self {
+            Storage::Dir { dir, depth, .. } => Storage::path(dir, *depth, name),
+            Storage::Memory(_) | Storage::Backend(_) => path::PathBuf::from(name),
+        }
+    }
+
+    // path of a file, for directory storage only
+    fn path(dir: &path::Path, depth: usize, name: &str) -> path::PathBuf {
+        shard_dir(dir, depth, name).join(name)
+    }
+
+    // directory of name, created if need be, for directory storage only
+    fn create_shard_dir(
+        dir: &path::Path,
+        depth: usize,
+        name: &str,
+        file_mode: Option<u32>,
+    ) -> io::Result<path::PathBuf> {
+        let shard = shard_dir(dir, depth, name);
+        if depth > 0 && !shard.is_dir() {
+            create_dir_all(&shard, file_mode)?;
+            // entries of the new directories have to be durable too
+            for d in shard.ancestors().skip(1).take(depth) {
+                sync_dir(d)?;
+            }
+        }
+        Ok(shard)
+    }
+
+    // None if there is no such file
+    pub(crate) fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Storage::Dir { dir, depth, .. } => match fs::read(Storage::path(dir, *depth, name)) {
+                Ok(buf) => Ok(Some(buf)),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        Ok(None)
+                    } else {
+                        Err(e)
+                    }
+                }
+            },
+            Storage::Memory(map) => Ok(Storage::memory_read(map).get(name).map(|f| f.buf.clone())),
+            Storage::Backend(backend) => backend.read(name),
+        }
+    }
+
+    pub(crate) fn write(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+        let chunk_size = match self {
+            Storage::Dir {
+                write_buffer_size, ..
+            } => *write_buffer_size,
+            Storage::Memory(_) => buf.len().max(1),
+            Storage::Backend(backend) => return backend.write(name, buf),
+        };
+        self.write_with(name, |w| {
+            for chunk in buf.chunks(chunk_size) {
+                w.write_all(chunk)?;
+            }
+            Ok(())
+        })
+    }
+
+    // like write, leaving the file, and its directory, to be synced later, by sync_file and sync
+    fn write_unsynced(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Storage::Dir {
+                dir,
+                depth,
+                write_buffer_size,
+                file_mode,
+            } => {
+                let shard = Storage::create_shard_dir(dir, *depth, name, *file_mode)?;
+                write_atomic(
+                    &shard.join(name),
+                    *write_buffer_size,
+                    *file_mode,
+                    false,
+                    |w| w.write_all(buf),
+                )
+            }
+            Storage::Memory(_) | Storage::Backend(_) => self.write(name, buf),
+        }
+    }
+
+    // makes the content of a file written by write_unsynced durable
+    fn sync_file(&self, name: &str) -> io::Result<()> {
+        match self {
+            Storage::Dir { dir, depth, .. } => {
+                fs::File::open(Storage::path(dir, *depth, name))?.sync_all()
+            }
+            Storage::Memory(_) | Storage::Backend(_) => Ok(()),
+        }
+    }
+
+    // like write, with the content written by f. the file is replaced only if f succeeds.
+    fn write_with<E, F>(&self, name: &str, f: F) -> Result<(), E>
+    where
+        E: From<io::Error>,
+        F: FnOnce(&mut dyn WriteSeek) -> Result<(), E>,
+    {
+        match self {
+            Storage::Dir {
+                dir,
+                depth,
+                write_buffer_size,
+                file_mode,
+            } => {
+                let shard = Storage::create_shard_dir(dir, *depth, name, *file_mode)?;
+                write_atomic(&shard.join(name), *write_buffer_size, *file_mode, true, f)
+            }
+            Storage::Memory(map) => {
+                let mut w = io::Cursor::new(Vec::new());
+                f(&mut w)?;
+                let file = MemoryFile {
+                    buf: w.into_inner(),
+                    modified: time::SystemTime::now(),
+                };
+                Storage::memory_write(map).insert(name.to_string(), file);
+                Ok(())
+            }
+            Storage::Backend(backend) => {
+                let mut w = io::Cursor::new(Vec::new());
+                f(&mut w)?;
+                Ok(backend.write(name, &w.into_inner())?)
+            }
+        }
+    }
+
+    // like read, along with the modification time of the file as read
+    fn read_with_modified(&self, name: &str) -> io::Result<Option<(Vec<u8>, time::SystemTime)>> {
+        match self {
+            Storage::Dir { dir, depth, .. } => {
+                match fs::File::open(Storage::path(dir, *depth, name)) {
+                    Ok(mut f) => {
+                        let modified = f.metadata()?.modified()?;
+                        let mut buf = Vec::new();
+                        f.read_to_end(&mut buf)?;
+                        Ok(Some((buf, modified)))
+                    }
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::NotFound {
+                            Ok(None)
+                        } else {
+                            Err(e)
+                        }
+                    }
+                }
+            }
+            Storage::Memory(map) => Ok(Storage::memory_read(map)
+                .get(name)
+                .map(|f| (f.buf.clone(), f.modified))),
+            // backends can't read both at once
+            Storage::Backend(backend) => match backend.read(name)? {
+                Some(buf) => {
+                    let modified = backend.stat(name)?.map_or(time::UNIX_EPOCH, |(_, m)| m);
+                    Ok(Some((buf, modified)))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+
+    // reader of a file, None if there is no such file
+    fn open(&self, name: &str) -> io::Result<Option<Box<dyn Read + Send>>> {
+        match self {
+            Storage::Dir { dir, depth, .. } => {
+                match fs::File::open(Storage::path(dir, *depth, name)) {
+                    Ok(f) => Ok(Some(Box::new(io::BufReader::new(f)))),
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::NotFound {
+                            Ok(None)
+                        } else {
+                            Err(e)
+                        }
+                    }
+                }
+            }
+            Storage::Memory(map) => Ok(Storage::memory_read(map)
+                .get(name)
+                .map(|f| Box::new(io::Cursor::new(f.buf.clone())) as Box<dyn Read + Send>)),
+            Storage::Backend(backend) => Ok(backend
+                .read(name)?
+                .map(|buf| Box::new(io::Cursor::new(buf)) as Box<dyn Read + Send>)),
+        }
+    }
+
+    // moves file from to name to, replacing the file to if there is one. NotFound if there is no file from. the
+    // rename is synced, as writes are.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        match self {
+            Storage::Dir {
+                dir,
+                depth,
+                file_mode,
+                ..
+            } => {
+                let from_path = Storage::path(dir, *depth, from);
+                let shard = Storage::create_shard_dir(dir, *depth, to, *file_mode)?;
+                fs::rename(&from_path, shard.join(to))?;
+                sync_dir(&shard)?;
+                match from_path.parent() {
+                    Some(from_shard) if from_shard != shard => sync_dir(from_shard),
+                    _ => Ok(()),
+                }
+            }
+            Storage::Memory(map) => {
+                let mut files = Storage::memory_write(map);
+                match files.remove(from) {
+                    Some(file) => {
+                        files.insert(to.to_string(), file);
+                        Ok(())
+                    }
+                    None => Err(io::Error::new(io::ErrorKind::NotFound, from.to_string())),
+                }
+            }
+            // not atomic, but callers hold the cache locks of both names
+            Storage::Backend(backend) => match backend.read(from)? {
+                Some(buf) => {
+                    backend.write(to, &buf)?;
+                    backend.delete(from)
+                }
+                None => Err(io::Error::new(io::ErrorKind::NotFound, from.to_string())),
+            },
+        }
+    }
+
+    // no-op if there is no such file
+    pub(crate) fn remove(&self, name: &str) -> io::Result<()> {
+        match self {
+            Storage::Dir { dir, depth, .. } => {
+                remove_file_if_exists(&Storage::path(dir, *depth, name))
+            }
+            Storage::Memory(map) => {
+                Storage::memory_write(map).remove(name);
+                Ok(())
+            }
+            Storage::Backend(backend) => backend.delete(name),
+        }
+    }
+
+    fn exists(&self, name: &str) -> io::Result<bool> {
+        match self {
+            Storage::Dir { dir, depth, .. } => match fs::metadata(Storage::path(dir, *depth, name))
+            {
+                Ok(_) => Ok(true),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        Ok(false)
+                    } else {
+                        Err(e)
+                    }
+                }
+            },
+            Storage::Memory(map) => Ok(Storage::memory_read(map).contains_key(name)),
+            Storage::Backend(backend) => Ok(backend.stat(name)?.is_some()),
+        }
+    }
+
+    // first n bytes of a file, fewer if it is shorter. None if there is no such file.
+    fn read_prefix(&self, name: &str, n: usize) -> io::Result<Option<Vec<u8>>> {
+        match self {
+            Storage::Dir { dir, depth, .. } => {
+                match fs::File::open(Storage::path(dir, *depth, name)) {
+                    Ok(f) => {
+                        let mut buf = Vec::with_capacity(n);
+                        f.take(n as u64).read_to_end(&mut buf)?;
+                        Ok(Some(buf))
+                    }
+                    Err(e) => {
+                        if e.kind() == io::ErrorKind::NotFound {
+                            Ok(None)
+                        } else {
+                            Err(e)
+                        }
+                    }
+                }
+            }
+            Storage::Memory(map) => Ok(Storage::memory_read(map)
+                .get(name)
+                .map(|f| f.buf[..n.min(f.buf.len())].to_vec())),
+            Storage::Backend(backend) => Ok(backend.read(name)?.map(|mut buf| {
+                buf.truncate(n);
+                buf
+            })),
+        }
+    }
+
+    // length and modification time of a file, None if there is no such file
+    pub(crate) fn stat(&self, name: &str) -> io::Result<Option<(u64, time::SystemTime)>> {
+        match self {
+            Storage::Dir { dir, depth, .. } => match fs::metadata(Storage::path(dir, *depth, name))
+            {
+                Ok(m) => Ok(Some((m.len(), m.modified()?))),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::NotFound {
+                        Ok(None)
+                    } else {
+                        Err(e)
+                    }
+                }
+            },
+            Storage::Memory(map) => Ok(Storage::memory_read(map)
+                .get(name)
+                .map(|f| (f.buf.len() as u64, f.modified))),
+            Storage::Backend(backend) => backend.stat(name),
+        }
+    }
+
+    // names of all files. directories other than shard directories, files not at the depth of shard directories, and
+    // files whose names are not valid UTF-8, can't have been created by Diskv, so they are skipped.
+    pub(crate) fn names(&self) -> io::Result<Vec<String>> {
+        self.name_iter()?.collect()
+    }
+
+    fn name_iter(&self) -> io::Result<Names> {
+        match self {
+            Storage::Dir { dir, depth, .. } => Ok(Names::Dir {
+                depth: *depth,
+                levels: vec![fs::read_dir(dir)?],
+            }),
+            Storage::Memory(map) => {
+                let names: Vec<String> = Storage::memory_read(map).keys().cloned().collect();
+                Ok(Names::Memory(names.into_iter()))
+            }
+            Storage::Backend(backend) => Ok(Names::Memory(backend.list()?.into_iter())),
+        }
+    }
+
+    // every shard directory, for directory storage only. directories already there are left as they are.
+    fn create_shard_dirs(&self) -> io::Result<()> {
+        match self {
+            Storage::Dir {
+                dir,
+                depth,
+                file_mode,
+                ..
+            } => create_shard_levels(dir, *depth, *file_mode),
+            Storage::Memory(_) | Storage::Backend(_) => Ok(()),
+        }
+    }
+
+    // makes removals durable, new content is synced by write already. every shard directory gets synced, so with
+    // depth this gets slower as the store grows.
+    fn sync(&self) -> io::Result<()> {
+        match self {
+            Storage::Dir { dir, depth, .. } => {
+                let mut dirs = Vec::new();
+                collect_shard_dirs(dir, *depth, &mut dirs)?;
+                for d in dirs {
+                    sync_dir(&d)?;
+                }
+                sync_dir(dir)
+            }
+            Storage::Memory(_) | Storage::Backend(_) => Ok(()),
+        }
+    }
+
+    // makes removal of name durable, syncing just its directory
+    fn sync_removal(&self, name: &str) -> io::Result<()> {
+        match self {
+            Storage::Dir { dir, depth, .. } => match sync_dir(&shard_dir(dir, *depth, name)) {
+                // shard directory never got created
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                r => r,
+            },
+            Storage::Memory(_) | Storage::Backend(_) => Ok(()),
+        }
+    }
+
+    // just the key header of a hashed file
+    fn read_key_header(&self, name: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Storage::Dir { dir, depth, .. } => read_key_header(&Storage::path(dir, *depth, name)),
+            Storage::Memory(map) => match Storage::memory_read(map).get(name) {
+                Some(f) => Ok(split_key_header(&f.buf)?.0.to_vec()),
+                None => Err(io::Error::from(io::ErrorKind::NotFound)),
+            },
+            Storage::Backend(backend) => match backend.read(name)? {
+                Some(buf) => Ok(split_key_header(&buf)?.0.to_vec()),
+                None => Err(io::Error::from(io::ErrorKind::NotFound)),
+            },
+        }
+    }
+}
+
+//
+// Archive
+// Format of Diskv::export: ARCHIVE_MAGIC, ARCHIVE_VERSION as u32 little endian, then a record per key: key length as
+// u32 little endian, key, value length as u64 little endian, value. A zero key length, which no valid key has, ends
+// the archive, so that a stream cut short between records is told apart from a complete one.
+//
+const ARCHIVE_MAGIC: &[u8; 8] = b"DISKVARC";
+const ARCHIVE_VERSION: u32 = 1;
+
+// reads exactly len bytes, without trusting len for an allocation up front
+fn read_archive_bytes<R: Read>(r: &mut R, len: u64) -> DiskvResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    r.take(len).read_to_end(&mut buf)?;
+    if (buf.len() as u64) < len {
+        return Err(DiskvError::CorruptArchive(String::from("truncated")));
+    }
+    Ok(buf)
+}
+
+//
+// Write-ahead log
+// With Options::wal, every put and delete is appended to WAL_NAME under base_path, and synced, before any value or
+// expiry file is touched. Diskv::new replays the log, so writes interrupted by a crash get completed, and then
+// truncates it. Records are framed as op, key length as u32 little endian, key, value length as u32 little endian,
+// value, and for WAL_PUT_EXPIRING the expiry as encoded in expiry files. A record cut short by a crash is ignored.
+// WAL_NAME can't be produced by encode_name, so the log is never taken for a value.
+//
+const WAL_NAME: &str = "wal.log";
+const WAL_PUT: u8 = 1;
+const WAL_PUT_EXPIRING: u8 = 2;
+const WAL_DELETE: u8 = 3;
+
+enum WalRecord {
+    Put {
+        key: Vec<u8>,
+        val: Vec<u8>,
+        expires_at: Option<time::SystemTime>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+}
+
+// appends come from writers of all cache shards, so the file has its own lock
+struct Wal {
+    file: sync::Mutex<fs::File>,
+}
+
+impl Wal {
+    // opens the log at path along with the records it already holds
+    fn open(path: &path::Path, file_mode: Option<u32>) -> io::Result<(Wal, Vec<WalRecord>)> {
+        let mut options = fs::OpenOptions::new();
+        options.read(true).append(true).create(true);
+        set_file_mode(&mut options, file_mode);
+        let mut file = options.open(path)?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf)?;
+        let wal = Wal {
+            file: sync::Mutex::new(file),
+        };
+        Ok((wal, decode_wal(&buf)))
+    }
+
+    fn append(&self, record: &WalRecord) -> io::Result<()> {
+        let (op, key, val, expires_at) = match record {
+            WalRecord::Put {
+                key,
+                val,
+                expires_at: None,
+            } => (WAL_PUT, key, &val[..], None),
+            WalRecord::Put {
+                key,
+                val,
+                expires_at: Some(t),
+            } => (WAL_PUT_EXPIRING, key, &val[..], Some(*t)),
+            WalRecord::Delete { key } => (WAL_DELETE, key, &[][..], None),
+        };
+        if key.len() > u32::MAX as usize || val.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "write-ahead log records are limited to 4 GiB",
+            ));
+        }
+        let mut buf = Vec::with_capacity(17 + key.len() + val.len());
+        buf.push(op);
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&(val.len() as u32).to_le_bytes());
+        buf.extend_from_slice(val);
+        if let Some(t) = expires_at {
+            buf.extend_from_slice(&encode_expiry(t));
+        }
+        let mut file = self.file();
+        file.write_all(&buf)?;
+        file.sync_data()
+    }
+
+    // a panic while appending can at worst leave an incomplete record behind, which is ignored on replay anyway
+    fn file(&self) -> sync::MutexGuard<'_, fs::File> {
+        self.file.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn truncate(&self) -> io::Result<()> {
+        let file = self.file();
+        file.set_len(0)?;
+        file.sync_all()
+    }
+}
+
+fn decode_wal(mut buf: &[u8]) -> Vec<WalRecord> {
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        match decode_wal_record(buf) {
+            Some((record, rest)) => {
+                records.push(record);
+                buf = rest;
+            }
+            None => {
+                warn!(
+                    "ignoring {} bytes of incomplete write-ahead log record",
+                    buf.len()
+                );
+                break;
+            }
+        }
+    }
+    records
+}
+
+// None if buf ends before the record does, or doesn't hold a record at all
+fn decode_wal_record(buf: &[u8]) -> Option<(WalRecord, &[u8])> {
+    fn take(buf: &[u8], n: usize) -> Option<(&[u8], &[u8])> {
+        if buf.len() < n {
+            return None;
+        }
+        Some(buf.split_at(n))
+    }
+    fn take_framed(buf: &[u8]) -> Option<(&[u8], &[u8])> {
+        let (len, rest) = take(buf, 4)?;
+        take(rest, u32::from_le_bytes(len.try_into().unwrap()) as usize)
+    }
+
+    let (op, rest) = take(buf, 1)?;
+    let (key, rest) = take_framed(rest)?;
+    let (val, rest) = take_framed(rest)?;
+    let key = key.to_vec();
+    match op[0] {
+        WAL_PUT => Some((
+            WalRecord::Put {
+                key,
+                val: val.to_vec(),
+                expires_at: None,
+            },
+            rest,
+        )),
+        WAL_PUT_EXPIRING => {
+            let (expiry, rest) = take(rest, 8)?;
+            let record = WalRecord::Put {
+                key,
+                val: val.to_vec(),
+                expires_at: Some(decode_expiry(expiry).ok()?),
+            };
+            Some((record, rest))
+        }
+        WAL_DELETE => Some((WalRecord::Delete { key }, rest)),
+        _ => None,
+    }
+}
+
+// writes to a temporary file next to path and renames it over path. with sync, both the file and its directory are
+// synced, so once this returns path holds either the old or the new content in full, even across a crash.
+// content is written by f, through a buffer of buffer_size bytes.
+fn write_atomic<E, F>(
+    path: &path::Path,
+    buffer_size: usize,
+    file_mode: Option<u32>,
+    sync: bool,
+    f: F,
+) -> Result<(), E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&mut dyn WriteSeek) -> Result<(), E>,
+{
+    let tmp_path = tmp_path(path);
+    let written = create_new_file(&tmp_path, file_mode)
+        .map_err(E::from)
+        .and_then(|file| {
+            let mut w = io::BufWriter::with_capacity(buffer_size, file);
+            f(&mut w)?;
+            let file = w.into_inner().map_err(io::IntoInnerError::into_error)?;
+            if sync {
+                file.sync_all()?;
+            }
+            Ok(())
+        });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    fs::rename(&tmp_path, path)?;
+    if !sync {
+        return Ok(());
+    }
+    Ok(sync_dir(
+        path.parent().unwrap_or_else(|| path::Path::new(".")),
+    )?)
+}
+
+// temporary file to write path through, named as TMP_INFIX says
+fn tmp_path(path: &path::Path) -> path::PathBuf {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    let count = TMP_COUNT.fetch_add(1, Ordering::Relaxed);
+    tmp_name.push(format!("{}{}.{}", TMP_INFIX, std::process::id(), count));
+    path.with_file_name(tmp_name)
+}
+
+// creates a file which must not exist yet, with file_mode, if any, as its permissions
+fn create_new_file(path: &path::Path, file_mode: Option<u32>) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    set_file_mode(&mut options, file_mode);
+    options.open(path)
+}
+
+fn set_file_mode(options: &mut fs::OpenOptions, file_mode: Option<u32>) {
+    #[cfg(unix)]
+    if let Some(mode) = file_mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = (options, file_mode);
+}
+
+// like fs::create_dir_all, new directories getting file_mode, if any, with search permission added wherever read
+// permission is given, so that 0o600 makes them 0o700
+pub(crate) fn create_dir_all(path: &path::Path, file_mode: Option<u32>) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = file_mode {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(dir_mode(mode));
+    }
+    #[cfg(not(unix))]
+    let _ = file_mode;
+    builder.create(path)
+}
+
+fn dir_mode(file_mode: u32) -> u32 {
+    file_mode | (file_mode & 0o444) >> 2
+}
+
+// what Storage::write_with writes to: a buffered file, or a Vec in memory
+trait WriteSeek: Write + io::Seek {}
+
+impl<T: Write + io::Seek> WriteSeek for T {}
+
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &path::Path) -> io::Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+// directories can't be opened as files on other platforms, rename is relied upon there
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &path::Path) -> io::Result<()> {
+    Ok(())
+}
+
+fn remove_file_if_exists(path: &path::Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            if e.kind() == io::ErrorKind::NotFound {
+                Ok(())
+            } else {
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    impl Diskv {
+        fn file_path(&self, name: &str) -> path::PathBuf {
+            match &*self.storage {
+                Storage::Dir { dir, depth, .. } => Storage::path(dir, *depth, name),
+                Storage::Memory(_) => panic!("store is in memory"),
+                Storage::Backend(_) => panic!("store has a custom backend"),
+            }
+        }
+
+        fn key_path(&self, key: &str) -> path::PathBuf {
+            self.file_path(&self.key_name(key.as_bytes()).unwrap())
+        }
+
+        fn meta_path(&self, key: &str) -> path::PathBuf {
+            self.file_path(&self.meta_name(key.as_bytes()).unwrap())
+        }
+
+        // whether key has an expiry file, whatever the storage
+        fn has_meta(&self, key: &str) -> bool {
+            let name = self.meta_name(key.as_bytes()).unwrap();
+            self.storage.exists(&name).unwrap()
+        }
+    }
+
+    #[test]
+    fn key_encoding_round_trip() {
+        for key in [
+            "k1",
+            "user:123:name",
+            "a/b",
+            "..",
+            "line\nbreak",
+            "%41",
+            "k1.meta",
+        ]
+        .iter()
+        {
+            let name = encode_name(key.as_bytes(), KeyHasher::Fnv1a);
+            assert!(name.len() <= MAX_NAME_LEN);
+            assert!(!name.contains('/') && !name.contains('.'));
+            match parse_name(&name) {
+                Some(FileName::Escaped(decoded)) => assert_eq!(key.as_bytes(), &decoded[..]),
+                _ => panic!("{} didn't decode", name),
+            }
+        }
+
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        assert!(matches!(
+            parse_name(&encode_name(long.as_bytes(), KeyHasher::Fnv1a)),
+            Some(FileName::Hashed)
+        ));
+
+        let name = encode_name(long.as_bytes(), KeyHasher::DefaultHasher);
+        assert_eq!(1 + 16, name.len());
+        assert!(matches!(parse_name(&name), Some(FileName::Hashed)));
+        #[cfg(feature = "sha2")]
+        {
+            let name = encode_name(long.as_bytes(), KeyHasher::Sha256);
+            assert_eq!(1 + 64, name.len());
+            assert!(matches!(parse_name(&name), Some(FileName::Hashed)));
+        }
+
+        for name in ["k1.meta", "k1.tmp.123", "%4", "%zz", "%4a", "+123", ""].iter() {
+            assert!(parse_name(name).is_none(), "{} parsed", name);
+        }
+    }
+
+    #[cfg(feature = "sha2")]
+    #[test]
+    fn sha256_vectors() {
+        let hex = |bytes: &[u8]| KeyHasher::Sha256.hash_hex(bytes);
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hex(b"")
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hex(b"abc")
+        );
+        // more than one block
+        assert_eq!(
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3",
+            hex("a".repeat(1000).as_bytes())
+        );
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn blake3_names() {
+        let hex = |bytes: &[u8]| KeyHasher::Blake3.hash_hex(bytes);
+        assert_eq!(
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+            hex(b"")
+        );
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        let name = encode_name(long.as_bytes(), KeyHasher::Blake3);
+        assert!(matches!(parse_name(&name), Some(FileName::Hashed)));
+    }
+
+    #[test]
+    fn cache_get_put_get_put_get_delete_get() {
+        let key = String::from("k1");
+
+        let mut c = DiskvCache::new(10);
+        assert_eq!(None, c.get(key.as_bytes()));
+
+        c.put(key.as_bytes(), String::from("abcd").into_bytes());
+        assert_eq!(
+            Some(String::from("abcd").into_bytes()),
+            c.get(key.as_bytes())
+        );
+
+        c.put(key.as_bytes(), String::from("pqrs").into_bytes());
+        assert_eq!(
+            Some(String::from("pqrs").into_bytes()),
+            c.get(key.as_bytes())
+        );
+
+        c.delete(key.as_bytes());
+        assert_eq!(None, c.get(key.as_bytes()));
+    }
+
+    #[test]
+    fn cache_key_overwrite_size_check() {
+        let key1 = String::from("k1");
+        let mut c = DiskvCache::new(10);
+        assert_eq!(None, c.get(key1.as_bytes()));
+
+        c.put(key1.as_bytes(), String::from("0123456789").into_bytes());
+        assert_eq!(
+            Some(String::from("0123456789").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key1.as_bytes(), String::from("9876543210").into_bytes());
+        assert_eq!(
+            Some(String::from("9876543210").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key1.as_bytes(), String::from("123").into_bytes());
+        assert_eq!(
+            Some(String::from("123").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+    }
+
+    #[test]
+    fn cache_make_space() {
+        let key1 = String::from("k1");
+        let key2 = String::from("k2");
+        let key3 = String::from("k3");
+
+        let mut c = DiskvCache::new(10);
+        assert_eq!(None, c.get(key1.as_bytes()));
+        assert_eq!(None, c.get(key2.as_bytes()));
+        assert_eq!(None, c.get(key3.as_bytes()));
+
+        c.put(key1.as_bytes(), String::from("0123456").into_bytes());
+        assert_eq!(
+            Some(String::from("0123456").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key2.as_bytes(), String::from("789").into_bytes());
+        assert_eq!(
+            Some(String::from("789").into_bytes()),
+            c.get(key2.as_bytes())
+        );
+        assert_eq!(
+            Some(String::from("0123456").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key3.as_bytes(), String::from("abcdabcd").into_bytes());
+        assert_eq!(
+            Some(String::from("abcdabcd").into_bytes()),
+            c.get(key3.as_bytes())
+        );
+        assert_eq!(None, c.get(key1.as_bytes()));
+        assert_eq!(None, c.get(key2.as_bytes()));
+    }
+
+    #[test]
+    fn cache_make_space_frees_just_enough() {
+        let mut c = DiskvCache::new(10);
+        for key in ["k1", "k2", "k3", "k4", "k5"].iter() {
+            c.put(key.as_bytes(), vec![b'x'; 2]);
+        }
+        assert_eq!(10, c.stats().cache_size);
+        assert_eq!(0, c.make_space_for(0));
+
+        // 3 more bytes need 2 values of 2 bytes to go, least recently used ones
+        assert_eq!(4, c.make_space_for(3));
+        assert_eq!(6, c.stats().cache_size);
+        assert_eq!(2, c.stats().evictions);
+        assert!(!c.contains(b"k1") && !c.contains(b"k2"));
+        c.put(b"k6", vec![b'y'; 3]);
+        assert_eq!(9, c.stats().cache_size);
+        assert_eq!(2, c.stats().evictions);
+        for key in ["k3", "k4", "k5", "k6"].iter() {
+            assert!(c.contains(key.as_bytes()));
+        }
+
+        // more than can ever fit empties the cache, rather than looping
+        assert_eq!(9, c.make_space_for(11));
+        assert_eq!(0, c.stats().entries);
+    }
+
+    #[test]
+    fn cache_max_entries() {
+        let mut c = DiskvCache::with_policy(1024, Some(3), None, Box::<LruPolicy>::default());
+        for i in 0..100 {
+            c.put(format!("k{}", i).as_bytes(), vec![b'x']);
+            assert!(c.stats().entries <= 3);
+        }
+        assert_eq!(3, c.stats().entries);
+        assert_eq!(97, c.stats().evictions);
+        for key in ["k97", "k98", "k99"].iter() {
+            assert!(c.contains(key.as_bytes()));
+        }
+        // overwriting a cached key doesn't evict another one
+        c.put(b"k99", vec![b'y'; 2]);
+        assert_eq!(97, c.stats().evictions);
+
+        let mut c = DiskvCache::with_policy(1024, Some(0), None, Box::<LruPolicy>::default());
+        c.put(b"k1", vec![b'x']);
+        assert_eq!(0, c.stats().entries);
+    }
+
+    #[test]
+    fn cache_make_space_more() {
+        let key1 = String::from("k1");
+        let key2 = String::from("k2");
+        let key3 = String::from("k3");
+
+        let mut c = DiskvCache::new(5);
+        assert_eq!(None, c.get(key1.as_bytes()));
+        assert_eq!(None, c.get(key2.as_bytes()));
+        assert_eq!(None, c.get(key3.as_bytes()));
+
+        c.put(key1.as_bytes(), String::from("aa").into_bytes());
+        assert_eq!(
+            Some(String::from("aa").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key2.as_bytes(), String::from("bb").into_bytes());
+        assert_eq!(
+            Some(String::from("bb").into_bytes()),
+            c.get(key2.as_bytes())
+        );
+        assert_eq!(
+            Some(String::from("aa").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key3.as_bytes(), String::from("cc").into_bytes());
+        assert_eq!(
+            Some(String::from("cc").into_bytes()),
+            c.get(key3.as_bytes())
+        );
+
+        // only needed space is made - other keys stay intact
+        if c.get(key1.as_bytes()).is_none() {
+            assert_eq!(
+                Some(String::from("bb").into_bytes()),
+                c.get(key2.as_bytes())
+            );
+        } else {
+            assert_eq!(
+                Some(String::from("aa").into_bytes()),
+                c.get(key1.as_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn cache_evicts_least_recently_used() {
+        let key1 = String::from("k1");
+        let key2 = String::from("k2");
+        let key3 = String::from("k3");
+
+        let mut c = DiskvCache::new(10);
+        c.put(key1.as_bytes(), String::from("aaaa").into_bytes());
+        c.put(key2.as_bytes(), String::from("bbbb").into_bytes());
+
+        // reading k1 makes k2 the least recently used one
+        assert_eq!(
+            Some(String::from("aaaa").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+
+        c.put(key3.as_bytes(), String::from("cccc").into_bytes());
+        assert_eq!(
+            Some(String::from("cccc").into_bytes()),
+            c.get(key3.as_bytes())
+        );
+        assert_eq!(
+            Some(String::from("aaaa").into_bytes()),
+            c.get(key1.as_bytes())
+        );
+        assert_eq!(None, c.get(key2.as_bytes()));
+    }
+
+    #[test]
+    fn cache_eviction_policies() {
+        use crate::eviction::FifoPolicy;
+
+        // k1 is read before k3 gets put, so LRU evicts k2 while FIFO evicts k1
+        let evicted = |policy: Box<dyn EvictionPolicy>| -> Vec<&str> {
+            let mut c = DiskvCache::with_policy(4, None, None, policy);
+            c.put(b"k1", b"aa".to_vec());
+            c.put(b"k2", b"bb".to_vec());
+            c.get(b"k1");
+            c.put(b"k3", b"cc".to_vec());
+            ["k1", "k2"]
+                .iter()
+                .copied()
+                .filter(|k| !c.contains(k.as_bytes()))
+                .collect()
+        };
+        assert_eq!(vec!["k2"], evicted(Box::<LruPolicy>::default()));
+        assert_eq!(vec!["k1"], evicted(Box::<FifoPolicy>::default()));
+    }
+
+    #[test]
+    fn cache_size_stays_consistent() {
+        let keys: Vec<String> = (0..8).map(|i| format!("k{}", i)).collect();
+        let mut c = DiskvCache::new(20);
+
+        // simple LCG so the sequence of operations is random looking but reproducible
+        let mut seed: u32 = 42;
+        let mut next = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            seed >> 16
+        };
+
+        for _ in 0..5000 {
+            let key = &keys[next() as usize % keys.len()];
+            if next() % 3 == 0 {
+                c.delete(key.as_bytes());
+            } else {
+                c.put(key.as_bytes(), vec![b'x'; next() as usize % 13]);
+            }
+
+            let resident: u64 = c.cache.values().map(|e| e.val.len() as u64).sum();
+            assert_eq!(resident, c.cache_size);
+            assert!(c.cache_size <= c.cache_size_max);
+        }
+    }
+
+    #[test]
+    fn cache_size_beyond_u32() -> DiskvResult<()> {
+        let gib = 1024 * 1024 * 1024;
+        let dkv = Diskv::new(Options {
+            cache_size_max: 16 * gib,
+            ..Options::in_memory()
+        })?;
+        assert_eq!(16 * gib, dkv.shards[0].read().unwrap().cache_size_max);
+
+        // pretend the cache already holds 4GiB, values that big are not allocated in a test
+        let key = String::from("k1");
+        let mut c = DiskvCache::new(16 * gib);
+        c.cache_size = u32::MAX as u64;
+        c.put(key.as_bytes(), vec![b'x'; 10]);
+        assert_eq!(Some(vec![b'x'; 10]), c.get(key.as_bytes()));
+        assert_eq!(u32::MAX as u64 + 10, c.cache_size);
+        c.delete(key.as_bytes());
+        assert_eq!(u32::MAX as u64, c.cache_size);
+        Ok(())
+    }
+
+    #[test]
+    fn cache_expired_entry_is_a_miss() {
+        let key = String::from("k1");
+        let mut c = DiskvCache::new(10);
+
+        let past = time::SystemTime::now() - time::Duration::from_secs(1);
+        c.put(key.as_bytes(), String::from("aa").into_bytes());
+        c.set_expiry(key.as_bytes(), Some(past));
+        assert_eq!(None, c.get(key.as_bytes()));
+        assert!(!c.contains(key.as_bytes()));
+
+        let future = time::SystemTime::now() + time::Duration::from_secs(60);
+        c.put(key.as_bytes(), String::from("bb").into_bytes());
+        c.set_expiry(key.as_bytes(), Some(future));
+        assert_eq!(Some(String::from("bb").into_bytes()), c.get(key.as_bytes()));
+    }
+
+    #[test]
+    fn cache_stats_count_evictions() {
+        let mut c = DiskvCache::new(4);
+        c.put(b"k1", String::from("aa").into_bytes());
+        c.put(b"k2", String::from("bb").into_bytes());
+        c.put(b"k3", String::from("cccc").into_bytes());
+        c.put(b"k4", String::from("ddddd").into_bytes());
+
+        let stats = c.stats();
+        assert_eq!(2, stats.evictions);
+        assert_eq!(1, stats.ignored);
+        assert_eq!(4, stats.cache_size);
+        assert_eq!(1, stats.entries);
+    }
+
+    #[test]
+    fn cache_ignore_large_vals() {
+        let key = String::from("k1");
+        let mut c = DiskvCache::new(10);
+        assert_eq!(None, c.get(key.as_bytes()));
+
+        c.put(key.as_bytes(), String::from("abcdpqrsxy").into_bytes()); // gets cached
+        assert_eq!(
+            Some(String::from("abcdpqrsxy").into_bytes()),
+            c.get(key.as_bytes())
+        );
+
+        c.put(key.as_bytes(), String::from("abcdpqrsxyz").into_bytes()); // won't get cached
+        assert_eq!(
+            Some(String::from("abcdpqrsxy").into_bytes()),
+            c.get(key.as_bytes())
+        );
+    }
+
+    #[test]
+    fn diskv_get_put_get() -> DiskvResult<()> {
+        let test_data_path = path::PathBuf::from("test_data");
+        let dkv = Diskv::new(Options {
+            base_path: test_data_path.clone(),
+            cache_size_max: 12,
+            ..Default::default()
+        })
+        .expect("failed to init diskv");
+
+        let key1 = "k1";
+        let key2 = "k2";
+
+        dkv.put(key2, String::from("aa").into_bytes())?;
+        assert_eq!(
+            String::from("aa").into_bytes(),
+            dkv.get(key2).unwrap().unwrap()
+        );
+
+        // get
+        assert!(dkv.get(key1).unwrap().is_none());
+
+        // put get
+        dkv.put(key1, String::from("0123456789").into_bytes())?;
+        assert_eq!(
+            String::from("0123456789").into_bytes(),
+            dkv.get(key1).unwrap().unwrap()
+        );
+
+        // put get
+        dkv.put(key1, String::from("1111111111").into_bytes())?;
+        assert_eq!(
+            String::from("1111111111").into_bytes(),
+            dkv.get(key1).unwrap().unwrap()
+        );
+
+        // delete get
+        dkv.delete(key1)?;
+        assert!(dkv.get(key1).unwrap().is_none());
+
+        assert_eq!(
+            String::from("aa").into_bytes(),
+            dkv.get(key2).unwrap().unwrap()
+        );
+
+        fs::remove_dir_all(&test_data_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_in_memory_get_put_get() -> DiskvResult<()> {
+        let test_data_path = path::PathBuf::from("test_data_in_memory");
+        let _ = fs::remove_dir_all(&test_data_path);
+        let dkv = Diskv::new(Options {
+            base_path: test_data_path.clone(),
+            cache_size_max: 12,
+            ..Options::in_memory()
+        })
+        .expect("failed to init diskv");
+
+        let key1 = "k1";
+        let key2 = "k2";
+
+        dkv.put(key2, String::from("aa").into_bytes())?;
+        assert_eq!(
+            String::from("aa").into_bytes(),
+            dkv.get(key2).unwrap().unwrap()
+        );
+
+        // get
+        assert!(dkv.get(key1).unwrap().is_none());
+
+        // put get
+        dkv.put(key1, String::from("0123456789").into_bytes())?;
+        assert_eq!(
+            String::from("0123456789").into_bytes(),
+            dkv.get(key1).unwrap().unwrap()
+        );
+
+        // put get, evicts k2 from the cache, so it has to come from storage below
+        dkv.put(key1, String::from("1111111111").into_bytes())?;
+        assert_eq!(
+            String::from("1111111111").into_bytes(),
+            dkv.get(key1).unwrap().unwrap()
+        );
+
+        // delete get
+        dkv.delete(key1)?;
+        assert!(dkv.get(key1).unwrap().is_none());
+
+        assert_eq!(
+            String::from("aa").into_bytes(),
+            dkv.get(key2).unwrap().unwrap()
+        );
+
+        assert!(!path::Path::new(&test_data_path).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_in_memory_keys_ttl_clear() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options {
+            cache_size_max: 0,
+            ..Options::in_memory()
+        })?;
+        let long_key = "l".repeat(250);
+        dkv.put("k1", b"aa".to_vec())?;
+        dkv.put(&long_key, b"bb".to_vec())?;
+        dkv.put_with_ttl("k2", b"cc".to_vec(), time::Duration::from_millis(20))?;
+
+        let mut keys = dkv.keys()?;
+        keys.sort();
+        assert_eq!(
+            vec![String::from("k1"), String::from("k2"), long_key.clone()],
+            keys
+        );
+        assert_eq!(Some(b"bb".to_vec()), dkv.get(&long_key)?);
+
+        thread::sleep(time::Duration::from_millis(30));
+        assert_eq!(None, dkv.get("k2")?);
+        assert_eq!(2, dkv.len()?);
+
+        dkv.clear()?;
+        assert!(dkv.is_empty()?);
+        assert!(!path::Path::new(&dkv.options.base_path).exists());
+        Ok(())
+    }
+
+    // constructor of a Diskv for the test of a name, with a cache_size_max, like new_test_diskv
+    type NewDiskv = fn(&str, u64) -> Diskv;
+
+    // runs tests taking a NewDiskv against every storage: files under base_path, FsBackend, MemBackend and
+    // StorageFormat::Segment, each as a test of its own in a module named after the test. tests looking at files
+    // directly, by path, only run against files.
+    macro_rules! each_storage {
+        ($($test:ident),* $(,)?) => {
+            $(
+                mod $test {
+                    use super::*;
+
+                    #[test]
+                    fn files() -> DiskvResult<()> {
+                        super::$test(new_test_diskv)
+                    }
+
+                    #[test]
+                    fn fs_backend() -> DiskvResult<()> {
+                        super::$test(new_fs_backend_diskv)
+                    }
+
+                    #[test]
+                    fn mem_backend() -> DiskvResult<()> {
+                        super::$test(new_mem_backend_diskv)
+                    }
+
+                    #[test]
+                    fn segment() -> DiskvResult<()> {
+                        super::$test(new_segment_diskv)
+                    }
+                }
+            )*
+        };
+    }
+
+    each_storage!(
+        diskv_contains_key,
+        diskv_keys,
+        diskv_peek,
+        diskv_get_string,
+        diskv_keys_invalid_utf8,
+        diskv_put_without_ttl_clears_expiry,
+        diskv_stats,
+        diskv_touch,
+        diskv_warm,
+        diskv_put_many_get_many,
+        diskv_put_returning_take,
+        diskv_swap,
+        diskv_increment,
+        diskv_merge,
+        diskv_handle,
+        diskv_update_transforms,
+        diskv_export_import,
+        diskv_get_or_insert_with,
+        diskv_cached_keys,
+        diskv_retain,
+        diskv_put_if_absent,
+        diskv_put_no_cache,
+        diskv_get_populates_cache,
+        diskv_key_validation,
+        diskv_flush_cache,
+        diskv_evict,
+        diskv_namespace,
+        diskv_compare_and_swap,
+        diskv_recovers_from_poisoned_lock,
+        diskv_scan_prefix,
+        diskv_get_range,
+        diskv_count_by_prefix,
+    );
+
+    fn new_fs_backend_diskv(name: &str, cache_size_max: u64) -> Diskv {
+        let base_path = path::PathBuf::from(format!("test_data_{}_fs_backend", name));
+        let _ = fs::remove_dir_all(&base_path);
+        let backend = crate::backend::FsBackend::new(&base_path).expect("failed to init backend");
+        Diskv::new(Options {
+            base_path,
+            cache_size_max,
+            backend: Some(Box::new(backend)),
+            ..Default::default()
+        })
+        .expect("failed to init diskv")
+    }
+
+    // base_path is never created
+    fn new_mem_backend_diskv(name: &str, cache_size_max: u64) -> Diskv {
+        Diskv::new(Options {
+            base_path: path::PathBuf::from(format!("test_data_{}_mem_backend", name)),
+            cache_size_max,
+            backend: Some(Box::new(crate::backend::MemBackend::new())),
+            ..Default::default()
+        })
+        .expect("failed to init diskv")
+    }
+
+    fn new_segment_diskv(name: &str, cache_size_max: u64) -> Diskv {
+        let base_path = path::PathBuf::from(format!("test_data_{}_segment", name));
+        let _ = fs::remove_dir_all(&base_path);
+        Diskv::new(Options {
+            base_path,
+            cache_size_max,
+            storage_format: StorageFormat::Segment,
+            ..Default::default()
+        })
+        .expect("failed to init diskv")
+    }
+
+    // removes base_path of a Diskv made by a NewDiskv, if it was created at all
+    fn remove_test_diskv(dkv: &Diskv) -> io::Result<()> {
+        match fs::remove_dir_all(&dkv.options.base_path) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+
+    fn new_test_diskv(name: &str, cache_size_max: u64) -> Diskv {
+        let base_path = path::PathBuf::from(format!("test_data_{}", name));
+        let _ = fs::remove_dir_all(&base_path);
+        Diskv::new(Options {
+            base_path,
+            cache_size_max,
+            ..Default::default()
+        })
+        .expect("failed to init diskv")
+    }
+
+    #[test]
+    fn diskv_builder() -> DiskvResult<()> {
+        let dkv = DiskvBuilder::new()
+            .base_path("test_data_builder")
+            .cache_size_max(16)
+            .max_value_size(8)
+            .build()?;
+        let defaults = Options::default();
+        assert_eq!(path::Path::new("test_data_builder"), dkv.options.base_path);
+        assert_eq!(16, dkv.options.cache_size_max);
+        assert_eq!(Some(8), dkv.options.max_value_size);
+        assert_eq!(defaults.codec, dkv.options.codec);
+        assert_eq!(defaults.wal, dkv.options.wal);
+        assert_eq!(defaults.cache_shards, dkv.options.cache_shards);
+        assert_eq!(16, dkv.options.lock_stripes);
+        assert_eq!(defaults.write_buffer_size, dkv.options.write_buffer_size);
+        assert_eq!(defaults.reaper_interval, dkv.options.reaper_interval);
+        assert!(dkv.options.sync_on_drop);
+        assert!(dkv.key_path("k1").starts_with("test_data_builder"));
+        fs::remove_dir_all(&dkv.options.base_path)?;
+
+        let dkv = DiskvBuilder::new().in_memory(true).build()?;
+        dkv.put("k1", b"v1".to_vec())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        Ok(())
+    }
+
+    fn diskv_contains_key(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("contains_key", 4);
+
+        let key1 = String::from("k1");
+        let key2 = String::from("k2");
+        assert!(!dkv.contains_key(&key1)?);
+
+        // cached
+        dkv.put(&key1, String::from("aa").into_bytes())?;
+        assert!(dkv.contains_key(&key1)?);
+
+        // too large to be cached, only on disk
+        dkv.put(&key2, String::from("0123456789").into_bytes())?;
+        assert!(dkv.contains_key(&key2)?);
+        assert_eq!(
+            None,
+            dkv.shard(key2.as_bytes())
+                .read()
+                .unwrap()
+                .get(key2.as_bytes())
+        );
+
+        dkv.delete(&key1)?;
+        assert!(!dkv.contains_key(&key1)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_len_is_empty() -> DiskvResult<()> {
+        let dkv = new_test_diskv("len_is_empty", 12);
+        assert_eq!(0, dkv.len()?);
+        assert!(dkv.is_empty()?);
+
+        dkv.put("k1", String::from("aa").into_bytes())?;
+        dkv.put("k2", String::from("bb").into_bytes())?;
+        dkv.put("k2", String::from("cc").into_bytes())?;
+        assert_eq!(2, dkv.len()?);
+        assert!(!dkv.is_empty()?);
+
+        // sub-directories are not counted
+        fs::create_dir(dkv.options.base_path.join("subdir"))?;
+        assert_eq!(2, dkv.len()?);
+
+        dkv.delete("k1")?;
+        assert_eq!(1, dkv.len()?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_keys(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("keys", 12);
+        assert!(dkv.keys()?.is_empty());
+
+        dkv.put("k1", String::from("aa").into_bytes())?;
+        dkv.put("k2", String::from("bb").into_bytes())?;
+        let mut keys = dkv.keys()?;
+        keys.sort();
+        assert_eq!(vec![String::from("k1"), String::from("k2")], keys);
+
+        // keys round-trip back into get
+        for key in &keys {
+            assert!(dkv.get(key)?.is_some());
+        }
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_peek(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("peek", 1024);
+        dkv.put("k1", b"v1".to_vec())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.peek("k1"));
+
+        // on disk only
+        dkv.evict("k1");
+        assert_eq!(None, dkv.peek("k1"));
+        assert!(!dkv.is_cached("k1"));
+        assert_eq!(None, dkv.peek("absent"));
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!(Some(b"v1".to_vec()), dkv.peek(&String::from("k1")));
+        let stats = dkv.stats();
+        assert_eq!((1, 0), (stats.misses, stats.hits));
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_bytes_keys() -> DiskvResult<()> {
+        let options = || Options {
+            base_path: path::PathBuf::from("test_data_bytes_keys"),
+            wal: true,
+            ..Default::default()
+        };
+        let _ = fs::remove_dir_all(&options().base_path);
+        let dkv = Diskv::new(options())?;
+        let binary = vec![0xff, 0x00, 0x80, b'/'];
+        let long = vec![0xfe; 250]; // hashed
+        dkv.put_bytes(&binary, b"v1".to_vec())?;
+        dkv.put_bytes(&long, b"v2".to_vec())?;
+        dkv.put_bytes(b"k3", b"v3".to_vec())?;
+        assert!(dkv.key_path("k3").exists());
+        assert_eq!(Some(b"v3".to_vec()), dkv.get("k3")?);
+        assert_eq!(Some(b"v1".to_vec()), dkv.get_bytes(&binary)?);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get_bytes(&long)?);
+        assert_eq!(None, dkv.get_bytes(&[0xff])?);
+        // cached like string keys
+        let hits = dkv.stats().hits;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get_bytes(&binary)?);
+        assert_eq!((3, hits + 1), (dkv.stats().entries, dkv.stats().hits));
+        assert!(matches!(
+            dkv.put_bytes(&[], Vec::new()),
+            Err(DiskvError::InvalidKey(_))
+        ));
+        assert_eq!(vec!["k3"], dkv.keys()?);
+        drop(dkv);
+
+        let dkv = Diskv::new(options())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get_bytes(&binary)?);
+        let mut keys = dkv.keys_bytes()?;
+        keys.sort();
+        assert_eq!(vec![b"k3".to_vec(), long.clone(), binary.clone()], keys);
+        dkv.delete_bytes(&binary)?;
+        dkv.delete_bytes(&long)?;
+        dkv.delete_bytes(b"k3")?;
+        assert_eq!(None, dkv.get_bytes(&binary)?);
+        assert_eq!(0, dkv.len()?);
+
+        fs::remove_dir_all(&options().base_path)?;
+        Ok(())
+    }
+
+    fn diskv_get_string(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("get_string", 1024);
+        dkv.put_string("k1", "grüße")?;
+        assert_eq!(Some(String::from("grüße")), dkv.get_string("k1")?);
+        assert_eq!(Some("grüße".as_bytes().to_vec()), dkv.get_uncached("k1")?);
+        assert_eq!(None, dkv.get_string("absent")?);
+
+        dkv.put("k2", vec![b'a', 0xff, b'b'])?;
+        match dkv.get_string("k2") {
+            Err(DiskvError::InvalidUtf8 { key }) => assert_eq!("k2", key),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_newest_modified() -> DiskvResult<()> {
+        let dkv = new_test_diskv("newest_modified", 1024);
+        assert_eq!(None, dkv.newest_modified()?);
+
+        dkv.put("k1", b"v1".to_vec())?;
+        thread::sleep(time::Duration::from_millis(20));
+        dkv.put("k2", b"v2".to_vec())?;
+        fs::write(
+            dkv.options.base_path.join(format!("k1{}99999", TMP_INFIX)),
+            b"stray",
+        )?;
+        let newest = fs::metadata(dkv.key_path("k2"))?.modified()?;
+        assert!(newest > fs::metadata(dkv.key_path("k1"))?.modified()?);
+        assert_eq!(Some(newest), dkv.newest_modified()?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_values() -> DiskvResult<()> {
+        let dkv = new_test_diskv("values", 4);
+        assert!(dkv.values()?.is_empty());
+
+        dkv.put("k1", b"aa".to_vec())?;
+        dkv.put("k2", b"0123456789".to_vec())?; // too large for the cache
+        dkv.put_with_ttl("k3", b"cc".to_vec(), time::Duration::from_secs(60))?;
+        fs::create_dir(dkv.options.base_path.join("subdir"))?;
+        let mut values = dkv.values()?;
+        values.sort();
+        assert_eq!(
+            vec![b"0123456789".to_vec(), b"aa".to_vec(), b"cc".to_vec()],
+            values
+        );
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_keys_invalid_utf8(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("keys_invalid_utf8", 12);
+        let binary = vec![0x6b, 0xff, 0x31];
+        dkv.put_bytes(&binary, b"aa".to_vec())?;
+        dkv.put("k1", b"bb".to_vec())?;
+        // left out of string listings, which keep working
+        assert_eq!(vec!["k1"], dkv.keys()?);
+        assert_eq!(1, dkv.scan_prefix("")?.len());
+        assert_eq!(1, dkv.iter()?.count());
+        assert!(dkv.verify()?.is_empty());
+        let mut archive = Vec::new();
+        dkv.export(&mut archive)?;
+
+        dkv.clear()?;
+        assert!(dkv.keys_bytes()?.is_empty());
+        dkv.import(&archive[..])?;
+        assert_eq!(Some(b"aa".to_vec()), dkv.get_bytes(&binary)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_put_with_ttl() -> DiskvResult<()> {
+        let dkv = new_test_diskv("put_with_ttl", 12);
+        let key = String::from("k1");
+
+        dkv.put_with_ttl(
+            &key,
+            String::from("aa").into_bytes(),
+            time::Duration::from_millis(100),
+        )?;
+        assert!(dkv.meta_path(&key).exists());
+        assert_eq!(Some(String::from("aa").into_bytes()), dkv.get(&key)?);
+        assert!(dkv.contains_key(&key)?);
+        assert_eq!(1, dkv.len()?);
+        assert_eq!(vec![key.clone()], dkv.keys()?);
+
+        thread::sleep(time::Duration::from_millis(150));
+        assert!(!dkv.contains_key(&key)?);
+        assert_eq!(None, dkv.get(&key)?);
+
+        // expired key got removed lazily by get
+        assert!(!dkv.key_path(&key).exists());
+        assert!(!dkv.meta_path(&key).exists());
+        assert_eq!(0, dkv.len()?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_put_without_ttl_clears_expiry(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("put_without_ttl", 12);
+        let key = String::from("k1");
+
+        dkv.put_with_ttl(
+            &key,
+            String::from("aa").into_bytes(),
+            time::Duration::from_millis(50),
+        )?;
+        dkv.put(&key, String::from("bb").into_bytes())?;
+        assert!(!dkv.has_meta(&key));
+
+        thread::sleep(time::Duration::from_millis(100));
+        assert_eq!(Some(String::from("bb").into_bytes()), dkv.get(&key)?);
+
+        // doesn't clash with the expiry file of k1
+        let meta_key = String::from("k1.meta");
+        dkv.put(&meta_key, String::from("cc").into_bytes())?;
+        dkv.put_with_ttl(
+            &key,
+            String::from("dd").into_bytes(),
+            time::Duration::from_secs(60),
+        )?;
+        assert_eq!(
+            Some(String::from("cc").into_bytes()),
+            dkv.get_uncached(&meta_key)?
+        );
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_put_ignores_stray_tmp_files() -> DiskvResult<()> {
+        let dkv = new_test_diskv("stray_tmp", 12);
+        let key = String::from("k1");
+        let val: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+        dkv.put(&key, val.clone())?;
+
+        // what a crash in the middle of a put leaves behind
+        let stray = dkv
+            .options
+            .base_path
+            .join(format!("{}{}99999", key, TMP_INFIX));
+        fs::write(&stray, &val[..100])?;
+
+        // too large for the cache, so this is read from disk
+        assert_eq!(Some(val.clone()), dkv.get(&key)?);
+        assert_eq!(vec![key.clone()], dkv.keys()?);
+        assert_eq!(1, dkv.len()?);
+
+        dkv.put(&key, val.clone())?;
+        assert_eq!(Some(val), dkv.get(&key)?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_compact() -> DiskvResult<()> {
+        let dkv = new_test_diskv("compact", 1024);
+        dkv.put("live", b"v1".to_vec())?;
+        dkv.put_with_ttl("expiring", b"v2".to_vec(), time::Duration::from_secs(60))?;
+
+        let base_path = &dkv.options.base_path;
+        let planted = [
+            format!("live{}99999.7", TMP_INFIX),
+            format!("gone{}99999", TMP_INFIX),
+            format!("gone{}{}99999", META_SUFFIX, TMP_INFIX),
+            format!("gone{}", META_SUFFIX),
+        ];
+        for name in planted.iter() {
+            fs::write(base_path.join(name), b"stray")?;
+        }
+        fs::write(base_path.join("not%2"), b"foreign")?;
+
+        assert_eq!(planted.len(), dkv.compact()?);
+        let mut left: Vec<String> = fs::read_dir(base_path)?
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(vec!["expiring", "expiring.meta", "live", "not%2"], left);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get_uncached("expiring")?);
+        assert_eq!(0, dkv.compact()?);
+
+        // every write gets a temporary file of its own
+        let path = dkv.key_path("live");
+        let (tmp1, tmp2) = (tmp_path(&path), tmp_path(&path));
+        assert_ne!(tmp1, tmp2);
+        for tmp in [tmp1, tmp2].iter() {
+            let name = tmp.file_name().unwrap().to_str().unwrap();
+            assert!(is_store_name(name) && !is_value_name(name), "{}", name);
+        }
+
+        fs::remove_dir_all(base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_metrics_text() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options {
+            cache_size_max: 4,
+            ..Options::in_memory()
+        })?;
+        dkv.put("k1", b"aa".to_vec())?;
+        dkv.get("k1")?;
+        dkv.get("absent")?;
+
+        let text = dkv.metrics_text();
+        let mut samples = HashMap::new();
+        let mut described = HashSet::new();
+        for line in text.lines() {
+            let mut words = line.split(' ');
+            match (words.next(), words.next(), words.next()) {
+                (Some("#"), Some("HELP"), Some(name)) => assert!(described.insert(name)),
+                (Some("#"), Some("TYPE"), Some(name)) => {
+                    assert!(described.contains(name));
+                    let kind = words.next();
+                    assert!(kind == Some("counter") || kind == Some("gauge"));
+                    assert_eq!(kind == Some("counter"), name.ends_with("_total"));
+                }
+                (Some(name), Some(value), None) => {
+                    assert!(described.contains(name), "{} has no HELP", name);
+                    samples.insert(name, value.parse::<f64>().unwrap());
+                }
+                _ => panic!("malformed line: {}", line),
+            }
+        }
+        assert!(text.ends_with('\n'));
+        assert_eq!(Some(&1.0), samples.get("diskv_cache_hits_total"));
+        assert_eq!(Some(&1.0), samples.get("diskv_cache_misses_total"));
+        assert_eq!(Some(&0.0), samples.get("diskv_cache_evictions_total"));
+        assert_eq!(Some(&2.0), samples.get("diskv_cache_bytes"));
+        assert_eq!(Some(&1.0), samples.get("diskv_cache_entries"));
+        assert_eq!(7, samples.len());
+        Ok(())
+    }
+
+    fn diskv_stats(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("stats", 4);
+        let key1 = String::from("k1");
+        let key2 = String::from("k2");
+
+        dkv.put(&key1, String::from("aa").into_bytes())?;
+        dkv.get(&key1)?; // hit
+        dkv.get(&key1)?; // hit
+        dkv.get(&key2)?; // miss, not on disk either
+
+        dkv.put(&key2, String::from("0123456789").into_bytes())?; // ignored
+        dkv.get(&key2)?; // miss, read from disk and ignored again
+
+        assert_eq!(
+            CacheStats {
+                hits: 2,
+                misses: 2,
+                evictions: 0,
+                ignored: 2,
+                cache_size: 2,
+                entries: 1,
+                cache_size_max: 4,
+            },
+            dkv.stats()
+        );
+
+        dkv.reset_stats();
+        assert_eq!(
+            CacheStats {
+                cache_size: 2,
+                entries: 1,
+                cache_size_max: 4,
+                ..Default::default()
+            },
+            dkv.stats()
+        );
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_adaptive_cache() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options {
+            cache_size_max: 128,
+            adaptive_cache: Some(AdaptiveCache {
+                floor: 64,
+                ceiling: 1024,
+                window: 10,
+                ..Default::default()
+            }),
+            ..Options::in_memory()
+        })?;
+        for i in 0..8 {
+            dkv.put(&format!("k{}", i), vec![b'x'; 16])?;
+        }
+        assert_eq!(128, dkv.stats().cache_size_max);
+
+        // every lookup hits, limit doubles after each window of 10, up to the ceiling
+        for _ in 0..20 {
+            dkv.get("k1")?;
+        }
+        assert_eq!(512, dkv.stats().cache_size_max);
+        for _ in 0..100 {
+            dkv.get("k1")?;
+        }
+        assert_eq!(1024, dkv.stats().cache_size_max);
+
+        // every lookup misses, limit halves down to the floor, evicting what no longer fits
+        for i in 0..80 {
+            dkv.get(&format!("absent{}", i))?;
+        }
+        let stats = dkv.stats();
+        assert_eq!(64, stats.cache_size_max);
+        assert!(stats.cache_size <= 64);
+        assert_eq!(Some(vec![b'x'; 16]), dkv.get("k7")?);
+        Ok(())
+    }
+
+    fn diskv_touch(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("touch", 12);
+        for key in ["k1", "k2", "k3"].iter() {
+            dkv.put(key, vec![b'x'; 4])?;
+        }
+        // k1 would be evicted first, unless touched
+        assert!(dkv.touch("k1", None)?);
+        dkv.put("k4", vec![b'x'; 4])?;
+        assert_eq!(vec!["k1", "k3", "k4"], dkv.cached_keys());
+
+        assert!(dkv.touch("k2", Some(time::Duration::from_millis(20)))?);
+        assert!(!dkv.is_cached("k2"));
+        assert!(dkv.has_meta("k2"));
+        assert!(dkv.touch("k3", Some(time::Duration::from_millis(20)))?);
+        thread::sleep(time::Duration::from_millis(40));
+        assert_eq!(None, dkv.get("k2")?);
+        assert_eq!(None, dkv.get("k3")?);
+        assert!(!dkv.touch("k3", None)?);
+        assert!(!dkv.touch("absent", None)?);
+        assert_eq!(Some(vec![b'x'; 4]), dkv.get("k1")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_simulate_eviction() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options {
+            cache_size_max: 16,
+            ..Options::in_memory()
+        })?;
+        for i in 0..4 {
+            dkv.put(&format!("k{}", i), vec![b'x'; 4])?;
+        }
+        dkv.get("k0")?;
+        assert!(dkv.simulate_eviction(0).is_empty());
+
+        let simulated = dkv.simulate_eviction(6);
+        assert_eq!(vec!["k1", "k2"], simulated);
+        assert_eq!(4, dkv.stats().entries);
+        let before = dkv.cached_keys();
+        dkv.put("k4", vec![b'y'; 6])?;
+        let evicted: Vec<String> = before.into_iter().filter(|k| !dkv.is_cached(k)).collect();
+        assert_eq!(simulated, evicted);
+        assert!(dkv.simulate_eviction(17).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_set_cache_size_max() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options {
+            cache_size_max: 64,
+            cache_shards: 2,
+            ..Options::in_memory()
+        })?;
+        for i in 0..8 {
+            dkv.put(&format!("k{}", i), vec![b'x'; 4])?;
+        }
+        assert_eq!(32, dkv.stats().cache_size);
+
+        dkv.set_cache_size_max(16)?;
+        let stats = dkv.stats();
+        assert_eq!(16, stats.cache_size_max);
+        assert!(stats.cache_size <= 16);
+        assert_eq!(8, stats.entries as u64 + stats.evictions);
+        for i in 0..8 {
+            assert_eq!(Some(vec![b'x'; 4]), dkv.get_uncached(&format!("k{}", i))?);
+        }
+
+        dkv.set_cache_size_max(1024)?;
+        for i in 8..40 {
+            dkv.put(&format!("k{}", i), vec![b'x'; 4])?;
+        }
+        assert_eq!(1024, dkv.stats().cache_size_max);
+        assert!(dkv.stats().cache_size > 64);
+        Ok(())
+    }
+
+    fn diskv_warm(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("warm", 6);
+        for key in ["k1", "k2", "k3"].iter().copied() {
+            dkv.put(key, b"aa".to_vec())?;
+        }
+        dkv.put("large", b"0123456789".to_vec())?;
+        dkv.flush_cache();
+
+        dkv.warm(&["k1", "k3", "absent", "large"])?;
+        assert_eq!(vec!["k1", "k3"], dkv.cached_keys());
+        assert_eq!(0, dkv.stats().hits + dkv.stats().misses);
+        assert!(matches!(dkv.warm(&[""]), Err(DiskvError::InvalidKey(_))));
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_get_required() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options::in_memory())?;
+        dkv.put("k1", b"v1".to_vec())?;
+        assert_eq!(b"v1".to_vec(), dkv.get_required("k1")?);
+        match dkv.get_required("k2") {
+            Err(DiskvError::NotFound { key }) => assert_eq!("k2", key),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_get_uncached() -> DiskvResult<()> {
+        let dkv = new_test_diskv("get_uncached", 4);
+        let key1 = String::from("k1");
+        let key2 = String::from("k2");
+
+        dkv.put(&key1, String::from("aa").into_bytes())?;
+        // only on disk
+        fs::write(
+            dkv.key_path(&key2),
+            encode_file(key2.as_bytes(), b"bb", &dkv.file_format())?,
+        )?;
+
+        assert_eq!(
+            Some(String::from("aa").into_bytes()),
+            dkv.get_uncached(&key1)?
+        );
+        assert_eq!(
+            Some(String::from("bb").into_bytes()),
+            dkv.get_uncached(&key2)?
+        );
+        assert_eq!(None, dkv.get_uncached("k3")?);
+
+        // k2 was not cached, k1 is still there
+        let cache = dkv.shard(key1.as_bytes()).read().unwrap();
+        assert!(cache.contains(key1.as_bytes()));
+        assert!(!cache.contains(key2.as_bytes()));
+        assert_eq!(1, cache.stats().hits);
+        drop(cache);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_put_many_get_many(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("put_many_get_many", 12);
+
+        dkv.put_many(vec![
+            (String::from("k1"), String::from("aa").into_bytes()),
+            (String::from("k2"), String::from("bb").into_bytes()),
+            (String::from("k3"), String::from("cc").into_bytes()),
+        ])?;
+        assert_eq!(3, dkv.len()?);
+
+        let keys = vec![
+            String::from("k3"),
+            String::from("k4"),
+            String::from("k1"),
+            String::from("k5"),
+        ];
+        assert_eq!(
+            vec![
+                Some(String::from("cc").into_bytes()),
+                None,
+                Some(String::from("aa").into_bytes()),
+                None,
+            ],
+            dkv.get_many(&keys)?
+        );
+
+        // invalid key fails the batch before anything gets written
+        let res = dkv.put_many(vec![
+            (String::from("k6"), String::from("dd").into_bytes()),
+            (String::new(), String::from("ee").into_bytes()),
+        ]);
+        assert!(res.is_err());
+        assert_eq!(None, dkv.get("k6")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_put_returning_take(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("put_returning", 0);
+        let key = String::from("k1");
+
+        assert_eq!(None, dkv.put_returning(&key, b"aa".to_vec())?);
+        assert_eq!(
+            Some(b"aa".to_vec()),
+            dkv.put_returning(&key, b"bb".to_vec())?
+        );
+        assert_eq!(Some(b"bb".to_vec()), dkv.get(&key)?);
+
+        assert_eq!(Some(b"bb".to_vec()), dkv.take(&key)?);
+        assert_eq!(None, dkv.get(&key)?);
+        assert_eq!(None, dkv.take(&key)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_verify_on_hit() -> DiskvResult<()> {
+        for verify_on_hit in [false, true].iter().copied() {
+            let base_path =
+                path::PathBuf::from(format!("test_data_verify_on_hit_{}", verify_on_hit));
+            let _ = fs::remove_dir_all(&base_path);
+            let dkv = DiskvBuilder::new()
+                .base_path(&base_path)
+                .verify_on_hit(verify_on_hit)
+                .build()?;
+            dkv.put("k1", b"v1".to_vec())?;
+            dkv.put("k2", b"v2".to_vec())?;
+            assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+
+            // deleted behind the back of the store
+            fs::remove_file(dkv.key_path("k1"))?;
+            if verify_on_hit {
+                assert_eq!(None, dkv.get("k1")?);
+                assert_eq!(vec!["k2"], dkv.cached_keys());
+            } else {
+                assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+            }
+            assert_eq!(Some(b"v2".to_vec()), dkv.get("k2")?);
+
+            fs::remove_dir_all(&base_path)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_entries_by_mtime() -> DiskvResult<()> {
+        let dkv = new_test_diskv("entries_by_mtime", 1024);
+        for key in ["k2", "k3", "k1"].iter() {
+            dkv.put(key, b"v".to_vec())?;
+            thread::sleep(time::Duration::from_millis(20));
+        }
+        dkv.put("k5".repeat(100).as_str(), b"v".to_vec())?; // hashed
+        let order = |newest_first| -> DiskvResult<Vec<String>> {
+            Ok(dkv
+                .entries_by_mtime(newest_first)?
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect())
+        };
+        assert_eq!(vec!["k2", "k3", "k1", &"k5".repeat(100)], order(false)?);
+        assert_eq!(vec![&"k5".repeat(100), "k1", "k3", "k2"], order(true)?);
+
+        // ties are sorted by key
+        let t = time::SystemTime::now() - time::Duration::from_secs(60);
+        for key in ["k3", "k1"].iter() {
+            fs::File::options()
+                .write(true)
+                .open(dkv.key_path(key))?
+                .set_modified(t)?;
+        }
+        let entries = dkv.entries_by_mtime(false)?;
+        assert_eq!(("k1".to_string(), t), entries[0]);
+        assert_eq!(("k3".to_string(), t), entries[1]);
+        assert_eq!("k2", entries[2].0);
+        assert_eq!(vec!["k2", "k1", "k3"], order(true)?[1..]);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_detect_external_writes() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_detect_external_writes");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = DiskvBuilder::new()
+            .base_path(&base_path)
+            .detect_external_writes(true)
+            .build()?;
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put("k1", b"v2".to_vec())?;
+        dkv.put("k2", b"v1".to_vec())?;
+
+        // another process writing k1, the modification time being set by hand as both writes may fall within the
+        // granularity of file times
+        let other = DiskvBuilder::new().base_path(&base_path).build()?;
+        other.put("k1", b"other".to_vec())?;
+        let past = time::SystemTime::now() - time::Duration::from_secs(60);
+        fs::File::options()
+            .write(true)
+            .open(dkv.key_path("k1"))?
+            .set_modified(past)?;
+        match dkv.put("k1", b"v3".to_vec()) {
+            Err(DiskvError::ConcurrentModification { key }) => assert_eq!("k1", key),
+            res => panic!("expected ConcurrentModification, got {:?}", res),
+        }
+        assert_eq!(Some(b"other".to_vec()), dkv.get("k1")?);
+        dkv.put("k1", b"v3".to_vec())?;
+        assert_eq!(Some(b"v3".to_vec()), dkv.get("k1")?);
+
+        // read from disk into the cache, then deleted
+        drop(dkv);
+        let dkv = DiskvBuilder::new()
+            .base_path(&base_path)
+            .detect_external_writes(true)
+            .build()?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k2")?);
+        other.delete("k2")?;
+        assert!(matches!(
+            dkv.put("k2", b"v2".to_vec()),
+            Err(DiskvError::ConcurrentModification { .. })
+        ));
+        dkv.put("k2", b"v2".to_vec())?;
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_cache_disabled() -> DiskvResult<()> {
+        let dkv = new_test_diskv("cache_disabled", 0);
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put("empty", Vec::new())?;
+        dkv.put_with_ttl("k2", b"v2".to_vec(), time::Duration::from_millis(20))?;
+        for _ in 0..2 {
+            assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+            assert_eq!(Some(Vec::new()), dkv.get("empty")?);
+            assert_eq!(Some(b"v2".to_vec()), dkv.get("k2")?);
+        }
+        assert!(dkv.cached_keys().is_empty());
+        thread::sleep(time::Duration::from_millis(40));
+        assert_eq!(None, dkv.get("k2")?);
+        assert!(!dkv.key_path("k2").exists());
+
+        dkv.put("k1", b"v1 again".to_vec())?;
+        assert_eq!(Some(b"v1 again".to_vec()), dkv.get("k1")?);
+        assert!(dkv.cached_keys().is_empty());
+        assert_eq!(CacheStats::default(), dkv.stats());
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_bulk_load() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_bulk_load");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            dir_shard_depth: 1,
+            write_policy: WritePolicy::WriteAround,
+            ..Default::default()
+        };
+        let dkv = Diskv::new(options())?;
+        dkv.put_with_ttl("k0", b"old".to_vec(), time::Duration::from_secs(60))?;
+        let items = (0..500).map(|i| (format!("k{}", i), format!("v{}", i).into_bytes()));
+        dkv.bulk_load(items)?;
+        assert!(dkv.cached_keys().is_empty());
+        assert!(!dkv.meta_path("k0").exists());
+        drop(dkv);
+
+        let dkv = Diskv::new(options())?;
+        assert_eq!(500, dkv.len()?);
+        for i in 0..500 {
+            let key = format!("k{}", i);
+            assert_eq!(Some(format!("v{}", i).into_bytes()), dkv.get(&key)?);
+        }
+        let items = vec![
+            (String::from("k500"), Vec::new()),
+            (String::new(), Vec::new()),
+        ];
+        assert!(matches!(
+            dkv.bulk_load(items.into_iter()),
+            Err(DiskvError::InvalidKey(_))
+        ));
+        assert_eq!(Some(Vec::new()), dkv.get("k500")?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    fn diskv_swap(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = Arc::new(new_diskv("swap", 1024));
+        let key = String::from("slot");
+        assert_eq!(None, dkv.swap(&key, b"init".to_vec())?);
+
+        let threads: Vec<_> = (0..4)
+            .map(|id| {
+                let dkv = Arc::clone(&dkv);
+                let key = key.clone();
+                thread::spawn(move || -> DiskvResult<Vec<Vec<u8>>> {
+                    let mut seen = Vec::new();
+                    for i in 0..25 {
+                        let val = format!("{}-{}", id, i).into_bytes();
+                        seen.push(dkv.swap(&key, val)?.unwrap());
+                    }
+                    Ok(seen)
+                })
+            })
+            .collect();
+        let mut seen = Vec::new();
+        for t in threads {
+            seen.extend(t.join().unwrap()?);
+        }
+        // every value written but the last was swapped out exactly once
+        seen.push(dkv.get_uncached(&key)?.unwrap());
+        seen.sort();
+        let mut written: Vec<Vec<u8>> = (0..4)
+            .flat_map(|id| (0..25).map(move |i| format!("{}-{}", id, i).into_bytes()))
+            .collect();
+        written.push(b"init".to_vec());
+        written.sort();
+        assert_eq!(written, seen);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_max_disk_bytes() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_max_disk_bytes");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = DiskvBuilder::new()
+            .base_path(&base_path)
+            .max_disk_bytes(550)
+            .build()?;
+        for i in 0..10 {
+            dkv.put(&format!("k{}", i), vec![i as u8; 100])?;
+            // distinct modification times
+            thread::sleep(time::Duration::from_millis(5));
+        }
+
+        let mut used = 0;
+        for entry in fs::read_dir(&base_path)? {
+            used += entry?.metadata()?.len();
+        }
+        assert!(used <= 550, "{} bytes on disk", used);
+        assert_eq!(None, dkv.get("k0")?);
+        assert!(!dkv.key_path("k0").exists());
+        for i in 6..10 {
+            assert_eq!(Some(vec![i as u8; 100]), dkv.get(&format!("k{}", i))?);
+        }
+
+        match dkv.put("k10", vec![0; 551]) {
+            Err(DiskvError::ValueTooLarge {
+                size: 551,
+                limit: 550,
+            }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(Some(vec![9; 100]), dkv.get_uncached("k9")?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_max_value_size() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_max_value_size");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            max_value_size: Some(4),
+            ..Default::default()
+        })?;
+        let key = String::from("k1");
+
+        dkv.put(&key, b"aaaa".to_vec())?;
+        match dkv.put(&key, b"bbbbb".to_vec()) {
+            Err(DiskvError::ValueTooLarge { size: 5, limit: 4 }) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let key2 = String::from("k2");
+        assert!(dkv
+            .put_many(vec![
+                (key2.clone(), b"cc".to_vec()),
+                (key.clone(), b"ddddd".to_vec())
+            ])
+            .is_err());
+        assert_eq!(Some(b"aaaa".to_vec()), dkv.get(&key)?);
+        assert_eq!(1, dkv.len()?);
+        assert!(!dkv.key_path(&key2).exists());
+        fs::remove_dir_all(&base_path)?;
+
+        // no limit
+        let dkv = new_test_diskv("max_value_size", 0);
+        dkv.put(&key, vec![0; 1 << 16])?;
+        assert_eq!(Some(vec![0; 1 << 16]), dkv.get(&key)?);
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_increment(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = Arc::new(new_diskv("increment", 1024));
+        let key = String::from("counter");
+
+        let threads: Vec<_> = (1..=8)
+            .map(|delta| {
+                let dkv = Arc::clone(&dkv);
+                let key = key.clone();
+                thread::spawn(move || -> DiskvResult<()> {
+                    for _ in 0..25 {
+                        dkv.increment(&key, delta)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap()?;
+        }
+        let total = dkv.get(&key)?.unwrap();
+        assert_eq!(25 * 36, i64::from_le_bytes(total[..].try_into().unwrap()));
+        assert_eq!(25 * 36 - 1000, dkv.increment(&key, -1000)?);
+
+        let text = String::from("text");
+        dkv.put(&text, b"abc".to_vec())?;
+        match dkv.increment(&text, 1) {
+            Err(DiskvError::NotANumber(k)) => assert_eq!(text, k),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(Some(b"abc".to_vec()), dkv.get(&text)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_merge(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = Arc::new(new_diskv("merge", 1024));
+        let key = String::from("log");
+        let concat = |current: Option<Vec<u8>>, mut operand: Vec<u8>| {
+            let mut merged = current.unwrap_or_default();
+            merged.append(&mut operand);
+            merged
+        };
+
+        let threads: Vec<_> = (0..8u8)
+            .map(|id| {
+                let dkv = Arc::clone(&dkv);
+                let key = key.clone();
+                thread::spawn(move || -> DiskvResult<()> {
+                    for _ in 0..25 {
+                        dkv.merge(&key, vec![id], concat)?;
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap()?;
+        }
+        let mut merged = dkv.get_uncached(&key)?.unwrap();
+        assert_eq!(200, merged.len());
+        merged.sort_unstable();
+        for (id, chunk) in merged.chunks(25).enumerate() {
+            assert!(chunk.iter().all(|b| *b as usize == id));
+        }
+        assert_eq!(b"ab".to_vec(), dkv.merge("k2", b"ab".to_vec(), concat)?);
+        assert_eq!(Some(b"ab".to_vec()), dkv.get("k2")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_handle(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let handle = new_diskv("handle", 1024).handle();
+        let threads: Vec<_> = (0..4)
+            .map(|id| {
+                let handle = handle.clone();
+                thread::spawn(move || -> DiskvResult<()> {
+                    for i in 0..20 {
+                        let key = format!("t{}-k{}", id, i);
+                        handle.put(&key, key.clone().into_bytes())?;
+                        assert_eq!(Some(key.clone().into_bytes()), handle.get(&key)?);
+                        if i % 2 == 0 {
+                            handle.delete(&key)?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap()?;
+        }
+        assert_eq!(40, handle.len()?);
+        assert_eq!(Some(b"t3-k19".to_vec()), handle.get_uncached("t3-k19")?);
+
+        remove_test_diskv(&handle)?;
+        Ok(())
+    }
+
+    fn diskv_update_transforms(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("update_transforms", 1024);
+        dkv.put("k1", b"abc".to_vec())?;
+        dkv.update("k1", |v| v.map(|v| v.to_ascii_uppercase()))?;
+        assert_eq!(Some(b"ABC".to_vec()), dkv.get_uncached("k1")?);
+        assert_eq!(Some(b"ABC".to_vec()), dkv.get("k1")?);
+
+        dkv.update("k2", |v| Some(v.unwrap_or_else(|| b"new".to_vec())))?;
+        assert_eq!(Some(b"new".to_vec()), dkv.get("k2")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_update_deletes() -> DiskvResult<()> {
+        let dkv = new_test_diskv("update_deletes", 1024);
+        dkv.put("keep", b"1".to_vec())?;
+        dkv.put("drop", b"0".to_vec())?;
+        for key in ["keep", "drop", "absent"].iter() {
+            dkv.update(key, |v| v.filter(|v| v != b"0"))?;
+        }
+        assert_eq!(Some(b"1".to_vec()), dkv.get("keep")?);
+        assert_eq!(None, dkv.get("drop")?);
+        assert!(!dkv.key_path("drop").exists());
+        assert_eq!(None, dkv.get("absent")?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    // a backend failing with StorageFull stands in for a full disk
+    #[test]
+    fn diskv_out_of_space() -> DiskvResult<()> {
+        let failures = Arc::new(AtomicU64::new(0));
+        let dkv = DiskvBuilder::new()
+            .backend(FlakyBackend {
+                inner: crate::backend::MemBackend::new(),
+                failures: Arc::clone(&failures),
+                kind: io::ErrorKind::StorageFull,
+            })
+            .cache_size_max(1024)
+            .build()?;
+        let key = String::from("k1");
+        dkv.put(&key, b"v1".to_vec())?;
+
+        failures.store(1, Ordering::SeqCst);
+        match dkv.put(&key, b"v2".to_vec()) {
+            Err(DiskvError::OutOfSpace(e)) => assert_eq!(io::ErrorKind::StorageFull, e.kind()),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        // neither cache nor disk got the value
+        assert_eq!(Some(b"v1".to_vec()), dkv.get(&key)?);
+        assert_eq!(Some(b"v1".to_vec()), dkv.read_file(key.as_bytes())?);
+        assert_eq!(1, dkv.stats().entries);
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_io_error_context() -> DiskvResult<()> {
+        let dkv = new_test_diskv("io_error_context", 1024);
+        // a non-empty directory in place of the value file can be neither read, replaced nor removed
+        let path = dkv.key_path("k1");
+        fs::create_dir_all(path.join("nested"))?;
+
+        let expected = format!("key 'k1' at {}: ", path.display());
+        for (op, res) in [
+            ("read", dkv.get("k1").map(|_| ())),
+            ("write", dkv.put("k1", b"v1".to_vec())),
+            ("delete", dkv.delete("k1")),
+        ]
+        .iter()
+        {
+            match res {
+                Err(e @ DiskvError::KeyIOError { .. }) => {
+                    let message = e.to_string();
+                    assert!(message.starts_with(&format!("failed to {} {}", op, expected)));
+                    assert!(error::Error::source(e).is_some());
+                }
+                res => panic!("unexpected result of {}: {:?}", op, res),
+            }
+        }
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_rename_key() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_rename_key");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            cache_shards: 4,
+            wal: true,
+            sync_on_drop: false,
+            ..Default::default()
+        };
+        let dkv = Diskv::new(options())?;
+        let long_key = "l".repeat(250);
+
+        dkv.put("k1", b"v1".to_vec())?;
+        assert!(dkv.rename_key("k1", "k2")?);
+        assert!(!dkv.is_cached("k1") && dkv.is_cached("k2"));
+        assert_eq!(None, dkv.get("k1")?);
+        assert!(!dkv.key_path("k1").exists());
+        assert_eq!(Some(b"v1".to_vec()), dkv.read_file(b"k2")?);
+
+        // to is replaced, expiry moves along
+        dkv.put_with_ttl("k3", b"v3".to_vec(), time::Duration::from_secs(60))?;
+        assert!(dkv.rename_key("k3", "k2")?);
+        assert_eq!(Some(b"v3".to_vec()), dkv.get("k2")?);
+        assert!(dkv.meta_path("k2").exists() && !dkv.meta_path("k3").exists());
+
+        // hashed names get rewritten
+        assert!(dkv.rename_key("k2", &long_key)?);
+        assert!(dkv.rename_key(&long_key, "k4")?);
+        assert_eq!(Some(b"v3".to_vec()), dkv.read_file(b"k4")?);
+        assert_eq!(None, dkv.get(&long_key)?);
+
+        assert!(!dkv.rename_key("k1", "k5")?);
+        assert_eq!(None, dkv.get("k5")?);
+
+        // logged puts of k1 and k3 aren't replayed over the renames
+        drop(dkv);
+        let dkv = Diskv::new(options())?;
+        assert_eq!(vec![String::from("k4")], dkv.keys()?);
+        assert_eq!(Some(b"v3".to_vec()), dkv.get("k4")?);
+
+        fs::remove_dir_all(&base_path)?;
+
+        let dkv = Diskv::new(Options::in_memory())?;
+        dkv.put("k1", b"v1".to_vec())?;
+        assert!(dkv.rename_key("k1", "k2")?);
+        assert_eq!(vec![String::from("k2")], dkv.keys()?);
+
+        // a rename is a write, so with WriteAround it leaves to uncached
+        let dkv = Diskv::new(Options {
+            write_policy: WritePolicy::WriteAround,
+            ..Options::in_memory()
+        })?;
+        dkv.put("k1", b"v1".to_vec())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert!(dkv.is_cached("k1"));
+        assert!(dkv.rename_key("k1", "k2")?);
+        assert!(!dkv.is_cached("k1") && !dkv.is_cached("k2"));
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k2")?);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn diskv_file_mode() -> DiskvResult<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base_path = path::PathBuf::from("test_data_file_mode");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            wal: true,
+            dir_shard_depth: 1,
+            file_mode: Some(0o600),
+            ..Default::default()
+        })?;
+        dkv.put_with_ttl("k1", b"v1".to_vec(), time::Duration::from_secs(60))?;
+
+        let mode = |path: &path::Path| -> io::Result<u32> {
+            Ok(fs::metadata(path)?.permissions().mode() & 0o777)
+        };
+        assert_eq!(0o600, mode(&dkv.key_path("k1"))?);
+        assert_eq!(0o600, mode(&dkv.meta_path("k1"))?);
+        assert_eq!(0o600, mode(&base_path.join(WAL_NAME))?);
+        assert_eq!(0o700, mode(dkv.key_path("k1").parent().unwrap())?);
+        assert_eq!(0o700, mode(&base_path)?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    // a FIFO without writer stands in for a hung disk, opening it for reading blocks until a writer shows up
+    #[cfg(unix)]
+    #[test]
+    fn diskv_op_timeout() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_op_timeout");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            op_timeout: Some(time::Duration::from_millis(50)),
+            ..Default::default()
+        })?;
+        dkv.put("k1", b"v1".to_vec())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get_uncached("k1")?);
+
+        dkv.flush_cache();
+        fs::remove_file(dkv.key_path("k1"))?;
+        let mkfifo = std::process::Command::new("mkfifo")
+            .arg(dkv.key_path("k1"))
+            .status()?;
+        assert!(mkfifo.success());
+        let started = time::Instant::now();
+        assert!(matches!(dkv.get("k1"), Err(DiskvError::Timeout)));
+        assert!(started.elapsed() < time::Duration::from_secs(5));
+
+        // lets the read left in the background finish
+        drop(
+            fs::OpenOptions::new()
+                .write(true)
+                .open(dkv.key_path("k1"))?,
+        );
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_sync() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_sync");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            sync_on_write: true,
+            ..Default::default()
+        })?;
+        let key = String::from("k1");
+
+        dkv.put(&key, b"aa".to_vec())?;
+        dkv.sync()?;
+        dkv.delete(&key)?;
+        assert!(!dkv.key_path(&key).exists());
+        dkv.put(&key, b"aa".to_vec())?;
+        dkv.clear()?;
+        dkv.sync()?;
+
+        Diskv::new(Options::in_memory())?.sync()?;
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    // holds up the write of file gated until released, telling held once it is held up
+    struct GatedBackend {
+        inner: crate::backend::MemBackend,
+        gated: String,
+        held: sync::Mutex<mpsc::Sender<()>>,
+        release: sync::Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl Backend for GatedBackend {
+        fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+            self.inner.read(name)
+        }
+
+        fn write(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+            if name == self.gated {
+                let _ = self.held.lock().unwrap().send(());
+                let release = self.release.lock().unwrap();
+                release
+                    .recv_timeout(time::Duration::from_secs(10))
+                    .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "never released"))?;
+            }
+            self.inner.write(name, buf)
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            self.inner.delete(name)
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            self.inner.list()
+        }
+    }
+
+    #[test]
+    fn diskv_key_locks() -> DiskvResult<()> {
+        let (held_tx, held) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel();
+        let dkv = Arc::new(
+            DiskvBuilder::new()
+                .backend(GatedBackend {
+                    inner: crate::backend::MemBackend::new(),
+                    gated: encode_name(b"k0", Options::default().key_hasher),
+                    held: sync::Mutex::new(held_tx),
+                    release: sync::Mutex::new(release_rx),
+                })
+                .lock_stripes(4)
+                .build()?,
+        );
+        assert_eq!(4, dkv.key_locks.len());
+        let stripe = dkv.stripe_index(b"k0");
+        let find = |same: bool| {
+            (1..)
+                .map(|i| format!("k{}", i))
+                .find(|k| (dkv.stripe_index(k.as_bytes()) == stripe) == same)
+                .unwrap()
+        };
+        let (other, same) = (find(false), find(true));
+
+        let writer = {
+            let dkv = Arc::clone(&dkv);
+            thread::spawn(move || dkv.put("k0", b"v0".to_vec()))
+        };
+        held.recv_timeout(time::Duration::from_secs(10)).unwrap();
+
+        // k0 is held up storing its value: keys of other stripes are written meanwhile, whether or not they share its
+        // cache shard, those of its stripe wait for it
+        dkv.put(&other, b"v1".to_vec())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get(&other)?);
+        let (done_tx, done) = mpsc::channel();
+        let waiter = {
+            let dkv = Arc::clone(&dkv);
+            thread::spawn(move || done_tx.send(dkv.put(&same, b"v2".to_vec())).unwrap())
+        };
+        assert!(done.recv_timeout(time::Duration::from_millis(100)).is_err());
+
+        release.send(()).unwrap();
+        writer.join().unwrap()?;
+        done.recv().unwrap()?;
+        waiter.join().unwrap();
+        assert_eq!(Some(b"v0".to_vec()), dkv.get("k0")?);
+        assert_eq!(3, dkv.len()?);
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_sharded_cache() -> DiskvResult<()> {
+        use std::sync::mpsc;
+
+        // whether a get of another key completes while a writer holds the lock of key1
+        let get_while_writing = |cache_shards: usize| -> DiskvResult<bool> {
+            let base_path = path::PathBuf::from(format!("test_data_sharded_{}", cache_shards));
+            let _ = fs::remove_dir_all(&base_path);
+            let dkv = Arc::new(Diskv::new(Options {
+                base_path: base_path.clone(),
+                cache_shards,
+                ..Default::default()
+            })?);
+            let key1 = String::from("k0");
+            let key2 = (1..)
+                .map(|i| format!("k{}", i))
+                .find(|k| {
+                    cache_shards == 1
+                        || dkv.shard_index(k.as_bytes()) != dkv.shard_index(key1.as_bytes())
+                })
+                .unwrap();
+            dkv.put(&key2, b"bb".to_vec())?;
+
+            let writer = dkv.shard(key1.as_bytes()).write().unwrap();
+            let (tx, rx) = mpsc::channel();
+            let reader = Arc::clone(&dkv);
+            let t = thread::spawn(move || tx.send(reader.get(&key2)).unwrap());
+            let completed = rx.recv_timeout(time::Duration::from_millis(200));
+            drop(writer);
+            t.join().unwrap();
+            let completed = match completed {
+                Ok(v) => {
+                    assert_eq!(Some(b"bb".to_vec()), v?);
+                    true
+                }
+                Err(_) => false,
+            };
+            fs::remove_dir_all(&base_path)?;
+            Ok(completed)
+        };
+        assert!(!get_while_writing(1)?);
+        assert!(get_while_writing(8)?);
+
+        // stats and clear cover all shards
+        let base_path = path::PathBuf::from("test_data_sharded");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Arc::new(Diskv::new(Options {
+            base_path: base_path.clone(),
+            cache_shards: 4,
+            ..Default::default()
+        })?);
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let dkv = Arc::clone(&dkv);
+                thread::spawn(move || -> DiskvResult<()> {
+                    for j in 0..25 {
+                        let key = format!("k{}_{}", i, j);
+                        dkv.put(&key, key.clone().into_bytes())?;
+                        assert_eq!(Some(key.clone().into_bytes()), dkv.get(&key)?);
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap()?;
+        }
+        let stats = dkv.stats();
+        assert_eq!((100, 100), (stats.hits, stats.entries));
+        dkv.clear()?;
+        assert_eq!(0, dkv.stats().entries);
+        assert!(dkv.is_empty()?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    fn diskv_export_import(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let src = new_diskv("export", 1024);
+        let long_key = "l".repeat(250);
+        src.put("k1", b"aa".to_vec())?;
+        src.put("k/2", vec![0, 255, 10])?;
+        src.put(&long_key, Vec::new())?;
+        let mut archive = Vec::new();
+        src.export(&mut archive)?;
+
+        let dst = new_diskv("import", 1024);
+        dst.put("k1", b"old".to_vec())?;
+        dst.put("k3", b"cc".to_vec())?;
+        dst.import(&archive[..])?;
+        assert_eq!(src.scan_prefix("k")?.len() + 1, dst.scan_prefix("k")?.len());
+        for (key, val) in src.scan_prefix("")? {
+            assert_eq!(Some(val), dst.get(&key)?);
+        }
+        assert_eq!(Some(b"cc".to_vec()), dst.get("k3")?);
+
+        // every truncation of the archive is detected
+        for len in 0..archive.len() {
+            match dst.import(&archive[..len]) {
+                Err(DiskvError::CorruptArchive(_)) => {}
+                r => panic!("unexpected result for {} bytes: {:?}", len, r),
+            }
+        }
+        let mut bad_magic = archive.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(
+            dst.import(&bad_magic[..]),
+            Err(DiskvError::CorruptArchive(_))
+        ));
+
+        remove_test_diskv(&src)?;
+        remove_test_diskv(&dst)?;
+        Ok(())
+    }
+
+    fn diskv_get_or_insert_with(new_diskv: NewDiskv) -> DiskvResult<()> {
+        use std::sync::atomic::AtomicUsize;
+
+        let dkv = Arc::new(new_diskv("get_or_insert_with", 1024));
+        let key = String::from("k1");
+        let calls = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(sync::Barrier::new(8));
+
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                let (dkv, key, calls, barrier) = (
+                    Arc::clone(&dkv),
+                    key.clone(),
+                    Arc::clone(&calls),
+                    Arc::clone(&barrier),
+                );
+                thread::spawn(move || {
+                    barrier.wait();
+                    dkv.get_or_insert_with(&key, || {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        thread::sleep(time::Duration::from_millis(20));
+                        format!("v{}", i).into_bytes()
+                    })
+                })
+            })
+            .collect();
+        let vals: Vec<Vec<u8>> = threads
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect::<DiskvResult<_>>()?;
+        assert_eq!(1, calls.load(Ordering::SeqCst));
+        assert!(vals.iter().all(|v| *v == vals[0]));
+        assert_eq!(Some(vals[0].clone()), dkv.get_uncached(&key)?);
+
+        // present already, f is not called
+        let v = dkv.get_or_insert_with(&key, || panic!("called for a present key"))?;
+        assert_eq!(vals[0], v);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_read_only() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_read_only");
+        let _ = fs::remove_dir_all(&base_path);
+        let (key1, key2) = (String::from("k1"), String::from("k2"));
+        let dkv = new_test_diskv("read_only", 1024);
+        dkv.put(&key1, b"aa".to_vec())?;
+        dkv.put_with_ttl(&key2, b"bb".to_vec(), time::Duration::from_millis(10))?;
+        drop(dkv);
+        thread::sleep(time::Duration::from_millis(20));
+
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            read_only: true,
+            ..Default::default()
+        })?;
+        assert_eq!(Some(b"aa".to_vec()), dkv.get(&key1)?);
+        assert_eq!(Some(b"aa".to_vec()), dkv.get(&key1)?);
+        assert_eq!(1, dkv.stats().hits);
+        assert_eq!(vec![(key1.clone(), b"aa".to_vec())], dkv.scan_prefix("k1")?);
+        // expired, but not removed
+        assert_eq!(None, dkv.get(&key2)?);
+        assert!(dkv.key_path(&key2).exists());
+
+        let is_read_only = |r: DiskvResult<()>| matches!(r, Err(DiskvError::ReadOnly));
+        assert!(is_read_only(dkv.put(&key1, b"xx".to_vec())));
+        assert!(is_read_only(
+            dkv.put_many(vec![(key1.clone(), b"xx".to_vec())])
+        ));
+        assert!(is_read_only(dkv.delete(&key1)));
+        assert!(is_read_only(dkv.take(&key1).map(|_| ())));
+        assert!(is_read_only(dkv.increment(&key1, 1).map(|_| ())));
+        assert!(is_read_only(
+            dkv.compare_and_swap(&key1, None, Vec::new()).map(|_| ())
+        ));
+        assert!(is_read_only(
+            dkv.get_or_insert_with(&key2, Vec::new).map(|_| ())
+        ));
+        assert!(is_read_only(dkv.import(&[][..])));
+        assert!(is_read_only(dkv.clear()));
+        assert_eq!(Some(b"aa".to_vec()), dkv.get_uncached(&key1)?);
+        assert_eq!(b"aa".to_vec(), dkv.get_or_insert_with(&key1, Vec::new)?);
+        fs::remove_dir_all(&base_path)?;
+
+        // missing base_path isn't created
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            read_only: true,
+            ..Default::default()
+        })?;
+        assert_eq!(None, dkv.get(&key1)?);
+        assert!(!path::Path::new(&base_path).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_dir_shard_depth() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_dir_shard_depth");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            cache_size_max: 0,
+            dir_shard_depth: 2,
+            sync_on_write: true,
+            ..Default::default()
+        })?;
+        let mut keys: Vec<String> = (0..200).map(|i| format!("k{}", i)).collect();
+        keys.push("l".repeat(250));
+        for key in &keys {
+            dkv.put(key, key.clone().into_bytes())?;
+        }
+        dkv.put_with_ttl(
+            &keys[0],
+            keys[0].clone().into_bytes(),
+            time::Duration::from_secs(60),
+        )?;
+
+        // nothing but shard directories right under base_path, and files two levels below
+        let top: Vec<_> = fs::read_dir(&base_path)?.collect::<io::Result<_>>()?;
+        assert!(top.len() > 100);
+        for entry in top {
+            assert!(entry.file_type()?.is_dir());
+            for entry in fs::read_dir(entry.path())? {
+                assert!(entry?.file_type()?.is_dir());
+            }
+        }
+        let key_path = dkv.key_path(&keys[0]);
+        assert_eq!(key_path.parent(), dkv.meta_path(&keys[0]).parent());
+        assert_eq!(
+            3,
+            key_path
+                .strip_prefix(&base_path)
+                .unwrap()
+                .components()
+                .count()
+        );
+
+        for key in &keys {
+            assert_eq!(Some(key.clone().into_bytes()), dkv.get(key)?);
+        }
+        let mut stored = dkv.keys()?;
+        stored.sort();
+        keys.sort();
+        assert_eq!(keys, stored);
+
+        dkv.delete(&keys[1])?;
+        assert_eq!(None, dkv.get(&keys[1])?);
+        assert_eq!(keys.len() - 1, dkv.len()?);
+        dkv.sync()?;
+        dkv.clear()?;
+        assert!(dkv.is_empty()?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_prepare_shards() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_prepare_shards");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = DiskvBuilder::new()
+            .base_path(&base_path)
+            .dir_shard_depth(1)
+            .build()?;
+        dkv.put("k1", b"v1".to_vec())?;
+        let count = || fs::read_dir(&base_path).unwrap().count();
+        assert_eq!(1, count());
+
+        dkv.prepare_shards()?;
+        assert_eq!(256, count());
+        for entry in fs::read_dir(&base_path)? {
+            let entry = entry?;
+            assert!(entry.file_type()?.is_dir());
+            assert!(is_shard_dir_name(entry.file_name().to_str().unwrap()));
+        }
+        dkv.prepare_shards()?;
+        assert_eq!(256, count());
+        assert_eq!(Some(b"v1".to_vec()), dkv.get_uncached("k1")?);
+        assert_eq!(1, dkv.len()?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_sync_on_drop() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_sync_on_drop");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            wal: true,
+            ..Default::default()
+        };
+
+        let dkv = Diskv::new(options())?;
+        dkv.put("k1", b"aa".to_vec())?;
+        dkv.delete("k1")?;
+        dkv.put("k2", b"bb".to_vec())?;
+        drop(dkv);
+        // log got checkpointed
+        assert_eq!(0, fs::metadata(base_path.join(WAL_NAME))?.len());
+
+        let dkv = Diskv::new(options())?;
+        assert_eq!(None, dkv.get("k1")?);
+        assert_eq!(Some(b"bb".to_vec()), dkv.get("k2")?);
+        drop(dkv);
+
+        // without it the log is left as it is
+        let dkv = Diskv::new(Options {
+            sync_on_drop: false,
+            ..options()
+        })?;
+        dkv.put("k3", b"cc".to_vec())?;
+        drop(dkv);
+        assert!(fs::metadata(base_path.join(WAL_NAME))?.len() > 0);
+        assert_eq!(Some(b"cc".to_vec()), Diskv::new(options())?.get("k3")?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_get_with_metadata() -> DiskvResult<()> {
+        let dkv = new_test_diskv("get_with_metadata", 4);
+        dkv.put("small", b"abc".to_vec())?;
+        dkv.put("large", b"0123456789".to_vec())?; // too large for the cache
+
+        for key in ["small", "large"].iter() {
+            let (v, meta) = dkv.get_with_metadata(key)?.unwrap();
+            assert_eq!(v.len() as u64, meta.size);
+            let modified = fs::metadata(dkv.key_path(key))?.modified()?;
+            assert_eq!(modified, meta.modified);
+        }
+        assert_eq!(vec!["small"], dkv.cached_keys());
+        assert!(dkv.get_with_metadata("absent")?.is_none());
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_metadata() -> DiskvResult<()> {
+        for (name, in_memory) in [("metadata", false), ("metadata_in_memory", true)].iter() {
+            let dkv = new_test_diskv(name, 1024);
+            let dkv = if *in_memory {
+                fs::remove_dir_all(&dkv.options.base_path)?;
+                Diskv::new(Options::in_memory())?
+            } else {
+                dkv
+            };
+            let long_key = "l".repeat(250);
+            let before = time::SystemTime::now() - time::Duration::from_secs(1);
+
+            assert_eq!(None, dkv.metadata("k1")?);
+            dkv.put("k1", vec![b'x'; 1000])?;
+            dkv.put(&long_key, vec![b'y'; 10])?;
+            let meta = dkv.metadata("k1")?.unwrap();
+            assert_eq!(1000, meta.size);
+            assert!(meta.modified >= before && meta.modified <= time::SystemTime::now());
+            assert_eq!(10, dkv.metadata(&long_key)?.unwrap().size);
+            assert_eq!(None, dkv.metadata(&"l".repeat(251))?);
+
+            dkv.put_with_ttl("k2", Vec::new(), time::Duration::from_millis(10))?;
+            assert_eq!(0, dkv.metadata("k2")?.unwrap().size);
+            thread::sleep(time::Duration::from_millis(20));
+            assert_eq!(None, dkv.metadata("k2")?);
+
+            if !*in_memory {
+                fs::remove_dir_all(&dkv.options.base_path)?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_delete_prefix() -> DiskvResult<()> {
+        let dkv = new_test_diskv("delete_prefix", 1024);
+        for key in &["session:1", "session:2", "session:3", "user:1", "user:2"] {
+            dkv.put(key, key.as_bytes().to_vec())?;
+        }
+        let long_key = format!("session:{}", "l".repeat(240));
+        dkv.put(&long_key, Vec::new())?;
+
+        assert_eq!(4, dkv.delete_prefix("session:")?);
+        for key in &["session:1", "session:2", "session:3", &long_key] {
+            assert_eq!(None, dkv.get(key)?);
+            assert!(!dkv.key_path(key).exists());
+        }
+        let mut keys = dkv.keys()?;
+        keys.sort();
+        assert_eq!(vec!["user:1", "user:2"], keys);
+        assert_eq!(Some(b"user:1".to_vec()), dkv.get("user:1")?);
+        assert_eq!(0, dkv.delete_prefix("session:")?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_reaper() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_reaper");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path,
+            reaper_interval: Some(time::Duration::from_millis(20)),
+            ..Default::default()
+        })?;
+        dkv.put_with_ttl("k1", b"v1".to_vec(), time::Duration::from_millis(10))?;
+        dkv.put_with_ttl("k2", b"v2".to_vec(), time::Duration::from_secs(60))?;
+        dkv.put("k3", b"v3".to_vec())?;
+        assert_eq!(3, dkv.stats().entries);
+
+        thread::sleep(time::Duration::from_millis(200));
+        assert!(!dkv.key_path("k1").exists());
+        assert!(!dkv.meta_path("k1").exists());
+        assert_eq!(2, dkv.stats().entries);
+        assert!(dkv.key_path("k2").exists() && dkv.key_path("k3").exists());
+
+        // dropping stops the thread without waiting for the next round
+        let base_path = dkv.options.base_path.clone();
+        let started = time::Instant::now();
+        drop(dkv);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_open() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_open");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            ..Default::default()
+        };
+
+        match Diskv::open(options()) {
+            Err(DiskvError::IOError(e)) => assert_eq!(io::ErrorKind::NotFound, e.kind()),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        assert!(!path::Path::new(&base_path).exists());
+
+        fs::write(&base_path, b"not a store")?;
+        match Diskv::open(options()) {
+            Err(DiskvError::NotADirectory(path)) => assert_eq!(base_path, path),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+        fs::remove_file(&base_path)?;
+
+        Diskv::new(options())?.put("k1", b"v1".to_vec())?;
+        let dkv = Diskv::open(options())?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_transaction() -> DiskvResult<()> {
+        let dkv = new_test_diskv("transaction", 1024);
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put_with_ttl("k2", b"v2".to_vec(), time::Duration::from_secs(60))?;
+
+        let mut tx = dkv.transaction();
+        tx.put("k1", b"v1-new".to_vec());
+        tx.delete("k2");
+        tx.put("k3", b"v3".to_vec());
+        tx.commit()?;
+        assert_eq!(Some(b"v1-new".to_vec()), dkv.get("k1")?);
+        assert_eq!(None, dkv.get("k2")?);
+        assert_eq!(Some(b"v3".to_vec()), dkv.get("k3")?);
+
+        // dropped without commit
+        let mut tx = dkv.transaction();
+        tx.delete("k1");
+        drop(tx);
+        assert_eq!(Some(b"v1-new".to_vec()), dkv.get("k1")?);
+
+        // value of blocked can't be written, a directory being in the way
+        dkv.put_with_ttl("k2", b"v2".to_vec(), time::Duration::from_secs(60))?;
+        fs::create_dir(dkv.key_path("blocked"))?;
+        let mut tx = dkv.transaction();
+        tx.put("k1", b"v1-tx".to_vec());
+        tx.delete("k2");
+        tx.put("k3", b"v3-tx".to_vec());
+        tx.delete("k3");
+        tx.put("k4", b"v4".to_vec());
+        tx.put("blocked", b"v".to_vec());
+        assert!(matches!(tx.commit(), Err(DiskvError::IOError(_))));
+        assert_eq!(Some(b"v1-new".to_vec()), dkv.get_uncached("k1")?);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get_uncached("k2")?);
+        assert!(dkv.meta_path("k2").exists());
+        assert_eq!(Some(b"v3".to_vec()), dkv.get_uncached("k3")?);
+        assert_eq!(None, dkv.get_uncached("k4")?);
+        assert_eq!(Some(b"v1-new".to_vec()), dkv.get("k1")?);
+        assert_eq!(Some(b"v3".to_vec()), dkv.get("k3")?);
+
+        // nothing is applied for invalid keys
+        let mut tx = dkv.transaction();
+        tx.delete("k1");
+        tx.put("", Vec::new());
+        assert!(matches!(tx.commit(), Err(DiskvError::InvalidKey(_))));
+        assert_eq!(Some(b"v1-new".to_vec()), dkv.get("k1")?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_transaction_max_disk_bytes() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_transaction_max_disk_bytes");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = DiskvBuilder::new()
+            .base_path(&base_path)
+            .max_disk_bytes(350)
+            .build()?;
+        dkv.put("k1", vec![1; 100])?;
+        dkv.put("k2", vec![2; 100])?;
+
+        // would need another key to be evicted, nothing is applied
+        let mut tx = dkv.transaction();
+        tx.put("k3", vec![3; 100]);
+        tx.put("k4", vec![4; 100]);
+        assert!(matches!(tx.commit(), Err(DiskvError::OutOfSpace(_))));
+        assert_eq!(Some(vec![1; 100]), dkv.get_uncached("k1")?);
+        assert_eq!(Some(vec![2; 100]), dkv.get_uncached("k2")?);
+        assert_eq!(None, dkv.get_uncached("k3")?);
+
+        // fits once k1 is deleted by the same batch
+        let mut tx = dkv.transaction();
+        tx.delete("k1");
+        tx.put("k3", vec![3; 100]);
+        tx.put("k4", vec![4; 100]);
+        tx.commit()?;
+        assert_eq!(None, dkv.get_uncached("k1")?);
+        for key in ["k2", "k3", "k4"].iter() {
+            assert!(dkv.get_uncached(key)?.is_some());
+        }
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_verify_repair() -> DiskvResult<()> {
+        let dkv = new_test_diskv("verify_repair", 1024);
+        let quarantine = path::PathBuf::from("test_data_verify_repair_quarantine");
+        let _ = fs::remove_dir_all(&quarantine);
+        for i in 0..5 {
+            dkv.put(&format!("k{}", i), format!("v{}", i).into_bytes())?;
+        }
+        assert!(dkv.verify()?.is_empty());
+        assert!(dkv.repair(&quarantine)?.is_empty());
+        assert!(!quarantine.exists());
+
+        // flip a bit of the value on disk, its cached copy is still fine
+        let mut buf = fs::read(dkv.key_path("k3"))?;
+        *buf.last_mut().unwrap() ^= 1;
+        fs::write(dkv.key_path("k3"), &buf)?;
+        assert_eq!(vec!["k3"], dkv.verify()?);
+
+        assert_eq!(vec!["k3"], dkv.repair(&quarantine)?);
+        assert_eq!(buf, fs::read(quarantine.join("k3"))?);
+        assert!(!dkv.key_path("k3").exists());
+        assert_eq!(None, dkv.get("k3")?);
+        assert_eq!(4, dkv.len()?);
+        assert!(dkv.verify()?.is_empty());
+
+        fs::remove_dir_all(&quarantine)?;
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_checksum() -> DiskvResult<()> {
+        let dkv = new_test_diskv("checksum", 0); // nothing cached
+        let long_key = "l".repeat(250);
+        for key in ["k1", &long_key].iter().copied() {
+            dkv.put(key, b"value".to_vec())?;
+            // flip a bit of the value on disk
+            let mut buf = fs::read(dkv.key_path(key))?;
+            *buf.last_mut().unwrap() ^= 1;
+            fs::write(dkv.key_path(key), &buf)?;
+
+            match dkv.get(key) {
+                Err(DiskvError::ChecksumMismatch { key: k }) => assert_eq!(key, k),
+                res => panic!("unexpected result: {:?}", res),
+            }
+            assert_eq!(5, dkv.metadata(key)?.unwrap().size);
+        }
+        let base_path = dkv.options.base_path.clone();
+        drop(dkv);
+
+        // without verifying, corrupt values are returned as they are, and new ones are written without checksum
+        let dkv = Diskv::new(Options {
+            base_path,
+            verify_checksums: false,
+            ..Default::default()
+        })?;
+        assert_eq!(Some(b"valud".to_vec()), dkv.get("k1")?);
+        dkv.put("k2", b"v2".to_vec())?;
+        assert_eq!(
+            encode_file(b"k2", b"v2", &dkv.file_format())?,
+            fs::read(dkv.key_path("k2"))?
+        );
+        assert_eq!(2, dkv.metadata("k2")?.unwrap().size);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_cached_keys(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("cached_keys", 4);
+        assert!(dkv.cached_keys().is_empty());
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put("k2", b"v2".to_vec())?;
+        assert_eq!(vec!["k1", "k2"], dkv.cached_keys());
+        assert!(dkv.is_cached("k1"));
+        let stats = dkv.stats();
+
+        // k1 is the least recently used one
+        dkv.put("k3", b"v3".to_vec())?;
+        assert_eq!(vec!["k2", "k3"], dkv.cached_keys());
+        assert!(!dkv.is_cached("k1"));
+        assert!(dkv.is_cached("k3"));
+        assert_eq!(stats.hits, dkv.stats().hits);
+        assert_eq!(stats.misses, dkv.stats().misses);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn diskv_encryption() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_encryption");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = |encryption_key| Options {
+            base_path: base_path.clone(),
+            cache_size_max: 0,
+            encryption_key,
+            ..Default::default()
+        };
+        let long_key = "l".repeat(250);
+        let val = b"secret secret secret".to_vec();
+
+        // plain value written before the key was set
+        Diskv::new(options(None))?.put("plain", b"v".to_vec())?;
+        let dkv = Diskv::new(options(Some([7; 32])))?;
+        for key in ["k1", &long_key].iter().copied() {
+            dkv.put(key, val.clone())?;
+            let buf = fs::read(dkv.key_path(key))?;
+            assert!(!buf.windows(6).any(|w| w == b"secret"));
+            assert_eq!(Some(val.clone()), dkv.get(key)?);
+            assert_eq!(val.len() as u64, dkv.metadata(key)?.unwrap().size);
+        }
+        assert_eq!(Some(b"v".to_vec()), dkv.get("plain")?);
+        // same value, different nonce
+        dkv.put("k2", val.clone())?;
+        assert_ne!(fs::read(dkv.key_path("k1"))?, fs::read(dkv.key_path("k2"))?);
+        // moved to another key
+        fs::copy(dkv.key_path("k1"), dkv.key_path("k3"))?;
+        assert!(matches!(
+            dkv.get("k3"),
+            Err(DiskvError::DecryptionFailed { .. })
+        ));
+        drop(dkv);
+
+        for encryption_key in [Some([8; 32]), None].iter().copied() {
+            let dkv = Diskv::new(options(encryption_key))?;
+            for key in ["k1", &long_key].iter().copied() {
+                match dkv.get(key) {
+                    Err(DiskvError::DecryptionFailed { key: k }) => assert_eq!(key, k),
+                    res => panic!("unexpected result: {:?}", res),
+                }
+            }
+        }
+
+        // tampered with
+        let dkv = Diskv::new(options(Some([7; 32])))?;
+        let mut buf = fs::read(dkv.key_path("k1"))?;
+        *buf.last_mut().unwrap() ^= 1;
+        fs::write(dkv.key_path("k1"), &buf)?;
+        assert!(matches!(
+            dkv.get("k1"),
+            Err(DiskvError::DecryptionFailed { .. })
+        ));
+        let mut corrupt = dkv.verify()?;
+        corrupt.sort();
+        assert_eq!(vec!["k1", "k3"], corrupt);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    fn diskv_retain(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("retain", 1024);
+        for (key, len) in [("k1", 1), ("k2", 5), ("k3", 2), ("k4", 8)].iter().copied() {
+            dkv.put(key, vec![b'x'; len])?;
+        }
+        dkv.put_with_ttl("k5", vec![b'x'; 10], time::Duration::from_millis(10))?;
+        thread::sleep(time::Duration::from_millis(20));
+
+        let mut seen = Vec::new();
+        let deleted = dkv.retain(|key, val| {
+            seen.push(key.to_string());
+            val.len() > 2
+        })?;
+        assert_eq!(2, deleted);
+        seen.sort();
+        assert_eq!(vec!["k1", "k2", "k3", "k4"], seen);
+
+        let mut keys = dkv.keys()?;
+        keys.sort();
+        assert_eq!(vec!["k2", "k4"], keys);
+        assert_eq!(vec!["k2", "k4"], dkv.cached_keys());
+        assert_eq!(None, dkv.get("k1")?);
+        assert_eq!(Some(vec![b'x'; 8]), dkv.get("k4")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_write_large_value() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_write_large_value");
+        let _ = fs::remove_dir_all(&base_path);
+        let mut seed: u32 = 7;
+        let val: Vec<u8> = (0..5 * 1024 * 1024 + 3)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+
+        for write_buffer_size in [4096, 64 * 1024].iter().copied() {
+            let dkv = Diskv::new(Options {
+                base_path: base_path.clone(),
+                cache_size_max: 0,
+                write_buffer_size,
+                ..Default::default()
+            })?;
+            dkv.put("k1", val.clone())?;
+            assert_eq!(Some(val.clone()), dkv.get("k1")?);
+        }
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_iter() -> DiskvResult<()> {
+        for depth in [0, 2].iter().copied() {
+            let base_path = path::PathBuf::from(format!("test_data_iter_{}", depth));
+            let _ = fs::remove_dir_all(&base_path);
+            let dkv = Diskv::new(Options {
+                base_path,
+                cache_size_max: 0,
+                dir_shard_depth: depth,
+                ..Default::default()
+            })?;
+            let long_key = "l".repeat(250);
+            let mut expected = HashMap::new();
+            for key in ["k1", "k2", "k3", &long_key].iter().copied() {
+                dkv.put(key, format!("v-{}", key).into_bytes())?;
+                expected.insert(key.to_string(), format!("v-{}", key).into_bytes());
+            }
+            dkv.put_with_ttl("expired", Vec::new(), time::Duration::from_millis(10))?;
+            thread::sleep(time::Duration::from_millis(20));
+
+            let entries = dkv.iter()?.collect::<DiskvResult<HashMap<_, _>>>()?;
+            assert_eq!(expected, entries);
+
+            // each value is read only as the iterator gets to it
+            dkv.reset_stats();
+            let first = dkv.iter()?.next().unwrap()?;
+            assert_eq!(Some(&first.1), expected.get(&first.0));
+            assert!(dkv.stats().misses <= 2); // possibly after the expired one
+
+            fs::remove_dir_all(&dkv.options.base_path)?;
+        }
+
+        let dkv = Diskv::new(Options::in_memory())?;
+        dkv.put("k1", b"v1".to_vec())?;
+        assert_eq!(
+            vec![(String::from("k1"), b"v1".to_vec())],
+            dkv.iter()?.collect::<DiskvResult<Vec<_>>>()?
+        );
+        Ok(())
+    }
+
+    fn diskv_put_if_absent(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = Arc::new(new_diskv("put_if_absent", 1024));
+        assert!(dkv.put_if_absent("k1", b"v1".to_vec())?);
+        assert!(!dkv.put_if_absent("k1", b"v2".to_vec())?);
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+
+        for round in 0..10 {
+            let key = format!("lock{}", round);
+            let barrier = Arc::new(sync::Barrier::new(2));
+            let threads: Vec<_> = (0..2)
+                .map(|i| {
+                    let (dkv, key, barrier) = (Arc::clone(&dkv), key.clone(), Arc::clone(&barrier));
+                    thread::spawn(move || {
+                        barrier.wait();
+                        dkv.put_if_absent(&key, vec![i])
+                    })
+                })
+                .collect();
+            let won: Vec<bool> = threads
+                .into_iter()
+                .map(|t| t.join().unwrap())
+                .collect::<DiskvResult<_>>()?;
+            assert_eq!(1, won.iter().filter(|won| **won).count());
+            let winner = won.iter().position(|won| *won).unwrap() as u8;
+            assert_eq!(Some(vec![winner]), dkv.get_uncached(&key)?);
+        }
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_write_around() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_write_around");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path,
+            write_policy: WritePolicy::WriteAround,
+            ..Default::default()
+        })?;
+
+        dkv.put("k1", b"v1".to_vec())?;
+        assert!(dkv.cached_keys().is_empty());
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!(vec!["k1"], dkv.cached_keys());
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!(1, dkv.stats().hits);
+
+        // overwriting uncaches the old value
+        dkv.put_with_ttl("k1", b"v2".to_vec(), time::Duration::from_secs(60))?;
+        assert!(dkv.cached_keys().is_empty());
+        assert_eq!(Some(b"v2".to_vec()), dkv.get("k1")?);
+        assert_eq!(vec!["k1"], dkv.cached_keys());
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_put_no_cache(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("put_no_cache", 1024);
+        dkv.put("k1", b"v1".to_vec())?;
+        assert!(dkv.is_cached("k1"));
+
+        dkv.put_no_cache("k1", b"v2".to_vec())?;
+        assert!(dkv.cached_keys().is_empty());
+        assert_eq!(Some(b"v2".to_vec()), dkv.get("k1")?);
+        assert_eq!(1, dkv.stats().misses);
+        dkv.put_no_cache("k2", b"v3".to_vec())?;
+        assert_eq!(vec!["k1"], dkv.cached_keys());
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_get_populates_cache(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = Arc::new(new_diskv("get_populates_cache", 1024));
+        dkv.put_with_ttl("k1", b"v1".to_vec(), time::Duration::from_secs(60))?;
+        dkv.flush_cache();
+
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!(vec!["k1"], dkv.cached_keys());
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!((1, 1), (dkv.stats().hits, dkv.stats().misses));
+        assert_eq!(None, dkv.get("k2")?);
+        assert_eq!(vec!["k1"], dkv.cached_keys());
+
+        // whatever gets cached by racing gets and puts is the value on disk
+        let threads: Vec<_> = (0..4u8)
+            .map(|id| {
+                let dkv = Arc::clone(&dkv);
+                thread::spawn(move || -> DiskvResult<()> {
+                    for _ in 0..50 {
+                        if id % 2 == 0 {
+                            dkv.put("k3", vec![id])?;
+                        } else {
+                            dkv.get("k3")?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+        for t in threads {
+            t.join().unwrap()?;
+        }
+        assert_eq!(dkv.read_file(b"k3")?, dkv.get("k3")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_get_does_not_write() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_get_does_not_write");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            wal: true,
+            ..Default::default()
+        };
+        Diskv::new(options())?.put_with_ttl("k1", b"v1".to_vec(), time::Duration::from_secs(60))?;
+        let dkv = Diskv::new(options())?;
+        let modified = fs::metadata(dkv.key_path("k1"))?.modified()?;
+        let meta_modified = fs::metadata(dkv.meta_path("k1"))?.modified()?;
+        thread::sleep(time::Duration::from_millis(20));
+
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert!(dkv.is_cached("k1"));
+        assert_eq!(modified, fs::metadata(dkv.key_path("k1"))?.modified()?);
+        assert_eq!(
+            meta_modified,
+            fs::metadata(dkv.meta_path("k1"))?.modified()?
+        );
+        assert_eq!(0, fs::metadata(dkv.file_path(WAL_NAME))?.len());
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    // deterministic stream of bytes which don't compress
+    struct NoiseReader {
+        seed: u32,
+        left: usize,
+    }
+
+    impl Read for NoiseReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.left).min(10_000);
+            for b in &mut buf[..n] {
+                self.seed = self.seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                *b = (self.seed >> 16) as u8;
+            }
+            self.left -= n;
+            Ok(n)
+        }
+    }
+
+    // CRC-32 and length of everything r reads
+    fn stream_crc32<R: Read>(mut r: R) -> io::Result<(u32, usize)> {
+        let (mut crc, mut len, mut buf) = (crc32fast::Hasher::new(), 0, vec![0u8; 100_000]);
+        loop {
+            let n = r.read(&mut buf)?;
+            if n == 0 {
+                return Ok((crc.finalize(), len));
+            }
+            crc.update(&buf[..n]);
+            len += n;
+        }
+    }
+
+    #[test]
+    fn diskv_streaming() -> DiskvResult<()> {
+        let noise = |left| NoiseReader { seed: 7, left };
+        let len = 8 * 1024 * 1024 + 5;
+        let expected = stream_crc32(noise(len))?;
+
+        let base_path = path::PathBuf::from("test_data_streaming");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            wal: true,
+            max_value_size: Some(len as u64),
+            sync_on_drop: false,
+            ..Default::default()
+        };
+        let dkv = Diskv::new(options())?;
+        let long_key = "l".repeat(250);
+        for key in ["k1", &long_key].iter().copied() {
+            dkv.put(key, b"small".to_vec())?;
+            dkv.put_reader(key, noise(len))?;
+            assert!(!dkv.is_cached(key));
+            assert_eq!(expected, stream_crc32(dkv.get_reader(key)?.unwrap())?);
+            assert_eq!(len as u64, dkv.metadata(key)?.unwrap().size);
+        }
+        assert!(dkv.get_reader("k2")?.is_none());
+        assert!(dkv.get_reader(&"l".repeat(251))?.is_none());
+
+        // too large, old value is kept
+        match dkv.put_reader("k1", noise(len + 1)) {
+            Err(DiskvError::ValueTooLarge { .. }) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(expected, stream_crc32(dkv.get_reader("k1")?.unwrap())?);
+
+        // logged put of "small" isn't replayed over the streamed value
+        drop(dkv);
+        let dkv = Diskv::new(options())?;
+        assert_eq!(expected, stream_crc32(dkv.get_reader("k1")?.unwrap())?);
+        assert_eq!(len, dkv.get("k1")?.unwrap().len());
+
+        // put values read as well, corrupt ones failing at the end
+        let small = b"small".to_vec();
+        dkv.put("k3", small.clone())?;
+        let mut val = Vec::new();
+        dkv.get_reader("k3")?.unwrap().read_to_end(&mut val)?;
+        assert_eq!(small, val);
+        let mut buf = fs::read(dkv.key_path("k3"))?;
+        *buf.last_mut().unwrap() ^= 1;
+        fs::write(dkv.key_path("k3"), &buf)?;
+        let err = dkv
+            .get_reader("k3")?
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidData, err.kind());
+
+        fs::remove_dir_all(&base_path)?;
+
+        let dkv = Diskv::new(Options::in_memory())?;
+        dkv.put_reader("k1", noise(100_000))?;
+        assert_eq!(
+            stream_crc32(noise(100_000))?,
+            stream_crc32(dkv.get_reader("k1")?.unwrap())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_key_collision() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_key_collision");
+        let _ = fs::remove_dir_all(&base_path);
+        let options = || Options {
+            base_path: base_path.clone(),
+            cache_size_max: 0,
+            key_hasher: KeyHasher::Custom(|_| 42),
+            cache_shards: 4,
+            ..Default::default()
+        };
+        let dkv = Diskv::new(options())?;
+        let keys: Vec<String> = ["a", "b", "c"].iter().map(|c| c.repeat(250)).collect();
+        let base = encode_name(keys[0].as_bytes(), KeyHasher::Custom(|_| 42));
+        assert_eq!(
+            base,
+            encode_name(keys[1].as_bytes(), KeyHasher::Custom(|_| 42))
+        );
+
+        for (i, key) in keys.iter().enumerate() {
+            dkv.put(key, format!("v{}", i).into_bytes())?;
+        }
+        dkv.put_with_ttl(&keys[2], b"v2".to_vec(), time::Duration::from_secs(60))?;
+        for name in [
+            slot_name(&base, 0),
+            slot_name(&base, 1),
+            slot_name(&base, 2),
+        ]
+        .iter()
+        {
+            assert!(matches!(parse_name(name), Some(FileName::Hashed)));
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(Some(format!("v{}", i).into_bytes()), dkv.get(key)?);
+            assert_eq!(2, dkv.metadata(key)?.unwrap().size);
+        }
+        let mut r = dkv.get_reader(&keys[1])?.unwrap();
+        let mut val = Vec::new();
+        r.read_to_end(&mut val)?;
+        assert_eq!(b"v1".to_vec(), val);
+        dkv.put(&keys[1], b"v1-new".to_vec())?;
+        assert_eq!(3, dkv.len()?);
+
+        // the last key of the chain moves into the slot of a deleted one, expiry along with it
+        dkv.delete(&keys[0])?;
+        assert_eq!(None, dkv.get(&keys[0])?);
+        assert_eq!(Some(b"v1-new".to_vec()), dkv.get(&keys[1])?);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get(&keys[2])?);
+        assert!(dkv.read_expiry(keys[2].as_bytes())?.is_some());
+        assert_eq!(dkv.key_path(&keys[2]), dkv.file_path(&base));
+        assert!(!dkv.file_path(&slot_name(&base, 2)).exists());
+        drop(dkv);
+
+        let dkv = Diskv::new(options())?;
+        let mut stored = dkv.keys()?;
+        stored.sort();
+        assert_eq!(keys[1..].to_vec(), stored);
+        dkv.put(&keys[0], b"v0".to_vec())?;
+        assert_eq!(Some(b"v0".to_vec()), dkv.get(&keys[0])?);
+        assert_eq!(dkv.key_path(&keys[0]), dkv.file_path(&slot_name(&base, 2)));
+
+        // repair closes up the chain over a quarantined slot too
+        let path = dkv.file_path(&slot_name(&base, 1));
+        let mut buf = fs::read(&path)?;
+        *buf.last_mut().unwrap() ^= 1;
+        fs::write(&path, &buf)?;
+        let quarantine = path::PathBuf::from("test_data_key_collision_quarantine");
+        let _ = fs::remove_dir_all(&quarantine);
+        assert_eq!(vec![keys[1].clone()], dkv.repair(&quarantine)?);
+        assert_eq!(buf, fs::read(quarantine.join(slot_name(&base, 1)))?);
+        assert_eq!(Some(b"v0".to_vec()), dkv.get(&keys[0])?);
+        assert_eq!(dkv.key_path(&keys[0]), path);
+        assert_eq!(2, dkv.len()?);
+
+        fs::remove_dir_all(&quarantine)?;
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_keys_need_not_be_file_names() -> DiskvResult<()> {
+        let dkv = new_test_diskv("unsafe_keys", 12);
+        let keys = vec![
+            String::from("a/b"),
+            String::from(".."),
+            String::from("../escaped"),
+            String::from("line\nbreak"),
+            "long".repeat(60),
+            "/".repeat(MAX_KEY_LEN),
+        ];
+        for (i, key) in keys.iter().enumerate() {
+            dkv.put(key, format!("v{}", i).into_bytes())?;
+        }
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(Some(format!("v{}", i).into_bytes()), dkv.get_uncached(key)?);
+            assert!(dkv.contains_key(key)?);
+        }
+
+        // every value is a plain file right under base_path
+        for entry in fs::read_dir(&dkv.options.base_path)? {
+            assert!(entry?.file_type()?.is_file());
+        }
+        assert!(!path::Path::new("escaped").exists());
+
+        let mut stored = dkv.keys()?;
+        stored.sort();
+        let mut expected = keys.clone();
+        expected.sort();
+        assert_eq!(expected, stored);
+
+        for key in &keys {
+            dkv.delete(key)?;
+            assert_eq!(None, dkv.get(key)?);
+        }
+        assert!(dkv.is_empty()?);
+
+        match dkv.put("", String::from("aa").into_bytes()) {
+            Err(DiskvError::InvalidKey(_)) => (),
+            other => panic!("expected InvalidKey, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_key_validation(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("key_validation", 1024);
+
+        for key in [String::new(), "k".repeat(MAX_KEY_LEN + 1)].iter() {
+            assert!(matches!(
+                dkv.put(key, b"aa".to_vec()),
+                Err(DiskvError::InvalidKey(_))
+            ));
+            assert!(matches!(dkv.get(key), Err(DiskvError::InvalidKey(_))));
+            assert!(matches!(dkv.delete(key), Err(DiskvError::InvalidKey(_))));
+        }
+        match dkv.get(&"k".repeat(MAX_KEY_LEN + 1)) {
+            Err(e) => assert_eq!(
+                "invalid key: key is 256 bytes long, at most 255 are allowed",
+                e.to_string()
+            ),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(dkv.is_empty()?);
+
+        let key = "k".repeat(MAX_KEY_LEN);
+        dkv.put(&key, b"aa".to_vec())?;
+        assert_eq!(Some(b"aa".to_vec()), dkv.get_uncached(&key)?);
+        dkv.delete(&key)?;
+        assert_eq!(None, dkv.get(&key)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_flush_cache(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("flush_cache", 12);
+        let keys: Vec<String> = (0..5).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            dkv.put(key, String::from("aa").into_bytes())?;
+        }
+        assert_eq!(keys, dkv.cached_keys());
+
+        dkv.flush_cache();
+        assert!(dkv.cached_keys().is_empty());
+        assert_eq!(0, dkv.stats().cache_size);
+        assert_eq!(5, dkv.len()?);
+        for key in &keys {
+            assert_eq!(Some(String::from("aa").into_bytes()), dkv.get(key)?);
+        }
+        assert_eq!(keys, dkv.cached_keys());
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_evict(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("evict", 1024);
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put("k2", b"v2".to_vec())?;
+
+        dkv.evict("k1");
+        dkv.evict("absent");
+        assert_eq!(vec!["k2"], dkv.cached_keys());
+        assert_eq!(2, dkv.stats().cache_size);
+        assert_eq!(Some(b"v1".to_vec()), dkv.get_uncached("k1")?);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get_uncached("k2")?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    // fails as many times as told with kind, then gets out of the way of MemBackend
+    struct FlakyBackend {
+        inner: crate::backend::MemBackend,
+        failures: Arc<AtomicU64>,
+        kind: io::ErrorKind,
+    }
+
+    impl FlakyBackend {
+        fn fail(&self) -> io::Result<()> {
+            let left = self.failures.load(Ordering::SeqCst);
+            if left == 0 {
+                return Ok(());
+            }
+            self.failures.store(left - 1, Ordering::SeqCst);
+            Err(io::Error::new(self.kind, "flaky"))
+        }
+    }
+
+    impl Backend for FlakyBackend {
+        fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+            self.fail()?;
+            self.inner.read(name)
+        }
+
+        fn write(&self, name: &str, buf: &[u8]) -> io::Result<()> {
+            self.fail()?;
+            self.inner.write(name, buf)
+        }
+
+        fn delete(&self, name: &str) -> io::Result<()> {
+            self.fail()?;
+            self.inner.delete(name)
+        }
+
+        fn list(&self) -> io::Result<Vec<String>> {
+            self.inner.list()
+        }
+    }
+
+    #[test]
+    fn diskv_io_retries() -> DiskvResult<()> {
+        let flaky = |kind| -> DiskvResult<(Diskv, Arc<AtomicU64>)> {
+            let failures = Arc::new(AtomicU64::new(0));
+            let dkv = DiskvBuilder::new()
+                .backend(FlakyBackend {
+                    inner: crate::backend::MemBackend::new(),
+                    failures: Arc::clone(&failures),
+                    kind,
+                })
+                .io_retries(3)
+                .io_retry_backoff(time::Duration::from_millis(1))
+                .build()?;
+            Ok((dkv, failures))
+        };
+
+        let (dkv, failures) = flaky(io::ErrorKind::Interrupted)?;
+        failures.store(2, Ordering::SeqCst);
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.evict("k1");
+        failures.store(2, Ordering::SeqCst);
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        failures.store(2, Ordering::SeqCst);
+        dkv.delete("k1")?;
+        assert_eq!(None, dkv.get("k1")?);
+
+        // retries run out
+        failures.store(10, Ordering::SeqCst);
+        match dkv.put("k1", b"v1".to_vec()) {
+            Err(DiskvError::KeyIOError { source, .. }) => {
+                assert_eq!(io::ErrorKind::Interrupted, source.kind())
+            }
+            res => panic!("expected KeyIOError, got {:?}", res),
+        }
+        assert_eq!(6, failures.load(Ordering::SeqCst));
+
+        // not retried
+        let (dkv, failures) = flaky(io::ErrorKind::PermissionDenied)?;
+        failures.store(2, Ordering::SeqCst);
+        assert!(dkv.put("k1", b"v1".to_vec()).is_err());
+        assert_eq!(1, failures.load(Ordering::SeqCst));
+        Ok(())
+    }
+
+    fn diskv_namespace(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("namespace", 1024);
+        let (users, sessions) = (dkv.namespace("users"), dkv.namespace("sessions"));
+        users.put("k1", b"user".to_vec())?;
+        sessions.put("k1", b"session".to_vec())?;
+        sessions.put("k2", b"session".to_vec())?;
+        dkv.put("k1", b"plain".to_vec())?;
+        // "users:k" + ":1" and "users" + ":k:1" if names weren't prefixed with their length
+        dkv.namespace("users:k").put("1", b"other".to_vec())?;
+        assert_eq!(None, users.get("k:1")?);
+
+        assert_eq!(Some(b"user".to_vec()), users.get("k1")?);
+        assert_eq!(Some(b"session".to_vec()), sessions.get("k1")?);
+        assert_eq!(Some(b"plain".to_vec()), dkv.get("k1")?);
+        assert!(!users.contains_key("k2")?);
+        assert_eq!(vec!["k1"], users.keys()?);
+        let mut keys = sessions.keys()?;
+        keys.sort();
+        assert_eq!(vec!["k1", "k2"], keys);
+
+        users.delete("k1")?;
+        assert_eq!(None, users.get("k1")?);
+        assert_eq!(Some(b"session".to_vec()), sessions.get("k1")?);
+
+        users.put("k1", b"user".to_vec())?;
+        sessions.clear()?;
+        assert!(sessions.keys()?.is_empty());
+        assert_eq!(Some(b"user".to_vec()), users.get("k1")?);
+        assert_eq!(Some(b"plain".to_vec()), dkv.get("k1")?);
+        assert_eq!(3, dkv.len()?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_drain() -> DiskvResult<()> {
+        let dkv = new_test_diskv("drain", 4);
+        let base_path = dkv.options.base_path.clone();
+        for i in 0..10 {
+            dkv.put(&format!("k{}", i), format!("v{}", i).into_bytes())?;
+        }
+        dkv.put_with_ttl("k0", b"v0".to_vec(), time::Duration::from_secs(60))?;
+        dkv.put_with_ttl("gone", b"v".to_vec(), time::Duration::from_millis(1))?;
+        thread::sleep(time::Duration::from_millis(5));
+
+        let values = dkv.drain()?;
+        assert_eq!(10, values.len());
+        for i in 0..10 {
+            assert_eq!(
+                Some(&format!("v{}", i).into_bytes()),
+                values.get(&format!("k{}", i))
+            );
+        }
+        assert_eq!(0, fs::read_dir(&base_path)?.count());
+        fs::remove_dir_all(&base_path)?;
+
+        // a key which can't be returned keeps everything in place
+        let dkv = new_test_diskv("drain", 4);
+        dkv.put("k1", b"v1".to_vec())?;
+        dkv.put_bytes(&[0xff, 0xfe], b"v2".to_vec())?;
+        assert!(matches!(dkv.drain(), Err(DiskvError::InvalidKey(_))));
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            ..Default::default()
+        })?;
+        assert_eq!(Some(b"v1".to_vec()), dkv.get("k1")?);
+        assert_eq!(Some(b"v2".to_vec()), dkv.get_bytes(&[0xff, 0xfe])?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_clear() -> DiskvResult<()> {
+        let dkv = new_test_diskv("clear", 12);
+        let keys: Vec<String> = (0..5).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            dkv.put(key, String::from("aa").into_bytes())?;
+        }
+        dkv.put_with_ttl(
+            &keys[0],
+            String::from("aa").into_bytes(),
+            time::Duration::from_secs(60),
+        )?;
+        let stray = dkv.options.base_path.join("k1.tmp.123");
+        fs::write(&stray, "xx")?;
+        let foreign = dkv.options.base_path.join("not.ours");
+        fs::write(&foreign, "xx")?;
+
+        dkv.clear()?;
+        assert_eq!(0, dkv.len()?);
+        for key in &keys {
+            assert_eq!(None, dkv.get(key)?);
+        }
+        assert!(!dkv.meta_path(&keys[0]).exists());
+        assert!(!stray.exists());
+        assert!(foreign.exists());
+        assert_eq!(0, dkv.stats().cache_size);
+
+        // still usable
+        dkv.put(&keys[0], String::from("bb").into_bytes())?;
+        assert_eq!(Some(String::from("bb").into_bytes()), dkv.get(&keys[0])?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    fn diskv_compare_and_swap(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("compare_and_swap", 12);
+        let key = String::from("counter");
+
+        // None expects the key to be absent
+        assert!(dkv.compare_and_swap(&key, None, String::from("0").into_bytes())?);
+        assert!(!dkv.compare_and_swap(&key, None, String::from("1").into_bytes())?);
+        assert!(!dkv.compare_and_swap(
+            &key,
+            Some(String::from("5").into_bytes()),
+            String::from("6").into_bytes()
+        )?);
+        assert_eq!(Some(String::from("0").into_bytes()), dkv.get(&key)?);
+
+        // two updates racing from the same observed value, only one of them wins
+        let dkv = Arc::new(dkv);
+        let barrier = Arc::new(sync::Barrier::new(2));
+        let handles: Vec<thread::JoinHandle<bool>> = (1..=2)
+            .map(|i| {
+                let dkv = Arc::clone(&dkv);
+                let barrier = Arc::clone(&barrier);
+                let key = key.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    dkv.compare_and_swap(
+                        &key,
+                        Some(String::from("0").into_bytes()),
+                        format!("{}", i).into_bytes(),
+                    )
+                    .unwrap()
+                })
+            })
+            .collect();
+        let won: Vec<bool> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(1, won.iter().filter(|w| **w).count());
+
+        let winner = won.iter().position(|w| *w).unwrap() + 1;
+        assert_eq!(Some(format!("{}", winner).into_bytes()), dkv.get(&key)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_recovers_from_poisoned_lock(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = Arc::new(new_diskv("poisoned_lock", 12));
+        let key = String::from("k1");
+        dkv.put(&key, String::from("aa").into_bytes())?;
+
+        let poisoner = Arc::clone(&dkv);
+        let res = thread::spawn(move || {
+            let _cache = poisoner.shard(b"k1").write().unwrap();
+            panic!("panic while holding the cache lock");
+        })
+        .join();
+        assert!(res.is_err());
+        assert!(dkv.shard(key.as_bytes()).is_poisoned());
+
+        // value is served from disk, the cache got emptied
+        assert_eq!(Some(String::from("aa").into_bytes()), dkv.get(&key)?);
+        assert!(!dkv.shard(key.as_bytes()).is_poisoned());
+        assert_eq!(1, dkv.stats().entries);
+
+        dkv.put(&key, String::from("bb").into_bytes())?;
+        assert_eq!(Some(String::from("bb").into_bytes()), dkv.get(&key)?);
+        dkv.delete(&key)?;
+        assert_eq!(None, dkv.get(&key)?);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_wal_recovery() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_wal");
+        let _ = fs::remove_dir_all(&base_path);
+        // drop without a checkpoint stands in for a crash
+        let options = || Options {
+            base_path: base_path.clone(),
+            wal: true,
+            sync_on_drop: false,
+            ..Default::default()
+        };
+        let wal_path = base_path.join(WAL_NAME);
+        let (key1, key2, key3) = (String::from("k1"), String::from("k2"), String::from("k3"));
+
+        let dkv = Diskv::new(options())?;
+        dkv.put(&key1, b"aa".to_vec())?;
+        dkv.put(&key2, b"bb".to_vec())?;
+        dkv.put_with_ttl(&key3, b"cc".to_vec(), time::Duration::from_secs(60))?;
+        dkv.put(&key1, b"a2".to_vec())?;
+        dkv.delete(&key2)?;
+        assert!(fs::metadata(&wal_path)?.len() > 0);
+        // value and expiry files lost in a crash, as well as a record cut short by it
+        fs::remove_file(dkv.key_path(&key1))?;
+        fs::remove_file(dkv.meta_path(&key3))?;
+        fs::write(dkv.key_path(&key2), "stale")?;
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&wal_path)?
+            .write_all(&[WAL_PUT, 2, 0])?;
+        drop(dkv);
+
+        let dkv = Diskv::new(options())?;
+        // replayed log is truncated
+        assert_eq!(0, fs::metadata(&wal_path)?.len());
+        assert_eq!(Some(b"a2".to_vec()), dkv.get(&key1)?);
+        assert_eq!(None, dkv.get(&key2)?);
+        assert_eq!(Some(b"cc".to_vec()), dkv.get(&key3)?);
+        assert!(dkv.meta_path(&key3).exists());
+        // log is not taken for a value
+        assert_eq!(2, dkv.len()?);
+
+        dkv.put(&key2, b"b2".to_vec())?;
+        assert!(fs::metadata(&wal_path)?.len() > 0);
+        dkv.checkpoint()?;
+        assert_eq!(0, fs::metadata(&wal_path)?.len());
+        drop(dkv);
+        assert_eq!(Some(b"b2".to_vec()), Diskv::new(options())?.get(&key2)?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_codec_none_stores_value_as_is() -> DiskvResult<()> {
+        let dkv = new_test_diskv("codec_none", 12);
+        let key = String::from("k1");
+        dkv.put(&key, String::from("aa").into_bytes())?;
+        let mut expected = vec![CHECKSUM_MARKER];
+        expected.extend_from_slice(&crc32fast::hash(b"aa").to_le_bytes());
+        expected.extend_from_slice(b"\0aa");
+        assert_eq!(expected, fs::read(dkv.key_path(&key))?);
+
+        fs::remove_dir_all(&dkv.options.base_path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn diskv_read_after_codec_change() -> DiskvResult<()> {
+        let base_path = path::PathBuf::from("test_data_codec_change");
+        let _ = fs::remove_dir_all(&base_path);
+        let key = String::from("k1");
+        let val = "compressible ".repeat(1000).into_bytes();
+
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            codec: Codec::Gzip,
+            ..Default::default()
+        })?;
+        dkv.put(&key, val.clone())?;
+        assert!((fs::metadata(dkv.key_path(&key))?.len() as usize) < val.len() / 5);
+        assert_eq!(Some(val.clone()), dkv.get(&key)?);
+        drop(dkv);
+
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            ..Default::default()
+        })?;
+        assert_eq!(Some(val), dkv.get(&key)?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    fn diskv_scan_prefix(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("scan_prefix", 2);
+        for key in &["user:12", "user:1", "user:2", "user:1:name", "group:1"] {
+            dkv.put(key, format!("v-{}", key).into_bytes())?;
+        }
+        // only cached one
+        dkv.put("user:1", String::from("v1").into_bytes())?;
+        let entries_before = dkv.stats().entries;
+
+        assert_eq!(
+            vec![
+                (String::from("user:1"), String::from("v1").into_bytes()),
+                (
+                    String::from("user:12"),
+                    String::from("v-user:12").into_bytes()
+                ),
+                (
+                    String::from("user:1:name"),
+                    String::from("v-user:1:name").into_bytes()
+                ),
+            ],
+            dkv.scan_prefix("user:1")?
+        );
+        assert_eq!(4, dkv.scan_prefix("user:")?.len());
+        assert_eq!(5, dkv.scan_prefix("")?.len());
+        assert!(dkv.scan_prefix("nobody")?.is_empty());
+
+        // values read from disk by the scan were not cached
+        assert_eq!(entries_before, dkv.stats().entries);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_get_range(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("get_range", 1024);
+        for key in ["a", "b", "b1", "c", "d"].iter() {
+            dkv.put(key, key.as_bytes().to_vec())?;
+        }
+        let keys = |entries: Vec<(String, Vec<u8>)>| -> Vec<String> {
+            entries.into_iter().map(|(k, _)| k).collect()
+        };
+
+        // start is included, end is not
+        assert_eq!(vec!["b", "b1", "c"], keys(dkv.get_range("b", "d", 10)?));
+        assert_eq!(vec!["b", "b1"], keys(dkv.get_range("b", "d", 2)?));
+        assert_eq!(
+            vec![(String::from("c"), b"c".to_vec())],
+            dkv.get_range("b1\0", "d", 1)?
+        );
+        assert!(dkv.get_range("b", "d", 0)?.is_empty());
+        assert!(dkv.get_range("d", "b", 10)?.is_empty());
+        assert_eq!(5, dkv.get_range("", "z", 10)?.len());
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    fn diskv_count_by_prefix(new_diskv: NewDiskv) -> DiskvResult<()> {
+        let dkv = new_diskv("count_by_prefix", 0);
+        let long_key = format!("user:1:{}", "l".repeat(240));
+        for key in [
+            "user:12",
+            "user:1",
+            "user:2",
+            "user:1:name",
+            "group:1",
+            &long_key,
+        ]
+        .iter()
+        {
+            dkv.put(key, key.as_bytes().to_vec())?;
+        }
+
+        let counts = dkv.count_by_prefix(&["user:", "user:1", "user:1:", "", "nobody"])?;
+        assert_eq!(5, counts.len());
+        assert_eq!(5, counts["user:"]);
+        assert_eq!(4, counts["user:1"]);
+        assert_eq!(2, counts["user:1:"]);
+        assert_eq!(6, counts[""]);
+        assert_eq!(0, counts["nobody"]);
+
+        remove_test_diskv(&dkv)?;
+        Ok(())
+    }
+
+    #[test]
+    fn diskv_scan_prefixes() -> DiskvResult<()> {
+        let dkv = Diskv::new(Options::in_memory())?;
+        for key in &["user:12", "user:1", "user:2", "user:1:name", "group:1"] {
+            dkv.put(key, key.as_bytes().to_vec())?;
+        }
+
+        let buckets = dkv.scan_prefixes(&["user:", "user:1", "user:1:", "nobody"])?;
+        let keys = |prefix: &str| -> Vec<&str> {
+            buckets[prefix].iter().map(|(k, _)| k.as_str()).collect()
+        };
+        assert_eq!(4, buckets.len());
+        assert_eq!(vec!["user:2"], keys("user:"));
+        assert_eq!(vec!["user:1", "user:12"], keys("user:1"));
+        assert_eq!(vec!["user:1:name"], keys("user:1:"));
+        assert!(keys("nobody").is_empty());
+        assert_eq!(b"user:12".to_vec(), buckets["user:1"][1].1);
         Ok(())
     }
 }
@@ -0,0 +1,185 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+//
+// EvictionPolicy
+// Decides which cached key gets evicted when the cache needs space. The cache tells the policy about every key it
+// caches (on_insert), hands out (on_access) and drops (on_remove), and asks for candidates until there is enough room.
+// A candidate the cache no longer holds is skipped, so a policy may lag behind without harm.
+// Options::eviction_policy constructs the policy used by Diskv, LruPolicy by default. Policies must be Send as Diskv
+// is shared across threads. eviction_order lets Diskv::simulate_eviction tell what would be evicted without evicting.
+// Keys are bytes, as keys put through Diskv::put_bytes need not be valid UTF-8.
+//
+pub trait EvictionPolicy: Send {
+    fn on_access(&mut self, key: &[u8]);
+    fn on_insert(&mut self, key: &[u8]);
+    fn evict_candidate(&mut self) -> Option<Vec<u8>>;
+
+    // keys removed other than through evict_candidate, e.g. deleted or overwritten ones
+    fn on_remove(&mut self, _key: &[u8]) {}
+
+    // keys in the order evict_candidate would hand them out, leaving the policy as it is. policies which can't tell
+    // report none, so nothing is simulated to be evicted.
+    fn eviction_order(&self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+// keys ordered by the logical time they were last stamped at
+#[derive(Debug, Default)]
+struct StampOrder {
+    clock: u64,
+    stamps: HashMap<Vec<u8>, u64>,
+    order: BTreeMap<u64, Vec<u8>>,
+}
+
+impl StampOrder {
+    fn stamp(&mut self, key: &[u8]) {
+        self.remove(key);
+        self.clock += 1;
+        self.stamps.insert(key.to_vec(), self.clock);
+        self.order.insert(self.clock, key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(stamp) = self.stamps.remove(key) {
+            self.order.remove(&stamp);
+        }
+    }
+
+    fn pop_oldest(&mut self) -> Option<Vec<u8>> {
+        let (_, key) = self.order.pop_first()?;
+        self.stamps.remove(&key);
+        Some(key)
+    }
+
+    fn oldest_first(&self) -> Vec<Vec<u8>> {
+        self.order.values().cloned().collect()
+    }
+}
+
+// least recently used key gets evicted first, both put and get count as use
+#[derive(Debug, Default)]
+pub struct LruPolicy {
+    order: StampOrder,
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn on_access(&mut self, key: &[u8]) {
+        self.order.stamp(key);
+    }
+
+    fn on_insert(&mut self, key: &[u8]) {
+        self.order.stamp(key);
+    }
+
+    fn evict_candidate(&mut self) -> Option<Vec<u8>> {
+        self.order.pop_oldest()
+    }
+
+    fn on_remove(&mut self, key: &[u8]) {
+        self.order.remove(key);
+    }
+
+    fn eviction_order(&self) -> Vec<Vec<u8>> {
+        self.order.oldest_first()
+    }
+}
+
+// least recently inserted key gets evicted first, reads don't matter
+#[derive(Debug, Default)]
+pub struct FifoPolicy {
+    order: StampOrder,
+}
+
+impl EvictionPolicy for FifoPolicy {
+    fn on_access(&mut self, _key: &[u8]) {}
+
+    fn on_insert(&mut self, key: &[u8]) {
+        self.order.stamp(key);
+    }
+
+    fn evict_candidate(&mut self) -> Option<Vec<u8>> {
+        self.order.pop_oldest()
+    }
+
+    fn on_remove(&mut self, key: &[u8]) {
+        self.order.remove(key);
+    }
+
+    fn eviction_order(&self) -> Vec<Vec<u8>> {
+        self.order.oldest_first()
+    }
+}
+
+// evicts in arbitrary, HashSet order. this is how the cache used to behave before policies were pluggable.
+#[derive(Debug, Default)]
+pub struct NaivePolicy {
+    keys: HashSet<Vec<u8>>,
+}
+
+impl EvictionPolicy for NaivePolicy {
+    fn on_access(&mut self, _key: &[u8]) {}
+
+    fn on_insert(&mut self, key: &[u8]) {
+        self.keys.insert(key.to_vec());
+    }
+
+    fn evict_candidate(&mut self) -> Option<Vec<u8>> {
+        let key = self.keys.iter().next()?.clone();
+        self.keys.remove(&key);
+        Some(key)
+    }
+
+    fn on_remove(&mut self, key: &[u8]) {
+        self.keys.remove(key);
+    }
+
+    // removing a key doesn't reorder the others, so this is the order of repeated evict_candidate calls
+    fn eviction_order(&self) -> Vec<Vec<u8>> {
+        self.keys.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eviction_order(policy: &mut dyn EvictionPolicy) -> Vec<String> {
+        for key in ["k1", "k2", "k3"] {
+            policy.on_insert(key.as_bytes());
+        }
+        policy.on_access(b"k1");
+        policy.on_insert(b"k4");
+        policy.on_remove(b"k3");
+        let simulated = policy.eviction_order();
+        let evicted: Vec<Vec<u8>> = std::iter::from_fn(|| policy.evict_candidate()).collect();
+        assert_eq!(simulated, evicted);
+        evicted
+            .into_iter()
+            .map(|key| String::from_utf8(key).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lru_policy_order() {
+        assert_eq!(
+            vec!["k2", "k1", "k4"],
+            eviction_order(&mut LruPolicy::default())
+        );
+    }
+
+    #[test]
+    fn fifo_policy_order() {
+        assert_eq!(
+            vec!["k1", "k2", "k4"],
+            eviction_order(&mut FifoPolicy::default())
+        );
+    }
+
+    #[test]
+    fn naive_policy_evicts_every_key_once() {
+        let mut order = eviction_order(&mut NaivePolicy::default());
+        order.sort();
+        assert_eq!(vec!["k1", "k2", "k4"], order);
+    }
+}
@@ -0,0 +1,10 @@
+#[cfg(feature = "async")]
+pub mod async_diskv;
+pub mod backend;
+pub mod codec;
+pub mod diskv;
+pub mod eviction;
+#[cfg(test)]
+mod thread_safety;
+#[cfg(feature = "serde")]
+pub mod typed;
@@ -1,9 +1,8 @@
-use std::sync::Arc;
 use std::thread;
 
-mod diskv;
+use diskv_rust::diskv;
 
-fn rwthread(name: String, cnt: u32, dkv: Arc<diskv::Diskv>) -> thread::JoinHandle<()> {
+fn rwthread(name: String, cnt: u32, dkv: diskv::DiskvHandle) -> thread::JoinHandle<()> {
     let th = thread::spawn(move || {
         let mut keys: Vec<String> = Vec::new();
         for i in 0..cnt {
@@ -12,27 +11,22 @@ fn rwthread(name: String, cnt: u32, dkv: Arc<diskv::Diskv>) -> thread::JoinHandl
 
         println!("writing keys in {}", name);
         for key in &keys {
-            println!("[{} put] key: {}", name, key.to_string());
+            println!("[{} put] key: {}", name, key);
             let val = format!("value of key {}", key);
-            dkv.put(key, val.into_bytes()).expect("failed to put");
+            dkv.put_string(key, &val).expect("failed to put");
         }
 
         println!("reading keys in {}", name);
         for key in &keys {
-            match dkv.get(key).expect("failed to get") {
-                Some(v) => println!(
-                    "[{} get] key: {}, val: {}",
-                    name,
-                    key.to_string(),
-                    String::from_utf8_lossy(&v)
-                ),
-                None => println!("key: {}, val: not found", key.to_string()),
+            match dkv.get_string(key).expect("failed to get") {
+                Some(v) => println!("[{} get] key: {}, val: {}", name, key, v),
+                None => println!("key: {}, val: not found", key),
             }
         }
 
         println!("deleting keys in {}", name);
         for key in &keys {
-            println!("[{} delete] key: {}", name, key.to_string());
+            println!("[{} delete] key: {}", name, key);
             dkv.delete(key).expect("failed to delete");
         }
     });
@@ -40,16 +34,15 @@ fn rwthread(name: String, cnt: u32, dkv: Arc<diskv::Diskv>) -> thread::JoinHandl
 }
 
 fn main() {
-    let dkv = Arc::new(
-        diskv::Diskv::new(diskv::Options {
-            base_path: String::from("data"),
-            cache_size_max: 128,
-        })
-        .expect("failed to create diskv"),
-    );
-
-    let th1 = rwthread(String::from("worker1"), 30, Arc::clone(&dkv));
-    let th2 = rwthread(String::from("worker2"), 5, Arc::clone(&dkv));
+    let dkv = diskv::DiskvBuilder::new()
+        .base_path("data")
+        .cache_size_max(128)
+        .build()
+        .expect("failed to create diskv")
+        .handle();
+
+    let th1 = rwthread(String::from("worker1"), 30, dkv.clone());
+    let th2 = rwthread(String::from("worker2"), 5, dkv.clone());
 
     match th1.join() {
         Ok(_) => println!("th1 finished."),
@@ -0,0 +1,63 @@
+// Diskv is meant to be shared across threads, by reference, through DiskvHandle or AsyncDiskv. these only build as
+// long as the public types stay Send and Sync, so a field which isn't fails the build instead of some user's.
+
+use crate::backend::{Backend, FsBackend, MemBackend, SegmentBackend};
+use crate::codec::Codec;
+use crate::diskv::{
+    AdaptiveCache, CacheStats, Diskv, DiskvBuilder, DiskvCache, DiskvError, DiskvHandle, DiskvIter,
+    EntryMeta, KeyHasher, Namespace, Options, StorageFormat, Transaction, WritePolicy,
+};
+use crate::eviction::{EvictionPolicy, FifoPolicy, LruPolicy, NaivePolicy};
+
+fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+
+fn assert_send<T: Send + ?Sized>() {}
+
+#[test]
+fn store_types_are_send_sync() {
+    assert_send_sync::<Diskv>();
+    assert_send_sync::<DiskvHandle>();
+    assert_send_sync::<DiskvCache>();
+    assert_send_sync::<DiskvIter<'static>>();
+    assert_send_sync::<Transaction<'static>>();
+    assert_send_sync::<Namespace<'static>>();
+    #[cfg(feature = "async")]
+    assert_send_sync::<crate::async_diskv::AsyncDiskv>();
+    #[cfg(feature = "serde")]
+    assert_send_sync::<crate::typed::TypedDiskv<String>>();
+}
+
+#[test]
+fn value_types_are_send_sync() {
+    assert_send_sync::<CacheStats>();
+    assert_send_sync::<EntryMeta>();
+    assert_send_sync::<DiskvError>();
+}
+
+#[test]
+fn option_types_are_send_sync() {
+    assert_send_sync::<Options>();
+    assert_send_sync::<DiskvBuilder>();
+    assert_send_sync::<AdaptiveCache>();
+    assert_send_sync::<KeyHasher>();
+    assert_send_sync::<StorageFormat>();
+    assert_send_sync::<WritePolicy>();
+    assert_send_sync::<Codec>();
+}
+
+#[test]
+fn backends_are_send_sync() {
+    assert_send_sync::<dyn Backend>();
+    assert_send_sync::<FsBackend>();
+    assert_send_sync::<MemBackend>();
+    assert_send_sync::<SegmentBackend>();
+}
+
+// policies are only ever used behind the cache lock, so they need not be Sync
+#[test]
+fn eviction_policies_are_send() {
+    assert_send::<dyn EvictionPolicy>();
+    assert_send_sync::<LruPolicy>();
+    assert_send_sync::<FifoPolicy>();
+    assert_send_sync::<NaivePolicy>();
+}
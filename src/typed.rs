@@ -0,0 +1,116 @@
+use std::marker::PhantomData;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::diskv::{Diskv, DiskvResult};
+
+//
+// TypedDiskv
+// Wraps Diskv to store values of type T instead of raw bytes. Values are encoded as JSON, so whatever is on disk is
+// still plain bytes and can be read with Diskv itself.
+//
+pub struct TypedDiskv<T> {
+    diskv: Diskv,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> TypedDiskv<T> {
+    pub fn new(diskv: Diskv) -> TypedDiskv<T> {
+        TypedDiskv {
+            diskv,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn into_inner(self) -> Diskv {
+        self.diskv
+    }
+
+    pub fn put(&self, key: &str, val: &T) -> DiskvResult<()> {
+        self.diskv.put(key, serde_json::to_vec(val)?)
+    }
+
+    pub fn get(&self, key: &str) -> DiskvResult<Option<T>> {
+        match self.diskv.get(key)? {
+            Some(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn delete(&self, key: &str) -> DiskvResult<()> {
+        self.diskv.delete(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diskv::{DiskvError, Options};
+    use serde::Deserialize;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[test]
+    fn typed_get_put_get_delete() -> DiskvResult<()> {
+        let base_path = PathBuf::from("test_data_typed");
+        let _ = fs::remove_dir_all(&base_path);
+        let tdkv: TypedDiskv<User> = TypedDiskv::new(Diskv::new(Options {
+            base_path: base_path.clone(),
+            cache_size_max: 128,
+            ..Default::default()
+        })?);
+
+        let key = String::from("u1");
+        assert_eq!(None, tdkv.get(&key)?);
+
+        let user = User {
+            name: String::from("sid"),
+            age: 30,
+        };
+        tdkv.put(&key, &user)?;
+        assert_eq!(Some(user), tdkv.get(&key)?);
+
+        // bytes on disk are plain JSON
+        let dkv = tdkv.into_inner();
+        assert_eq!(
+            String::from(r#"{"name":"sid","age":30}"#).into_bytes(),
+            dkv.get(&key)?.unwrap()
+        );
+
+        let tdkv: TypedDiskv<User> = TypedDiskv::new(dkv);
+        tdkv.delete(&key)?;
+        assert_eq!(None, tdkv.get(&key)?);
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn typed_decode_failure() -> DiskvResult<()> {
+        let base_path = PathBuf::from("test_data_typed_decode");
+        let _ = fs::remove_dir_all(&base_path);
+        let dkv = Diskv::new(Options {
+            base_path: base_path.clone(),
+            cache_size_max: 128,
+            ..Default::default()
+        })?;
+
+        let key = String::from("u1");
+        dkv.put(&key, String::from("not json").into_bytes())?;
+        let tdkv: TypedDiskv<User> = TypedDiskv::new(dkv);
+        match tdkv.get(&key) {
+            Err(DiskvError::Serde(_)) => (),
+            other => panic!("expected Serde error, got {:?}", other),
+        }
+
+        fs::remove_dir_all(&base_path)?;
+        Ok(())
+    }
+}